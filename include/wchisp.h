@@ -0,0 +1,72 @@
+/* C bindings of wchisp, build with `cargo build --release --features ffi` */
+#ifndef WCHISP_H
+#define WCHISP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes, the same as the wchisp process exit codes */
+#define WCHISP_OK 0
+#define WCHISP_ERR_GENERAL 1
+#define WCHISP_ERR_NO_DEVICE 2
+#define WCHISP_ERR_CHIP_MISMATCH 3
+#define WCHISP_ERR_FLASH_PROTECTED 4
+#define WCHISP_ERR_VERIFY_FAILED 5
+#define WCHISP_ERR_TRANSPORT 6
+#define WCHISP_ERR_BAD_FIRMWARE 7
+#define WCHISP_ERR_CANCELLED 130
+
+/* Flags of wchisp_flash() and wchisp_flash_file() */
+#define WCHISP_FLASH_ERASE (1u << 0)
+#define WCHISP_FLASH_VERIFY (1u << 1)
+#define WCHISP_FLASH_RESET (1u << 2)
+
+typedef struct WchispHandle WchispHandle;
+
+typedef struct WchispChipInfo {
+    char name[32];
+    uint8_t chip_id;
+    uint8_t device_type;
+    uint32_t flash_size;
+    uint32_t eeprom_size;
+    uint8_t uid[8];
+    uint32_t uid_len;
+    uint8_t bootloader_version[4];
+    /* 1 if protected, 0 if not, -1 if not supported */
+    int code_flash_protected;
+    /* empty if not known */
+    char part_number[32];
+} WchispChipInfo;
+
+/* `phase` is only valid during the call */
+typedef void (*wchisp_progress_cb)(const char *phase, uint64_t done, uint64_t total, void *user_data);
+
+const char *wchisp_last_error(void);
+int wchisp_probe_usb(void);
+
+int wchisp_open_usb(int index, WchispHandle **out);
+int wchisp_open_serial(const char *port, uint32_t baudrate, WchispHandle **out);
+void wchisp_close(WchispHandle *handle);
+
+int wchisp_info(WchispHandle *handle, WchispChipInfo *info);
+int wchisp_flash(WchispHandle *handle, const uint8_t *data, size_t len, uint32_t address,
+                 uint32_t flags, wchisp_progress_cb callback, void *user_data);
+int wchisp_flash_file(WchispHandle *handle, const char *path, uint32_t address, uint32_t flags,
+                      wchisp_progress_cb callback, void *user_data);
+int wchisp_verify(WchispHandle *handle, const uint8_t *data, size_t len, uint32_t address,
+                  wchisp_progress_cb callback, void *user_data);
+int wchisp_reset(WchispHandle *handle);
+
+int wchisp_config_get(WchispHandle *handle, const char *name, uint32_t *value);
+int wchisp_config_reset(WchispHandle *handle);
+int wchisp_config_unprotect(WchispHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WCHISP_H */
@@ -0,0 +1,103 @@
+// Page side of the WebUSB `UsbTransport` of the `wasm` feature, see src/transport/webusb.rs
+//
+// The worker running wchisp blocks on the shared buffer, this module runs its transfers:
+//
+//   const device = await navigator.usb.requestDevice({ filters: FILTERS });
+//   await openDevice(device);
+//   const buffer = new SharedArrayBuffer(BUFFER_SIZE);
+//   serve(device, buffer);
+//   worker.postMessage({ buffer });
+
+const STATE = 0;
+const OP = 1;
+const LEN = 2;
+const TIMEOUT = 3;
+const HEADER_SIZE = 16;
+
+const STATE_REQUEST = 1;
+const STATE_DONE = 2;
+const STATE_ERROR = 3;
+
+const OP_OUT = 1;
+const OP_IN = 2;
+
+// Bulk endpoint 0x02 OUT / 0x82 IN of the ISP interface
+const ENDPOINT = 2;
+
+export const BUFFER_SIZE = HEADER_SIZE + 64;
+
+// WCH ISP bootloaders, 4348:55e0 or 1a86:55e0
+export const FILTERS = [
+  { vendorId: 0x4348, productId: 0x55e0 },
+  { vendorId: 0x1a86, productId: 0x55e0 },
+];
+
+export async function openDevice(device) {
+  await device.open();
+  if (device.configuration === null) {
+    await device.selectConfiguration(1);
+  }
+  await device.claimInterface(0);
+}
+
+// Run transfers requested over `buffer` until `signal` is aborted
+export async function serve(device, buffer, signal) {
+  const control = new Int32Array(buffer, 0, HEADER_SIZE / 4);
+  const data = new Uint8Array(buffer, HEADER_SIZE);
+  // NOTE: WebUSB transfers can not be cancelled, an IN timed out is kept for the next IN
+  let pendingIn = null;
+
+  while (!signal?.aborted) {
+    const state = Atomics.load(control, STATE);
+    if (state !== STATE_REQUEST) {
+      const wait = Atomics.waitAsync(control, STATE, state, 1000);
+      if (wait.async) {
+        await wait.value;
+      }
+      continue;
+    }
+
+    try {
+      const len = Atomics.load(control, LEN);
+      if (Atomics.load(control, OP) === OP_OUT) {
+        // NOTE: slice() copies to a non-shared buffer, WebUSB refuses shared ones
+        await device.transferOut(ENDPOINT, data.slice(0, len));
+        Atomics.store(control, LEN, 0);
+      } else {
+        pendingIn ??= device.transferIn(ENDPOINT, len);
+        const timeout = Atomics.load(control, TIMEOUT);
+        let result;
+        try {
+          result = await Promise.race([
+            pendingIn,
+            new Promise((resolve) => setTimeout(resolve, timeout, null)),
+          ]);
+        } catch (e) {
+          pendingIn = null;
+          throw e;
+        }
+        if (result === null) {
+          throw new Error(`no response in ${timeout}ms`);
+        }
+        pendingIn = null;
+        if (result.status !== "ok") {
+          throw new Error(`transferIn: ${result.status}`);
+        }
+        const received = new Uint8Array(
+          result.data.buffer,
+          result.data.byteOffset,
+          result.data.byteLength,
+        );
+        data.set(received.subarray(0, data.length));
+        Atomics.store(control, LEN, Math.min(received.length, data.length));
+      }
+      Atomics.store(control, STATE, STATE_DONE);
+    } catch (e) {
+      const message = new TextEncoder().encode(String(e?.message ?? e)).subarray(0, data.length);
+      data.set(message);
+      Atomics.store(control, LEN, message.length);
+      Atomics.store(control, STATE, STATE_ERROR);
+    }
+    Atomics.notify(control, STATE);
+  }
+}
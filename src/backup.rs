@@ -0,0 +1,108 @@
+//! Config register backups, saved before every config write so that `config restore` can undo it
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use wchisp::Chip;
+
+/// A backup file, `<uid>-<unix ms>.json` in [`backup_dir`], `<uid>-<unix ms>-<n>.json` if
+/// several are saved in the same millisecond
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigBackup {
+    pub chip: String,
+    /// Chip UID, hex
+    pub uid: String,
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// RDPR, USER, DATA and WPR registers, hex
+    pub raw: String,
+}
+
+/// `$XDG_DATA_HOME/wchisp/backups`, `~/.local/share/wchisp/backups` by default,
+/// `%LOCALAPPDATA%\wchisp\backups` on Windows
+pub fn backup_dir() -> Result<PathBuf> {
+    let env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty());
+    let data_dir = if cfg!(target_os = "windows") {
+        env("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        env("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env("HOME").map(|home| Path::new(&home).join(".local/share")))
+    };
+    let data_dir =
+        data_dir.ok_or_else(|| anyhow::format_err!("no home directory to back up to"))?;
+    Ok(data_dir.join("wchisp").join("backups"))
+}
+
+/// Save `raw` config registers of the chip, returns the backup file path.
+///
+/// Existing backups are never overwritten.
+pub fn save(chip: &Chip, uid: &[u8], raw: &[u8]) -> Result<PathBuf> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let backup = ConfigBackup {
+        chip: chip.name.clone(),
+        uid: hex::encode(uid),
+        timestamp: now.as_secs(),
+        raw: hex::encode(raw),
+    };
+    let text = serde_json::to_string_pretty(&backup)? + "\n";
+    let dir = backup_dir()?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    for n in 0.. {
+        let name = match n {
+            0 => format!("{}-{}.json", backup.uid, now.as_millis()),
+            n => format!("{}-{}-{}.json", backup.uid, now.as_millis(), n),
+        };
+        let path = dir.join(name);
+        let mut file = match std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("creating {}", path.display())),
+        };
+        file.write_all(text.as_bytes())
+            .with_context(|| format!("writing {}", path.display()))?;
+        return Ok(path);
+    }
+    unreachable!()
+}
+
+/// Load a backup file
+pub fn load(path: &Path) -> Result<ConfigBackup> {
+    let text = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// The newest backup of the chip with `uid`
+pub fn latest(uid: &[u8]) -> Result<PathBuf> {
+    let dir = backup_dir()?;
+    let prefix = format!("{}-", hex::encode(uid));
+    // NOTE: a missing directory just has no backups
+    std::fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let stem = name.strip_prefix(&prefix)?.strip_suffix(".json")?;
+            // NOTE: older backups are named by seconds, they sort before any millisecond one
+            let (timestamp, n) = stem.split_once('-').unwrap_or((stem, "0"));
+            let key: (u128, u32) = (timestamp.parse().ok()?, n.parse().ok()?);
+            Some((key, entry.path()))
+        })
+        .max()
+        .map(|(_, path)| path)
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "no config backup of chip {} in {}",
+                hex::encode(uid),
+                dir.display()
+            )
+        })
+}
@@ -0,0 +1,290 @@
+//! BOOT0 and reset control of test fixtures, so that boards enter the bootloader unattended
+//!
+//! ```toml
+//! [boards.sensor-v2]
+//! uid = "cd-ab-12-34-56-78-9a-bc"
+//! boot = { gpiochip = "/dev/gpiochip0", boot0 = 17, reset = 27 }
+//!
+//! [boards.gateway]
+//! port = "/dev/ttyUSB0"
+//! boot = { ftdi = "0403:6015", boot0 = 0, reset = 1 }
+//! ```
+use std::{thread::sleep, time::Duration};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// How long reset is held, and how long the chip gets to boot into the bootloader
+const RESET_PULSE: Duration = Duration::from_millis(100);
+
+/// Where the BOOT0 and reset lines of a fixture are wired, exactly one backend is set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootConfig {
+    /// Linux GPIO character device, e.g. `/dev/gpiochip0`, lines are offsets on the chip
+    pub gpiochip: Option<String>,
+    /// `VID:PID` of an FTDI chip with CBUS bit-bang, e.g. `0403:6015`, lines are CBUS pins 0-3
+    /// that must be set to GPIO in the FTDI EEPROM
+    pub ftdi: Option<String>,
+    /// Line driving BOOT0, high in the bootloader unless `boot0_active_low`
+    pub boot0: Option<u32>,
+    /// Line driving the active-low reset
+    pub reset: Option<u32>,
+    /// BOOT0 is low in the bootloader, e.g. the download pin of CH5x chips
+    #[serde(default)]
+    pub boot0_active_low: bool,
+}
+
+/// Drives the BOOT0 and reset lines of a fixture
+pub trait BootControl {
+    /// Set the lines, `true` is electrically high
+    fn set_lines(&mut self, boot0: Option<bool>, reset: Option<bool>) -> Result<()>;
+}
+
+impl BootConfig {
+    fn open(&self) -> Result<Box<dyn BootControl>> {
+        let lines = (self.boot0, self.reset);
+        match (&self.gpiochip, &self.ftdi) {
+            (Some(path), None) => Ok(Box::new(gpiochip::GpioChip::open(path, lines)?)),
+            (None, Some(id)) => Ok(Box::new(ftdi::FtdiCbus::open(id, lines)?)),
+            _ => anyhow::bail!("board boot control needs exactly one of gpiochip or ftdi"),
+        }
+    }
+
+    /// Assert BOOT0, pulse reset, then release BOOT0 once the chip runs the bootloader
+    pub fn enter_bootloader(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.boot0.is_some() || self.reset.is_some(),
+            "board boot control needs a boot0 or reset line"
+        );
+        let mut control = self.open()?;
+        let boot0 = self.boot0.map(|_| !self.boot0_active_low);
+        let reset = self.reset.map(|_| false);
+        tracing::info!("Entering bootloader via BOOT0/reset lines");
+        control.set_lines(boot0, reset)?;
+        sleep(RESET_PULSE);
+        control.set_lines(boot0, reset.map(|_| true))?;
+        sleep(RESET_PULSE);
+        control.set_lines(boot0.map(|level| !level), None)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod gpiochip {
+    use std::{
+        fs::File,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    use anyhow::{Context, Result};
+
+    use super::BootControl;
+
+    const GPIO_V2_LINES_MAX: usize = 64;
+    const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+    // NOTE: _IOWR(0xB4, 0x07, struct gpio_v2_line_request) and
+    // _IOWR(0xB4, 0x0F, struct gpio_v2_line_values) of linux/gpio.h
+    const GPIO_V2_GET_LINE_IOCTL: libc::c_ulong = 0xc250_b407;
+    const GPIO_V2_LINE_SET_VALUES_IOCTL: libc::c_ulong = 0xc010_b40f;
+
+    #[repr(C)]
+    struct LineConfigAttribute {
+        id: u32,
+        padding: u32,
+        value: u64,
+        mask: u64,
+    }
+
+    #[repr(C)]
+    struct LineConfig {
+        flags: u64,
+        num_attrs: u32,
+        padding: [u32; 5],
+        attrs: [LineConfigAttribute; 10],
+    }
+
+    #[repr(C)]
+    struct LineRequest {
+        offsets: [u32; GPIO_V2_LINES_MAX],
+        consumer: [u8; 32],
+        config: LineConfig,
+        num_lines: u32,
+        event_buffer_size: u32,
+        padding: [u32; 5],
+        fd: i32,
+    }
+
+    #[repr(C)]
+    struct LineValues {
+        bits: u64,
+        mask: u64,
+    }
+
+    const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+
+    /// BOOT0 and reset as output lines of a Linux GPIO chip
+    pub struct GpioChip {
+        lines: File,
+        /// Index of BOOT0 and reset in the line request
+        boot0: Option<usize>,
+        reset: Option<usize>,
+    }
+
+    impl GpioChip {
+        pub fn open(path: &str, (boot0, reset): (Option<u32>, Option<u32>)) -> Result<Self> {
+            let chip = File::open(path).with_context(|| format!("opening {}", path))?;
+            let offsets: Vec<u32> = [boot0, reset].into_iter().flatten().collect();
+
+            // SAFETY: all-zero is a valid line request, it is fully owned and outlives the ioctl
+            let mut request: LineRequest = unsafe { std::mem::zeroed() };
+            request.offsets[..offsets.len()].copy_from_slice(&offsets);
+            request.consumer[..6].copy_from_slice(b"wchisp");
+            request.config.flags = GPIO_V2_LINE_FLAG_OUTPUT;
+            request.num_lines = offsets.len() as u32;
+            // SAFETY: the request struct matches `struct gpio_v2_line_request`
+            if unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL, &mut request) } < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("requesting lines {:?} of {}", offsets, path));
+            }
+            // SAFETY: the kernel returned a new fd owned by us
+            let lines = unsafe { File::from_raw_fd(request.fd) };
+
+            Ok(GpioChip {
+                lines,
+                boot0: boot0.map(|_| 0),
+                reset: reset.map(|_| boot0.map_or(0, |_| 1)),
+            })
+        }
+    }
+
+    impl BootControl for GpioChip {
+        fn set_lines(&mut self, boot0: Option<bool>, reset: Option<bool>) -> Result<()> {
+            let mut values = LineValues { bits: 0, mask: 0 };
+            for (index, level) in [(self.boot0, boot0), (self.reset, reset)] {
+                if let (Some(index), Some(level)) = (index, level) {
+                    values.mask |= 1 << index;
+                    values.bits |= (level as u64) << index;
+                }
+            }
+            // SAFETY: the values struct matches `struct gpio_v2_line_values`
+            let ret = unsafe {
+                libc::ioctl(
+                    self.lines.as_raw_fd(),
+                    GPIO_V2_LINE_SET_VALUES_IOCTL,
+                    &mut values,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod gpiochip {
+    use anyhow::Result;
+
+    use super::BootControl;
+
+    pub struct GpioChip;
+
+    impl GpioChip {
+        pub fn open(_path: &str, _lines: (Option<u32>, Option<u32>)) -> Result<Self> {
+            anyhow::bail!("gpiochip boot control is only supported on Linux")
+        }
+    }
+
+    impl BootControl for GpioChip {
+        fn set_lines(&mut self, _boot0: Option<bool>, _reset: Option<bool>) -> Result<()> {
+            unreachable!()
+        }
+    }
+}
+
+mod ftdi {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use rusb::{Context, DeviceHandle, UsbContext};
+
+    use super::BootControl;
+
+    const SIO_SET_BITMODE: u8 = 0x0b;
+    const BITMODE_CBUS: u16 = 0x20;
+    /// Interface A, the only one of CBUS capable chips
+    const INTERFACE_A: u16 = 1;
+    const FTDI_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// BOOT0 and reset as CBUS pins of an FTDI chip, e.g. the USB-UART bridge of the fixture
+    pub struct FtdiCbus {
+        handle: DeviceHandle<Context>,
+        boot0: Option<u8>,
+        reset: Option<u8>,
+        /// Pin levels, low nibble of the bitmode value
+        levels: u8,
+    }
+
+    impl FtdiCbus {
+        pub fn open(id: &str, (boot0, reset): (Option<u32>, Option<u32>)) -> Result<Self> {
+            let (vid, pid) = id
+                .split_once(':')
+                .and_then(|(vid, pid)| {
+                    Some((
+                        u16::from_str_radix(vid, 16).ok()?,
+                        u16::from_str_radix(pid, 16).ok()?,
+                    ))
+                })
+                .ok_or_else(|| anyhow::format_err!("invalid FTDI id {}, expected VID:PID", id))?;
+            let pin = |line: Option<u32>| -> Result<Option<u8>> {
+                match line {
+                    Some(line @ 0..=3) => Ok(Some(line as u8)),
+                    Some(line) => anyhow::bail!("CBUS pin {} out of range 0-3", line),
+                    None => Ok(None),
+                }
+            };
+            let (boot0, reset) = (pin(boot0)?, pin(reset)?);
+
+            // NOTE: vendor requests need no claimed interface, the UART stays usable
+            let handle = Context::new()?
+                .open_device_with_vid_pid(vid, pid)
+                .ok_or_else(|| wchisp::Error::NoDevice(format!("FTDI {} not found", id)))?;
+            Ok(FtdiCbus {
+                handle,
+                boot0,
+                reset,
+                levels: 0,
+            })
+        }
+    }
+
+    impl BootControl for FtdiCbus {
+        fn set_lines(&mut self, boot0: Option<bool>, reset: Option<bool>) -> Result<()> {
+            let mut outputs = 0;
+            for (pin, level) in [(self.boot0, boot0), (self.reset, reset)] {
+                if let Some(pin) = pin {
+                    outputs |= 1 << pin;
+                    if let Some(level) = level {
+                        self.levels = (self.levels & !(1 << pin)) | ((level as u8) << pin);
+                    }
+                }
+            }
+            let value = (BITMODE_CBUS << 8) | ((outputs as u16) << 4) | self.levels as u16;
+            self.handle.write_control(
+                rusb::request_type(
+                    rusb::Direction::Out,
+                    rusb::RequestType::Vendor,
+                    rusb::Recipient::Device,
+                ),
+                SIO_SET_BITMODE,
+                value,
+                INTERFACE_A,
+                &[],
+                FTDI_TIMEOUT,
+            )?;
+            Ok(())
+        }
+    }
+}
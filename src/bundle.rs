@@ -0,0 +1,255 @@
+//! Firmware bundles, `.wchfw` files.
+//!
+//! A bundle is a complete provisioning recipe in one CBOR file: code flash images, an optional
+//! EEPROM image, the target chip, the required bootloader version and config register values.
+//!
+//! A bundle can carry an Ed25519 signature over everything else in it, see [`Bundle::sign`].
+use std::{io::BufReader, path::Path};
+
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+pub use crate::format::Segment;
+
+use crate::{
+    flashing::{BootloaderVersion, EndReason},
+    format::{extend_firmware_to_sector_boundary, ImageDigest},
+    Error, Flashing, Transport,
+};
+
+/// File extension of bundles
+pub const EXTENSION: &str = "wchfw";
+
+const MAGIC: &str = "wchfw";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    magic: String,
+    version: u32,
+    /// Target chip name, a prefix, glob or `/regex/`, see [`crate::Chip::matches_name`]
+    pub chip: String,
+    /// The first bootloader version the bundle is known to work with
+    pub min_bootloader_version: Option<BootloaderVersion>,
+    /// Code flash images
+    pub code: Vec<Segment>,
+    /// The whole EEPROM(data flash)
+    #[serde(with = "serde_bytes")]
+    pub eeprom: Option<Vec<u8>>,
+    /// Config register or field values, by `REGISTER` or `REGISTER.FIELD` name, applied in order
+    pub config: Vec<(String, u32)>,
+    /// Signature over the bundle without this field
+    #[serde(default)]
+    signature: Option<Signature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Signature {
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    signature: Vec<u8>,
+}
+
+/// Result of [`Bundle::check_signature`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    /// Signed by one of the trusted keys
+    Trusted,
+    /// Not signed at all
+    Unsigned,
+    /// Correctly signed, but not by a trusted key
+    UntrustedKey,
+    /// The signature does not match the contents, the bundle was modified after signing
+    Invalid,
+}
+
+impl Bundle {
+    pub fn new(chip: impl Into<String>) -> Self {
+        Bundle {
+            magic: MAGIC.to_string(),
+            version: VERSION,
+            chip: chip.into(),
+            min_bootloader_version: None,
+            code: vec![],
+            eeprom: None,
+            config: vec![],
+            signature: None,
+        }
+    }
+
+    /// Whether `path` looks like a bundle, by extension
+    pub fn is_bundle_path<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(EXTENSION))
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let p = path.as_ref();
+        let parse = || -> Result<Bundle> {
+            let file = BufReader::new(std::fs::File::open(p)?);
+            let bundle: Bundle = ciborium::from_reader(file)?;
+            anyhow::ensure!(bundle.magic == MAGIC, "not a firmware bundle");
+            anyhow::ensure!(
+                bundle.version == VERSION,
+                "unsupported bundle version {}",
+                bundle.version
+            );
+            Ok(bundle)
+        };
+        parse().with_context(|| Error::BadFirmware(p.display().to_string()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        ciborium::into_writer(self, file)?;
+        Ok(())
+    }
+
+    /// Sign the bundle with an Ed25519 secret key, replacing any previous signature
+    pub fn sign(&mut self, secret_key: &[u8; 32]) -> Result<()> {
+        let key = SigningKey::from_bytes(secret_key);
+        self.signature = None;
+        let signature = key.sign(&self.signed_payload()?);
+        self.signature = Some(Signature {
+            public_key: key.verifying_key().to_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Public key the bundle was signed with, if signed
+    pub fn signer(&self) -> Option<&[u8]> {
+        self.signature.as_ref().map(|s| &s.public_key[..])
+    }
+
+    /// Check the signature against a set of trusted Ed25519 public keys
+    pub fn check_signature(&self, trusted_keys: &[[u8; 32]]) -> Result<SignatureStatus> {
+        let Some(signed) = &self.signature else {
+            return Ok(SignatureStatus::Unsigned);
+        };
+        let (Ok(public_key), Ok(signature)) = (
+            <[u8; 32]>::try_from(&signed.public_key[..]),
+            ed25519_dalek::Signature::from_slice(&signed.signature),
+        ) else {
+            return Ok(SignatureStatus::Invalid);
+        };
+        let Some(key) = VerifyingKey::from_bytes(&public_key).ok() else {
+            return Ok(SignatureStatus::Invalid);
+        };
+        if key.verify(&self.signed_payload()?, &signature).is_err() {
+            return Ok(SignatureStatus::Invalid);
+        }
+        if trusted_keys.contains(&public_key) {
+            Ok(SignatureStatus::Trusted)
+        } else {
+            Ok(SignatureStatus::UntrustedKey)
+        }
+    }
+
+    /// Public key of an Ed25519 secret key
+    pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+        SigningKey::from_bytes(secret_key)
+            .verifying_key()
+            .to_bytes()
+    }
+
+    /// The CBOR encoding of the bundle without its signature
+    fn signed_payload(&self) -> Result<Vec<u8>> {
+        let unsigned = Bundle {
+            signature: None,
+            ..self.clone()
+        };
+        let mut payload = vec![];
+        ciborium::into_writer(&unsigned, &mut payload)?;
+        Ok(payload)
+    }
+
+    /// Apply the whole bundle: code flash, EEPROM, config registers, then end the session with `end`.
+    ///
+    /// Everything is checked against the connected chip before anything is written.
+    /// `erase_all` allows erasing code flash below images not starting at 0, see
+    /// [`FlashSession::erase_all`](crate::flashing::FlashSession::erase_all).
+    pub fn apply<T: Transport>(
+        &self,
+        flashing: &mut Flashing<T>,
+        erase: bool,
+        erase_all: bool,
+        verify: bool,
+        end: Option<EndReason>,
+    ) -> Result<ImageDigest> {
+        flashing.check_chip_name(&self.chip)?;
+        let info = flashing.info();
+        if let Some(min) = self.min_bootloader_version {
+            anyhow::ensure!(
+                info.bootloader_version >= min,
+                "bundle requires bootloader {} or later, found {}",
+                min,
+                info.bootloader_version
+            );
+        }
+        if let Some(eeprom) = &self.eeprom {
+            anyhow::ensure!(
+                eeprom.len() as u32 == info.eeprom_size,
+                "EEPROM size mismatch: expected {}, got {}",
+                info.eeprom_size,
+                eeprom.len()
+            );
+        }
+        let sector_size = flashing.chip.sector_size() as usize;
+        let images: Vec<Segment> = self
+            .code
+            .iter()
+            .map(|segment| {
+                let mut data = segment.data.clone();
+                extend_firmware_to_sector_boundary(&mut data, sector_size, 0x00);
+                Segment::new(segment.address, data)
+            })
+            .collect();
+        for segment in &images {
+            anyhow::ensure!(
+                segment.end_address() <= info.flash_size as u64,
+                "bundle image out of code flash range: 0x{:08x}..0x{:08x}",
+                segment.address,
+                segment.end_address()
+            );
+        }
+        let config = if self.config.is_empty() {
+            None
+        } else {
+            let mut config = flashing.read_config()?;
+            for (name, value) in &self.config {
+                config.set(name, *value)?;
+            }
+            Some(config)
+        };
+
+        let digest = flashing
+            .session()
+            .erase(erase)
+            .erase_all(erase_all)
+            .verify(verify)
+            .reset(false)
+            .run(&images)?;
+
+        if let Some(eeprom) = &self.eeprom {
+            flashing.reidenfity()?;
+            tracing::info!("Erasing EEPROM(Data Flash)...");
+            flashing.erase_data()?;
+            tracing::info!("Writing EEPROM(Data Flash)...");
+            flashing.write_eeprom(eeprom)?;
+            tracing::info!("EEPROM written");
+        }
+        if let Some(config) = &config {
+            flashing.write_config(config)?;
+            tracing::info!("Config registers written");
+        }
+
+        if let Some(reason) = end {
+            let _ = flashing.end_session(reason);
+        }
+        Ok(digest)
+    }
+}
@@ -0,0 +1,63 @@
+//! Checks of chip family files, `devices/*.yaml` of the chip database, before they are contributed
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use wchisp::device::{ChipDB, ChipFamily};
+
+/// Check a family file, returns the problems as `FILE:LINE: message`
+pub fn validate(path: &Path) -> Result<Vec<String>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let family: ChipFamily = match serde_yaml::from_str(&text) {
+        Ok(family) => family,
+        Err(e) => {
+            let line = e.location().map(|l| l.line()).unwrap_or(1);
+            return Ok(vec![format!("{}:{}: {}", path.display(), line, e)]);
+        }
+    };
+
+    let mut problems = vec![];
+    for issue in family.lint() {
+        problems.push(format!(
+            "{}:{}: {}: {}",
+            path.display(),
+            line_of(&text, &issue.item),
+            issue.item,
+            issue.message
+        ));
+    }
+    // NOTE: a changed copy of a built-in family keeps its name
+    let builtin = ChipDB::load()?;
+    if let Some(other) = builtin
+        .families
+        .iter()
+        .find(|f| f.device_type == family.device_type && f.name != family.name)
+    {
+        problems.push(format!(
+            "{}:{}: device_type 0x{:02x} is already used by {}",
+            path.display(),
+            line_of(&text, &family.name),
+            family.device_type,
+            other.name
+        ));
+    }
+    Ok(problems)
+}
+
+/// Line of the last `name: NAME` entry of an item, the duplicate if there are two. Fields are
+/// looked up by the field name of `REGISTER.FIELD`.
+fn line_of(text: &str, item: &str) -> usize {
+    let name = item.rsplit('.').next().unwrap_or(item);
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            line.trim_start()
+                .trim_start_matches("- ")
+                .strip_prefix("name:")
+                .is_some_and(|value| value.trim().trim_matches(['"', '\'']) == name)
+        })
+        .map(|(index, _)| index + 1)
+        .last()
+        .unwrap_or(1)
+}
@@ -2,6 +2,10 @@
 
 pub const MAX_PACKET_SIZE: usize = 64;
 pub const SECTOR_SIZE: usize = 1024;
+/// Program and Verify data chunk that fits a request in [`MAX_PACKET_SIZE`]
+pub const CHUNK_SIZE: usize = 56;
+/// Program and Verify request header: command, length, address and padding byte
+pub const REQUEST_HEADER_SIZE: usize = 8;
 
 /// All readable and writable registers.
 /// - `RDPR`: Read Protection
@@ -36,4 +40,35 @@ pub mod commands {
     pub const WRITE_OTP: u8 = 0xc3;
     pub const READ_OTP: u8 = 0xc4;
     pub const SET_BAUD: u8 = 0xc5;
+
+    /// Name of the command code, for logging and tracing
+    pub fn name(code: u8) -> &'static str {
+        match code {
+            IDENTIFY => "IDENTIFY",
+            ISP_END => "ISP_END",
+            ISP_KEY => "ISP_KEY",
+            ERASE => "ERASE",
+            PROGRAM => "PROGRAM",
+            VERIFY => "VERIFY",
+            READ_CONFIG => "READ_CONFIG",
+            WRITE_CONFIG => "WRITE_CONFIG",
+            DATA_ERASE => "DATA_ERASE",
+            DATA_PROGRAM => "DATA_PROGRAM",
+            DATA_READ => "DATA_READ",
+            WRITE_OTP => "WRITE_OTP",
+            READ_OTP => "READ_OTP",
+            SET_BAUD => "SET_BAUD",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+/// Commands of the v1 protocol of old CH55x bootloaders, see [`crate::transport::LegacyTransport`]
+pub mod commands_v1 {
+    pub const DETECT: u8 = 0xa2;
+    pub const END: u8 = 0xa5;
+    pub const VERIFY: u8 = 0xa7;
+    pub const PROGRAM: u8 = 0xa8;
+    pub const ERASE: u8 = 0xa9;
+    pub const READ_CONFIG: u8 = 0xbb;
 }
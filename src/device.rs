@@ -1,9 +1,14 @@
 //! MCU Chip definition, with chip-specific or chip-family-specific flags
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt, ops::Range, str::FromStr};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    constants::{CHUNK_SIZE, SECTOR_SIZE},
+    transport::Link,
+};
+
 /// MCU Family
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChipFamily {
@@ -17,6 +22,16 @@ pub struct ChipFamily {
     pub variants: Vec<Chip>,
     #[serde(default)]
     pub config_registers: Vec<ConfigRegister>,
+    pub timing: Option<Timing>,
+    #[serde(default)]
+    pub min_bootloader_version: BTreeMap<Capability, BootloaderVersion>,
+    #[serde(default)]
+    pub quirks: Vec<QuirkRule>,
+    pub sector_size: Option<u32>,
+    pub chunk_size: Option<usize>,
+    pub max_baudrate: Option<u32>,
+    pub core: Option<Core>,
+    pub write_protect_block: Option<u32>,
 }
 
 impl ChipFamily {
@@ -29,6 +44,176 @@ impl ChipFamily {
         }
         Ok(())
     }
+
+    /// Check a family definition more thoroughly than loading it, for contributed device files.
+    ///
+    /// Covers [`ChipFamily::validate`], duplicate names and chip IDs, overlapping register
+    /// fields and explanation values that do not parse or fit.
+    pub fn lint(&self) -> Vec<LintIssue> {
+        let mut issues = vec![];
+        let mut issue = |item: &str, message: String| {
+            let issue = LintIssue {
+                item: item.to_string(),
+                message,
+            };
+            if !issues.contains(&issue) {
+                issues.push(issue);
+            }
+        };
+
+        if self.device_type != self.mcu_type.wrapping_add(0x10) {
+            issue(
+                &self.name,
+                format!(
+                    "device_type 0x{:02x} is not mcu_type + 0x10",
+                    self.device_type
+                ),
+            );
+        }
+
+        let mut names = BTreeMap::new();
+        let mut chip_ids = BTreeMap::new();
+        for variant in &self.variants {
+            if let Some(other) = names.insert(&variant.name, &variant.name) {
+                issue(&variant.name, format!("duplicate variant name {}", other));
+            }
+            if let Some(other) = chip_ids.insert(variant.chip_id, &variant.name) {
+                issue(
+                    &variant.name,
+                    format!(
+                        "chip_id 0x{:02x} is also used by {}",
+                        variant.chip_id, other
+                    ),
+                );
+            }
+        }
+        for variant in &self.variants {
+            if let Err(e) = variant.validate() {
+                issue(&variant.name, e.to_string());
+            }
+            if variant.flash_size == 0 {
+                issue(&variant.name, "flash_size is 0".to_string());
+            }
+            // NOTE: the `ALL` marker probes every ID on purpose
+            if variant.alt_chip_ids.len() < 0x100 {
+                for id in &variant.alt_chip_ids {
+                    if let Some(other) = chip_ids.get(id).filter(|&&other| other != &variant.name) {
+                        issue(
+                            &variant.name,
+                            format!("alt_chip_ids 0x{:02x} is the chip_id of {}", id, other),
+                        );
+                    }
+                }
+            }
+            let mut part_names = vec![];
+            for part in &variant.parts {
+                if part.chip_id != variant.chip_id && !variant.alt_chip_ids.contains(&part.chip_id)
+                {
+                    issue(
+                        &part.name,
+                        format!(
+                            "chip_id 0x{:02x} is not an ID of variant {}",
+                            part.chip_id, variant.name
+                        ),
+                    );
+                }
+                if part_names.contains(&&part.name) {
+                    issue(&part.name, "duplicate part name".to_string());
+                }
+                part_names.push(&part.name);
+            }
+            let sector_size = variant.sector_size.or(self.sector_size);
+            if let Some(sector_size) = sector_size.filter(|size| !size.is_power_of_two()) {
+                issue(
+                    &variant.name,
+                    format!("sector_size {} is not a power of two", sector_size),
+                );
+            }
+            let block = variant.write_protect_block.or(self.write_protect_block);
+            let sector_size = sector_size.unwrap_or(SECTOR_SIZE as u32);
+            if let Some(block) = block.filter(|block| !block.is_multiple_of(sector_size)) {
+                issue(
+                    &variant.name,
+                    format!(
+                        "write_protect_block {} is not a multiple of the sector size {}",
+                        block, sector_size
+                    ),
+                );
+            }
+            lint_registers(&variant.config_registers, &mut issue);
+        }
+        lint_registers(&self.config_registers, &mut issue);
+        issues
+    }
+}
+
+/// A problem found by [`ChipFamily::lint`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintIssue {
+    /// Name of the family, variant, part, register or `REGISTER.FIELD` with the problem
+    pub item: String,
+    pub message: String,
+}
+
+fn lint_registers(registers: &[ConfigRegister], issue: &mut impl FnMut(&str, String)) {
+    let mut offsets = BTreeMap::new();
+    for register in registers {
+        if let Err(e) = register.validate() {
+            issue(&register.name, e.to_string());
+        }
+        if let Some(other) = offsets.insert(register.offset, &register.name) {
+            issue(
+                &register.name,
+                format!("offset 0x{:02x} is also used by {}", register.offset, other),
+            );
+        }
+        lint_explanations(&register.name, &register.explaination, u32::MAX, issue);
+
+        let mut used = 0_u32;
+        for field in &register.fields {
+            let item = format!("{}.{}", register.name, field.name);
+            if let Err(e) = field.validate() {
+                issue(&item, e.to_string());
+                continue;
+            }
+            let (msb, lsb) = (field.bit_range[0], field.bit_range[1]);
+            if msb > 31 {
+                issue(&item, format!("bit {} is out of the 32-bit register", msb));
+                continue;
+            }
+            let mask = (u32::MAX >> (31 - (msb - lsb))) << lsb;
+            if used & mask != 0 {
+                issue(
+                    &item,
+                    format!("bits [{}:{}] overlap another field", msb, lsb),
+                );
+            }
+            used |= mask;
+            lint_explanations(&item, &field.explaination, mask >> lsb, issue);
+        }
+    }
+}
+
+/// Explanation values must be numbers, or `_` for all others, within `max`
+fn lint_explanations(
+    item: &str,
+    explanations: &BTreeMap<String, String>,
+    max: u32,
+    issue: &mut impl FnMut(&str, String),
+) {
+    for value in explanations.keys().filter(|value| *value != "_") {
+        match parse_number(value) {
+            Some(n) if n > max => issue(
+                item,
+                format!("explaination value {} is larger than 0x{:X}", value, max),
+            ),
+            Some(_) => {}
+            None => issue(
+                item,
+                format!("explaination value {:?} is not a number", value),
+            ),
+        }
+    }
 }
 
 /// Represents an MCU chip
@@ -59,6 +244,197 @@ pub struct Chip {
 
     #[serde(default)]
     pub config_registers: Vec<ConfigRegister>,
+    timing: Option<Timing>,
+    #[serde(default)]
+    min_bootloader_version: BTreeMap<Capability, BootloaderVersion>,
+    #[serde(default)]
+    quirks: Vec<QuirkRule>,
+    sector_size: Option<u32>,
+    chunk_size: Option<usize>,
+    max_baudrate: Option<u32>,
+    core: Option<Core>,
+    write_protect_block: Option<u32>,
+    /// Full part numbers of the variant entry, see [`Chip::part_number`]
+    #[serde(default)]
+    parts: Vec<Part>,
+    #[serde(skip)]
+    part_number: Option<String>,
+}
+
+/// A full part number of a chip variant entry, with the package suffix, e.g. `CH32V103R8T6`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Part {
+    pub name: String,
+    /// Chip ID the bootloader of this part reports
+    pub chip_id: u8,
+}
+
+/// CPU core of a chip, decides what the start of a firmware image looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Core {
+    /// Vector table starting with a jump to the reset handler
+    Riscv,
+    /// Cortex-M, vector table of the initial stack pointer and the reset handler
+    Arm,
+    /// 8051, reset vector at 0x0000
+    E8051,
+}
+
+/// Bootloader version(BTVER), in the format of `[0x00, major, minor, 0x00]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BootloaderVersion(pub [u8; 4]);
+
+impl fmt::Display for BootloaderVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{:x}{:x}.{:x}{:x}", a, b, c, d)
+    }
+}
+
+/// Parse the displayed form, e.g. `02.60`
+impl FromStr for BootloaderVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits: Vec<u8> = s
+            .chars()
+            .filter(|c| *c != '.')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(|| anyhow::format_err!("invalid bootloader version: {}", s))?;
+        let digits: [u8; 4] = digits
+            .try_into()
+            .map_err(|_| anyhow::format_err!("invalid bootloader version: {}", s))?;
+        Ok(BootloaderVersion(digits))
+    }
+}
+
+impl TryFrom<String> for BootloaderVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<BootloaderVersion> for String {
+    fn from(version: BootloaderVersion) -> Self {
+        version.to_string()
+    }
+}
+
+/// Bootloader features missing in old BTVER, see `min_bootloader_version` of SCHEMA.yaml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// EEPROM(data flash) read
+    DataRead,
+    /// Config register read and write with a register mask
+    ConfigMaskRead,
+    /// Serial baudrates other than the default 115200, switched by `SetBaud`
+    SetBaud,
+}
+
+impl Capability {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Capability::DataRead => "EEPROM read",
+            Capability::ConfigMaskRead => "masked config register access",
+            Capability::SetBaud => "custom serial baudrate",
+        }
+    }
+
+    /// What to do instead
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Capability::DataRead => "please update the bootloader with WCHISPTool",
+            Capability::ConfigMaskRead => {
+                "please use `wchisp config info`, or WCHISPTool to change config registers"
+            }
+            Capability::SetBaud => "using the default 115200 baud",
+        }
+    }
+}
+
+/// Bootloader misbehaviours worked around by [`crate::Flashing`], see `quirks` of SCHEMA.yaml
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quirk {
+    /// Only the first 4 bytes of the UID are used for the XOR key, and there is no UID checksum
+    ShortUid,
+    /// Config registers, BTVER and UID have to be read one mask at a time
+    NoMaskAllConfigRead,
+    /// Wait at least `delay` ms after a code flash erase
+    EraseDelay,
+}
+
+/// A [`Quirk`] of the bootloader versions in `from..before`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuirkRule {
+    pub quirk: Quirk,
+    /// The first affected BTVER, `None` for all older ones
+    #[serde(default)]
+    pub from: Option<BootloaderVersion>,
+    /// The first fixed BTVER, `None` for all newer ones
+    #[serde(default)]
+    pub before: Option<BootloaderVersion>,
+    /// Wait in ms, of [`Quirk::EraseDelay`]
+    #[serde(default)]
+    pub delay: Option<u64>,
+}
+
+impl QuirkRule {
+    pub fn applies_to(&self, version: BootloaderVersion) -> bool {
+        self.from.is_none_or(|from| version >= from)
+            && self.before.is_none_or(|before| version < before)
+    }
+}
+
+/// Response timeouts of slow operations, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timing {
+    /// Code flash program of a chunk
+    pub program: u64,
+    /// EEPROM(data flash) program of a chunk
+    pub data_program: u64,
+    /// Code flash erase, the minimum wait
+    pub erase: u64,
+    /// Code flash erase of each 1KiB sector
+    pub erase_per_sector: u64,
+    /// Code flash erase, the maximum wait
+    pub erase_max: u64,
+    /// EEPROM(data flash) erase
+    pub data_erase: u64,
+    /// Wait after a code flash erase before programming, none by default as the erase response
+    /// already signals completion
+    pub erase_settle: u64,
+    /// Wait after programming before verifying
+    pub program_settle: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            program: 300,
+            data_program: 5,
+            erase: 5000,
+            erase_per_sector: 20,
+            erase_max: 60000,
+            data_erase: 1000,
+            erase_settle: 0,
+            program_settle: 0,
+        }
+    }
+}
+
+impl Timing {
+    /// Wait for a code flash erase of `sectors` 1KiB sectors, within `erase..=erase_max`
+    pub fn erase_timeout(&self, sectors: u32) -> u64 {
+        (self.erase_per_sector * sectors as u64).clamp(self.erase, self.erase_max.max(self.erase))
+    }
 }
 
 impl ::std::fmt::Display for Chip {
@@ -66,7 +442,7 @@ impl ::std::fmt::Display for Chip {
         write!(
             f,
             "{}[0x{:02x}{:02x}]",
-            self.name,
+            self.display_name(),
             self.chip_id,
             self.device_type(),
         )
@@ -88,7 +464,7 @@ impl Chip {
 /// but the value of the register when the device is in the flash-able mode.
 ///
 /// Read in LE mode.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConfigRegister {
     pub offset: usize,
     pub name: String,
@@ -104,7 +480,7 @@ pub struct ConfigRegister {
 
 impl ConfigRegister {
     fn validate(&self) -> Result<()> {
-        if self.offset % 4 != 0 {
+        if !self.offset.is_multiple_of(4) {
             anyhow::bail!("Config register offset must be 4-byte aligned");
         }
         for field in &self.fields {
@@ -115,7 +491,7 @@ impl ConfigRegister {
 }
 
 /// A range of bits in a register, with a name and a description
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RegisterField {
     // RangeInclusive is not supported well since serde_yaml 0.9
     pub bit_range: Vec<u8>,
@@ -188,13 +564,96 @@ impl ChipDB {
                     device_type
                 )
             })?;
-        // FIXME: better way to patch chip type?
-        chip.mcu_type = family.mcu_type;
-        chip.device_type = family.device_type;
+        chip.part_number = chip
+            .parts
+            .iter()
+            .find(|part| part.chip_id == chip_id)
+            .map(|part| part.name.clone());
         if chip_id != chip.chip_id {
-            log::warn!("Find chip via alternative id: 0x{:02x}", chip.chip_id);
+            if chip.part_number.is_none() {
+                tracing::warn!("Find chip via alternative id: 0x{:02x}", chip.chip_id);
+            }
             chip.chip_id = chip_id;
         }
+        Ok(Self::patch_chip(family, chip))
+    }
+
+    /// Find chip by name, case-insensitive. The first chip with the name as prefix is returned.
+    ///
+    /// A full part number of a variant entry, e.g. `CH32V103R8T6`, selects the part.
+    pub fn find_chip_by_name(&self, name: &str) -> Result<Chip> {
+        let name = name.to_uppercase();
+        let variants = || {
+            self.families
+                .iter()
+                .flat_map(|f| f.variants.iter().map(move |c| (f, c)))
+        };
+        if let Some((family, chip, part)) = variants().find_map(|(f, c)| {
+            c.parts
+                .iter()
+                .find(|part| part.name.to_uppercase() == name)
+                .map(|part| (f, c, part))
+        }) {
+            let mut chip = chip.clone();
+            chip.chip_id = part.chip_id;
+            chip.part_number = Some(part.name.clone());
+            return Ok(Self::patch_chip(family, chip));
+        }
+        variants()
+            .find(|(_, c)| c.name.to_uppercase().starts_with(&name))
+            .map(|(f, c)| Self::patch_chip(f, c.clone()))
+            .ok_or_else(|| anyhow::format_err!("Cannot find chip with name {}", name))
+    }
+
+    /// A chip not in the database yet, from an identity given by the user.
+    ///
+    /// Family-level properties are used when the device type is known. Otherwise the chip gets
+    /// conservative defaults: no EEPROM, no config registers and the default timing.
+    pub fn unknown_chip(&self, chip_id: u8, device_type: u8, flash_size: u32) -> Chip {
+        let chip = Chip {
+            name: "Unknown".to_string(),
+            chip_id,
+            alt_chip_ids: vec![],
+            mcu_type: device_type.wrapping_sub(0x10),
+            device_type,
+            flash_size,
+            eeprom_size: 0,
+            eeprom_start_addr: 0,
+            support_net: None,
+            support_usb: None,
+            support_serial: None,
+            config_registers: vec![],
+            timing: None,
+            min_bootloader_version: BTreeMap::new(),
+            quirks: vec![],
+            sector_size: None,
+            chunk_size: None,
+            max_baudrate: None,
+            core: None,
+            write_protect_block: None,
+            parts: vec![],
+            part_number: None,
+        };
+        match self.families.iter().find(|f| f.device_type == device_type) {
+            Some(family) => Self::patch_chip(family, chip),
+            None => chip,
+        }
+    }
+
+    /// Family-level default profile of a chip not in the database, without any config register
+    /// map, so that config registers are never written from a guessed layout
+    pub fn generic_chip(&self, chip_id: u8, device_type: u8, flash_size: u32) -> Chip {
+        let mut chip = self.unknown_chip(chip_id, device_type, flash_size);
+        chip.name = "Generic".to_string();
+        chip.config_registers.clear();
+        chip
+    }
+
+    /// Fill chip with family-level properties
+    fn patch_chip(family: &ChipFamily, mut chip: Chip) -> Chip {
+        // FIXME: better way to patch chip type?
+        chip.mcu_type = family.mcu_type;
+        chip.device_type = family.device_type;
         if chip.support_net.is_none() {
             chip.support_net = family.support_net;
         }
@@ -207,7 +666,31 @@ impl ChipDB {
         if chip.config_registers.is_empty() {
             chip.config_registers = family.config_registers.clone();
         }
-        Ok(chip)
+        if chip.timing.is_none() {
+            chip.timing = family.timing;
+        }
+        if chip.min_bootloader_version.is_empty() {
+            chip.min_bootloader_version = family.min_bootloader_version.clone();
+        }
+        if chip.quirks.is_empty() {
+            chip.quirks = family.quirks.clone();
+        }
+        if chip.sector_size.is_none() {
+            chip.sector_size = family.sector_size;
+        }
+        if chip.chunk_size.is_none() {
+            chip.chunk_size = family.chunk_size;
+        }
+        if chip.max_baudrate.is_none() {
+            chip.max_baudrate = family.max_baudrate;
+        }
+        if chip.core.is_none() {
+            chip.core = family.core;
+        }
+        if chip.write_protect_block.is_none() {
+            chip.write_protect_block = family.write_protect_block;
+        }
+        chip
     }
 }
 
@@ -217,6 +700,158 @@ impl Chip {
         self.mcu_type + 0x10
     }
 
+    /// Operation timeouts, from the chip or family definition
+    pub fn timing(&self) -> Timing {
+        self.timing.unwrap_or_default()
+    }
+
+    /// Override the timing of the chip database, e.g. from the command line
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = Some(timing);
+    }
+
+    /// The first bootloader version supporting `capability`, `None` if all versions do
+    pub fn min_bootloader_version(&self, capability: Capability) -> Option<BootloaderVersion> {
+        self.min_bootloader_version.get(&capability).copied()
+    }
+
+    /// The rule of `quirk` applying to the bootloader `version`, if any
+    pub fn quirk(&self, quirk: Quirk, version: BootloaderVersion) -> Option<&QuirkRule> {
+        self.quirks
+            .iter()
+            .find(|rule| rule.quirk == quirk && rule.applies_to(version))
+    }
+
+    /// Whether any bootloader version has `quirk`
+    pub fn may_have_quirk(&self, quirk: Quirk) -> bool {
+        self.quirks.iter().any(|rule| rule.quirk == quirk)
+    }
+
+    /// Match the chip name against `pattern`, case-sensitive.
+    ///
+    /// - `/CH58[23]/`: regex, unanchored
+    /// - `CH32V30*`: glob with `*`, `?` and `[...]`, matching the whole name
+    /// - `CH32V307`: name prefix
+    pub fn matches_name(&self, pattern: &str) -> Result<bool> {
+        if let Some(re) = pattern
+            .strip_prefix('/')
+            .and_then(|p| p.strip_suffix('/'))
+            .filter(|p| !p.is_empty())
+        {
+            let re = regex::Regex::new(re)
+                .map_err(|e| anyhow::format_err!("Invalid chip name regex {}: {}", pattern, e))?;
+            Ok(re.is_match(&self.name))
+        } else if pattern.contains(['*', '?', '[']) {
+            let mut re = String::from("^");
+            let mut in_class = false;
+            for c in pattern.chars() {
+                match c {
+                    '*' if !in_class => re.push_str(".*"),
+                    '?' if !in_class => re.push('.'),
+                    '[' if !in_class => {
+                        in_class = true;
+                        re.push('[');
+                    }
+                    ']' if in_class => {
+                        in_class = false;
+                        re.push(']');
+                    }
+                    '-' | '^' if in_class => re.push(c),
+                    _ => re.push_str(&regex::escape(&c.to_string())),
+                }
+            }
+            re.push('$');
+            let re = regex::Regex::new(&re)
+                .map_err(|e| anyhow::format_err!("Invalid chip name glob {}: {}", pattern, e))?;
+            Ok(re.is_match(&self.name))
+        } else {
+            Ok(self.name.starts_with(pattern))
+        }
+    }
+
+    /// Code flash sector size, images are padded to a multiple of it
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size.unwrap_or(SECTOR_SIZE as u32)
+    }
+
+    /// Addresses of the EEPROM(data flash) in firmware images, `None` unless it is mapped above
+    /// the code flash
+    pub fn eeprom_window(&self) -> Option<Range<u32>> {
+        (self.eeprom_size > 0 && self.eeprom_start_addr >= self.flash_size)
+            .then(|| self.eeprom_start_addr..self.eeprom_start_addr + self.eeprom_size)
+    }
+
+    /// Largest data chunk of a Program or Verify command the bootloader accepts
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(CHUNK_SIZE)
+    }
+
+    /// Highest serial baudrate the bootloader switches to reliably, `None` if not limited
+    pub fn max_baudrate(&self) -> Option<u32> {
+        self.max_baudrate
+    }
+
+    /// Full part number with the package suffix, `None` unless the variant entry lists parts and
+    /// the chip ID tells which one it is
+    pub fn part_number(&self) -> Option<&str> {
+        self.part_number.as_deref()
+    }
+
+    /// The part number if known, otherwise the variant name
+    pub fn display_name(&self) -> &str {
+        self.part_number().unwrap_or(&self.name)
+    }
+
+    /// CPU core, `None` if not known
+    pub fn core(&self) -> Option<Core> {
+        self.core
+    }
+
+    /// Code flash bytes write-protected by each bit of the WPR register, `None` if not supported
+    pub fn write_protect_block(&self) -> Option<u32> {
+        self.write_protect_block
+    }
+
+    /// WPR bits covering code flash `sectors` of [`Chip::sector_size`], set bits are the blocks
+    /// to (un)protect. The last bit covers the rest of the code flash, as on larger chips.
+    pub fn write_protect_mask(&self, sectors: Range<u32>) -> Result<u32> {
+        let block = self
+            .write_protect_block
+            .ok_or_else(|| anyhow::format_err!("{} has no sector write protection", self.name))?;
+        let sector_size = self.sector_size();
+        let sector_count = self.flash_size.div_ceil(sector_size);
+        anyhow::ensure!(
+            sectors.start < sectors.end && sectors.end <= sector_count,
+            "invalid sectors {}..{}, code flash has {} sectors",
+            sectors.start,
+            sectors.end,
+            sector_count
+        );
+        let per_block = block / sector_size;
+        // NOTE: the last bit protects everything from its block on
+        let last_start = 31 * per_block;
+        let on_boundary = |sector: u32| {
+            sector == sector_count || (sector.is_multiple_of(per_block) && sector <= last_start)
+        };
+        anyhow::ensure!(
+            on_boundary(sectors.start) && on_boundary(sectors.end),
+            "sectors {}..{} are not whole write protection blocks of {} sectors",
+            sectors.start,
+            sectors.end,
+            per_block
+        );
+        let bits = sectors.start / per_block..sectors.end.div_ceil(per_block).min(32);
+        Ok(bits.fold(0, |mask, bit| mask | (1 << bit)))
+    }
+
+    /// Code flash address ranges write-protected by `wpr`, where cleared bits protect, merged
+    pub fn write_protected_ranges(&self, wpr: u32) -> Vec<Range<u32>> {
+        match self.write_protect_block {
+            Some(block) => write_protected_ranges(block, self.flash_size, wpr),
+            None => vec![],
+        }
+    }
+
     /// Used when erasing 1K sectors
     pub const fn min_erase_sector_number(&self) -> u32 {
         if self.device_type() == 0x10 {
@@ -227,8 +862,8 @@ impl Chip {
     }
 
     /// Used when calculating XOR key
-    pub const fn uid_size(&self) -> usize {
-        if self.device_type() == 0x11 {
+    pub fn uid_size(&self, version: BootloaderVersion) -> usize {
+        if self.quirk(Quirk::ShortUid, version).is_some() {
             4
         } else {
             8
@@ -239,6 +874,15 @@ impl Chip {
     pub fn support_code_flash_protect(&self) -> bool {
         [0x14, 0x15, 0x17, 0x18, 0x19, 0x20].contains(&self.device_type())
     }
+
+    /// ISP over `link` support, by `support_usb` and `support_serial`, assumed if not listed
+    pub fn support_link(&self, link: Link) -> bool {
+        match link {
+            Link::Usb => self.support_usb,
+            Link::Serial => self.support_serial,
+        }
+        .unwrap_or(true)
+    }
 }
 
 fn parse_alt_chip_id_or_all_marker<'de, D>(
@@ -247,52 +891,72 @@ fn parse_alt_chip_id_or_all_marker<'de, D>(
 where
     D: serde::Deserializer<'de>,
 {
+    use serde::de::Error;
+
     let ids: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
-    Ok(ids
-        .into_iter()
-        .flat_map(|i| {
-            if i.starts_with("0x") || i.starts_with("0X") {
-                vec![i[2..].parse().unwrap()]
-            } else if i == "all" || i == "ALL" {
-                (0..=0xff).into_iter().collect()
-            } else {
-                vec![i.parse().unwrap()]
-            }
-        })
-        .collect())
+    let mut parsed = vec![];
+    for id in ids {
+        if id == "all" || id == "ALL" {
+            parsed.extend(0..=0xff);
+        } else {
+            let id = parse_number(&id)
+                .and_then(|n| u8::try_from(n).ok())
+                .ok_or_else(|| D::Error::custom(format!("invalid chip id {:?}", id)))?;
+            parsed.push(id);
+        }
+    }
+    Ok(parsed)
 }
 
 fn parse_address_and_offset<'de, D>(deserializer: D) -> std::result::Result<u32, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
+    use serde::de::Error;
+
     let s: String = serde::Deserialize::deserialize(deserializer)?;
-    if s.starts_with("0x") || s.starts_with("0X") {
-        Ok(u32::from_str_radix(&s[2..], 16).expect(&format!("error while parsering {:?}", s)))
-    } else if s.ends_with("K") {
-        Ok(1024
-            * u32::from_str_radix(&s[..s.len() - 1], 10)
-                .expect(&format!("error while parsering {:?}", s)))
-    } else if s.ends_with("KiB") {
-        Ok(1024
-            * u32::from_str_radix(&s[..s.len() - 3], 10)
-                .expect(&format!("error while parsering {:?}", s)))
-    } else if s.ends_with("KB") {
-        Ok(1024
-            * u32::from_str_radix(&s[..s.len() - 2], 10)
-                .expect(&format!("error while parsering {:?}", s)))
+    let (digits, scale) = if let Some(kib) = s
+        .strip_suffix("KiB")
+        .or_else(|| s.strip_suffix("KB"))
+        .or_else(|| s.strip_suffix('K'))
+    {
+        (kib, 1024)
     } else {
-        // parse pure digits here
-        Ok(s.parse().unwrap())
+        (s.as_str(), 1)
+    };
+    parse_number(digits)
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| D::Error::custom(format!("invalid address or size {:?}", s)))
+}
+
+/// Parse a decimal, `0x` hex or `0b` binary number, `None` if invalid
+/// See [`Chip::write_protected_ranges`], `block` bytes per WPR bit
+pub(crate) fn write_protected_ranges(block: u32, flash_size: u32, wpr: u32) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = vec![];
+    for bit in (0..32).filter(|bit| wpr & (1 << bit) == 0) {
+        let start = bit * block;
+        if start >= flash_size {
+            break;
+        }
+        let end = if bit == 31 {
+            flash_size
+        } else {
+            (start + block).min(flash_size)
+        };
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
     }
+    ranges
 }
 
 pub fn parse_number(s: &str) -> Option<u32> {
     if s.starts_with("0x") || s.starts_with("0X") {
-        Some(u32::from_str_radix(&s[2..], 16).expect(&format!("error while parsering {:?}", s)))
+        u32::from_str_radix(&s[2..], 16).ok()
     } else if s.starts_with("0b") || s.starts_with("0B") {
-        Some(u32::from_str_radix(&s[2..], 2).expect(&format!("error while parsering {:?}", s)))
+        u32::from_str_radix(&s[2..], 2).ok()
     } else {
-        Some(s.parse().expect("must be a number"))
+        s.parse().ok()
     }
 }
@@ -0,0 +1,290 @@
+//! Environment diagnostics, `wchisp doctor`
+use anyhow::Result;
+
+use wchisp::{
+    transport::{SerialTransport, UsbTransport},
+    Flashing,
+};
+
+/// Rules file written by `wchisp install-udev`
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/50-wchisp.rules";
+
+/// udev rules for both ISP vendor IDs, world-accessible, or accessible by `group` only
+pub fn udev_rules(group: Option<&str>) -> String {
+    let access = match group {
+        Some(group) => format!("GROUP=\"{}\", MODE=\"0660\"", group),
+        None => "MODE=\"0666\"".to_string(),
+    };
+    ["4348", "1a86"]
+        .iter()
+        .map(|vid| {
+            format!(
+                "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{}\", ATTRS{{idProduct}}==\"55e0\", {}\n",
+                vid, access
+            )
+        })
+        .collect()
+}
+
+/// Write the udev rules to `path`, reload udev, then check the device nodes of connected devices
+#[cfg(target_os = "linux")]
+pub fn install_udev(path: &str, group: Option<&str>) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    match std::fs::write(path, udev_rules(group)) {
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            anyhow::bail!(
+                "permission denied writing {}, run `sudo wchisp install-udev`",
+                path
+            )
+        }
+        result => result?,
+    }
+    tracing::info!("udev rules written to {}", path);
+
+    let mut reloaded = true;
+    for args in [
+        &["control", "--reload-rules"][..],
+        &["trigger", "--subsystem-match=usb"],
+        &["settle", "--timeout=10"],
+    ] {
+        match std::process::Command::new("udevadm").args(args).status() {
+            Ok(status) if status.success() => continue,
+            Ok(status) => tracing::warn!("`udevadm {}` failed: {}", args.join(" "), status),
+            Err(e) => tracing::warn!("Failed to run `udevadm {}`: {}", args.join(" "), e),
+        }
+        reloaded = false;
+    }
+    if reloaded {
+        tracing::info!("udev rules reloaded");
+    } else {
+        tracing::warn!("hint: the rules apply after a reboot, or once udev is reloaded");
+    }
+
+    let devices = match UsbTransport::list_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            tracing::warn!("Failed to enumerate USB devices: {}", e);
+            return Ok(());
+        }
+    };
+    if devices.is_empty() {
+        tracing::info!(
+            "No WCH ISP USB device connected, run `wchisp doctor` after plugging one in"
+        );
+    }
+    // same bits as the rules above, group or other read-write
+    let required = if group.is_some() { 0o060 } else { 0o006 };
+    for dev in &devices {
+        let node = format!("/dev/bus/usb/{:03}/{:03}", dev.bus_number, dev.address);
+        let meta = std::fs::metadata(&node)?;
+        let mode = meta.mode() & 0o777;
+        if mode & required == required {
+            tracing::info!("\t{}: mode {:04o}, gid {}, OK", node, mode, meta.gid());
+        } else {
+            tracing::warn!(
+                "\t{}: mode {:04o}, gid {}, rules not applied",
+                node,
+                mode,
+                meta.gid()
+            );
+            tracing::warn!("hint: unplug and replug the device");
+        }
+    }
+    Ok(())
+}
+
+/// Device interface GUID registered for WinUSB, any GUID works as libusb enumerates by VID:PID
+const WINUSB_INTERFACE_GUID: &str = "{2a3c8f1e-6b4d-4e57-9c21-8d5f0b7a3e64}";
+
+/// WinUSB driver package INF for both ISP vendor IDs, binding the inbox `winusb.inf`
+pub fn winusb_inf() -> String {
+    let mut inf = String::from(
+        "; WinUSB driver for WCH ISP bootloaders, generated by `wchisp install-driver`\r\n\
+         [Version]\r\n\
+         Signature = \"$Windows NT$\"\r\n\
+         Class = USBDevice\r\n\
+         ClassGUID = {88BAE032-5A81-49f0-BC3D-A4FF138216D6}\r\n\
+         Provider = %ManufacturerName%\r\n\
+         DriverVer = 01/01/2024,1.0.0.0\r\n\
+         \r\n\
+         [Manufacturer]\r\n\
+         %ManufacturerName% = Standard,NTamd64,NTx86,NTarm64\r\n",
+    );
+    for arch in ["NTamd64", "NTx86", "NTarm64"] {
+        inf.push_str(&format!("\r\n[Standard.{}]\r\n", arch));
+        for vid in ["4348", "1A86"] {
+            inf.push_str(&format!(
+                "%DeviceName% = USB_Install, USB\\VID_{}&PID_55E0\r\n",
+                vid
+            ));
+        }
+    }
+    inf.push_str(&format!(
+        "\r\n\
+         [USB_Install]\r\n\
+         Include = winusb.inf\r\n\
+         Needs = WINUSB.NT\r\n\
+         \r\n\
+         [USB_Install.Services]\r\n\
+         Include = winusb.inf\r\n\
+         Needs = WINUSB.NT.Services\r\n\
+         \r\n\
+         [USB_Install.HW]\r\n\
+         AddReg = Dev_AddReg\r\n\
+         \r\n\
+         [Dev_AddReg]\r\n\
+         HKR,,DeviceInterfaceGUIDs,0x10000,\"{}\"\r\n\
+         \r\n\
+         [Strings]\r\n\
+         ManufacturerName = \"wchisp\"\r\n\
+         DeviceName = \"WCH ISP Bootloader\"\r\n",
+        WINUSB_INTERFACE_GUID
+    ));
+    inf
+}
+
+/// Write the WinUSB INF to a temporary directory and install it with `pnputil`
+#[cfg(target_os = "windows")]
+pub fn install_driver() -> Result<()> {
+    let dir = std::env::temp_dir().join("wchisp-winusb");
+    std::fs::create_dir_all(&dir)?;
+    let inf = dir.join("wchisp.inf");
+    std::fs::write(&inf, winusb_inf())?;
+    tracing::info!("Driver package written to {}", inf.display());
+
+    let status = std::process::Command::new("pnputil")
+        .arg("/add-driver")
+        .arg(&inf)
+        .arg("/install")
+        .status()?;
+    if !status.success() {
+        tracing::warn!("hint: run from an administrator prompt");
+        tracing::warn!("hint: Windows may refuse the unsigned driver package, install the WinUSB driver with Zadig instead, https://zadig.akeo.ie");
+        anyhow::bail!("pnputil failed: {}", status);
+    }
+    tracing::info!("WinUSB driver installed, replug the device and run `wchisp doctor` to check");
+    Ok(())
+}
+
+/// Run all checks, returns the number of problems found.
+pub fn run() -> Result<usize> {
+    let mut problems = 0;
+
+    #[cfg(target_os = "linux")]
+    {
+        problems += check_udev_rules();
+        problems += check_port_grabbers();
+    }
+    problems += check_usb_devices()?;
+    problems += check_serial_ports();
+
+    if problems == 0 {
+        tracing::info!("No problems found");
+    } else {
+        tracing::warn!("{} problem(s) found", problems);
+    }
+    Ok(problems)
+}
+
+#[cfg(target_os = "linux")]
+fn check_udev_rules() -> usize {
+    const RULES_DIRS: &[&str] = &[
+        "/etc/udev/rules.d",
+        "/lib/udev/rules.d",
+        "/usr/lib/udev/rules.d",
+    ];
+
+    let found = RULES_DIRS
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+        .any(|rules| rules.contains("55e0") && (rules.contains("4348") || rules.contains("1a86")));
+    if found {
+        tracing::info!("udev rules for WCH ISP devices: found");
+        0
+    } else {
+        tracing::warn!("udev rules for WCH ISP devices: not found");
+        tracing::warn!("hint: run `sudo wchisp install-udev`, or add the following lines to {}, then run `sudo udevadm control --reload-rules && sudo udevadm trigger`", UDEV_RULES_PATH);
+        for line in udev_rules(None).lines() {
+            tracing::warn!("  {}", line);
+        }
+        1
+    }
+}
+
+/// ModemManager and brltty probe or grab newly attached serial ports.
+#[cfg(target_os = "linux")]
+fn check_port_grabbers() -> usize {
+    const GRABBERS: &[&str] = &["ModemManager", "brltty"];
+
+    let running: Vec<String> = std::fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| std::fs::read_to_string(entry.path().join("comm")).ok())
+                .map(|comm| comm.trim().to_string())
+                .filter(|comm| GRABBERS.contains(&comm.as_str()))
+                .collect()
+        })
+        .unwrap_or_default();
+    for name in &running {
+        tracing::warn!(
+            "{} is running, it might hold serial ports after plug-in",
+            name
+        );
+        match name.as_str() {
+            "ModemManager" => tracing::warn!("hint: stop it via `sudo systemctl stop ModemManager`, or wait ~10s before using the port"),
+            _ => tracing::warn!("hint: remove it via your package manager if you do not use a braille display"),
+        }
+    }
+    running.len()
+}
+
+fn check_usb_devices() -> Result<usize> {
+    let ndevices = match UsbTransport::scan_devices() {
+        Ok(n) => n,
+        Err(e) => {
+            tracing::warn!("Failed to enumerate USB devices: {}", e);
+            tracing::warn!("hint: check libusb is installed and accessible");
+            return Ok(1);
+        }
+    };
+    tracing::info!("Found {} WCH ISP USB device(s)", ndevices);
+
+    let mut problems = 0;
+    for i in 0..ndevices {
+        // NOTE: open_nth logs the platform-specific reason on failure
+        match UsbTransport::open_nth(i).and_then(|mut trans| Flashing::get_chip(&mut trans)) {
+            Ok(chip) => tracing::info!("\tDevice #{}: {}, OK", i, chip),
+            Err(e) => {
+                tracing::warn!("\tDevice #{}: present but not usable: {}", i, e);
+                #[cfg(target_os = "windows")]
+                tracing::warn!("hint: run `wchisp install-driver` as administrator, or install the WinUSB driver with Zadig, https://zadig.akeo.ie");
+                #[cfg(target_os = "linux")]
+                tracing::warn!(
+                    "hint: check udev rules, or whether another process is using the device"
+                );
+                problems += 1;
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn check_serial_ports() -> usize {
+    match SerialTransport::list_ports() {
+        Ok(ports) => {
+            tracing::info!("Found {} serial port(s)", ports.len());
+            for p in ports {
+                tracing::info!("\t{}", p);
+            }
+            0
+        }
+        Err(e) => {
+            tracing::warn!("Failed to enumerate serial ports: {}", e);
+            1
+        }
+    }
+}
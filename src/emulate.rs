@@ -0,0 +1,113 @@
+//! Software bootloader on a TCP socket or pty, `wchisp emulate`
+use std::net::TcpListener;
+
+use anyhow::Result;
+
+use wchisp::{constants::commands, emulator::Emulator};
+
+/// Where clients connect to
+pub enum Endpoint<'a> {
+    Tcp(&'a str),
+    Pty,
+}
+
+/// Serve clients one after another until killed, saving the code flash to `save` after each session
+pub fn run(mut emulator: Emulator, endpoint: Endpoint, save: Option<&str>) -> Result<()> {
+    tracing::info!(
+        "Emulating {}, UID {}",
+        emulator.chip(),
+        hex::encode(emulator.uid())
+    );
+    let save = |emulator: &Emulator| -> Result<()> {
+        if let Some(path) = save {
+            std::fs::write(path, &emulator.flash)?;
+            tracing::info!("Code flash saved to {}", path);
+        }
+        Ok(())
+    };
+
+    match endpoint {
+        Endpoint::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            tracing::info!(
+                "Listening on {}, connect with `wchisp -s -p tcp://{}`",
+                listener.local_addr()?,
+                listener.local_addr()?
+            );
+            for stream in listener.incoming() {
+                let mut stream = stream?;
+                tracing::info!("Client connected: {}", stream.peer_addr()?);
+                if let Err(e) = emulator.serve(&mut stream) {
+                    tracing::warn!("Client error: {:#}", e);
+                }
+                tracing::info!("Client disconnected");
+                save(&emulator)?;
+            }
+        }
+        Endpoint::Pty => {
+            let (mut master, _slave, name) = pty::open()?;
+            tracing::info!("Serving on {}, connect with `wchisp -s -p {}`", name, name);
+            // NOTE: the pty stays open between clients, so save at the end of each ISP session
+            while let Some(cmd) = emulator.serve_request(&mut master)? {
+                if cmd == commands::ISP_END {
+                    save(&emulator)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::{
+        ffi::CStr,
+        fs::File,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    use anyhow::Result;
+
+    /// Open a pseudo terminal, returning the master side, the slave side and its name
+    pub fn open() -> Result<(File, File, String)> {
+        // SAFETY: plain libc calls on a freshly opened fd, owned by the returned `File`
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // NOTE: the slave side is kept open in raw mode, otherwise the master reads fail with
+            // EIO while no client is connected, and the line discipline echoes responses back
+            let slave = File::options().read(true).write(true).open(&name)?;
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            Ok((master, slave, name))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod pty {
+    use anyhow::Result;
+
+    pub fn open() -> Result<(std::fs::File, std::fs::File, String)> {
+        anyhow::bail!("--pty is only supported on Unix")
+    }
+}
@@ -0,0 +1,286 @@
+//! A software WCH ISP bootloader, a hardware-free target for tests, demos and CI.
+//!
+//! [`Emulator`] answers protocol commands against an in-memory code flash, EEPROM and config
+//! registers of a chip from the chip database. It is a [`Transport`] itself, for in-process use,
+//! and [`Emulator::serve`] speaks the serial framing over any stream, e.g. a TCP socket or a pty.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use wchisp::{device::ChipDB, emulator::Emulator, Flashing};
+//!
+//! let chip = ChipDB::load()?.find_chip_by_name("CH32V307")?;
+//! let mut flashing = Flashing::new_from_transport(Emulator::new(chip))?;
+//! flashing.flash(0, &[0x13, 0x00, 0x00, 0x00])?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    time::Duration,
+};
+
+use anyhow::Result;
+use scroll::{Pread, Pwrite, LE};
+
+use crate::{
+    constants::{
+        commands, CFG_MASK_ALL, CFG_MASK_BTVER, CFG_MASK_RDPR_USER_DATA_WPR, CFG_MASK_UID,
+        SECTOR_SIZE,
+    },
+    device::{BootloaderVersion, Quirk},
+    flashing::xor_key,
+    transport::framed::{self, FrameError},
+    Chip, Transport,
+};
+
+/// Content of erased code flash and EEPROM
+const ERASED: u8 = 0xff;
+/// First payload byte of a failed command
+const STATUS_FAILED: u8 = 0xfe;
+/// RDPR value of unprotected code flash
+const RDPR_UNPROTECTED: u8 = 0xa5;
+/// Size of the RDPR, USER, DATA and WPR registers
+const CONFIG_SIZE: usize = 12;
+
+pub struct Emulator {
+    chip: Chip,
+    uid: [u8; 8],
+    bootloader_version: BootloaderVersion,
+    /// RDPR, USER, DATA and WPR registers
+    config: [u8; CONFIG_SIZE],
+    key: [u8; 8],
+    /// Code flash content
+    pub flash: Vec<u8>,
+    /// EEPROM(data flash) content
+    pub eeprom: Vec<u8>,
+    /// Responses not received yet, when used as a [`Transport`]
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl Emulator {
+    /// Erased chip with a random UID, unprotected, config registers at their reset values
+    pub fn new(chip: Chip) -> Self {
+        let mut uid: [u8; 8] = rand::random();
+        // NOTE: the last u16 is the sum of the others, see `Flashing::check_chip_uid`
+        let sum = (0..3)
+            .map(|i| uid.pread_with::<u16>(i * 2, LE).unwrap())
+            .fold(0_u16, u16::wrapping_add);
+        uid.pwrite_with(sum, 6, LE).unwrap();
+
+        let mut config = [
+            0xa5, 0x5a, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff,
+        ];
+        for reg in &chip.config_registers {
+            if let Some(reset) = reg.reset {
+                if reg.offset + 4 <= CONFIG_SIZE {
+                    config.pwrite_with(reset, reg.offset, LE).unwrap();
+                }
+            }
+        }
+
+        Emulator {
+            flash: vec![ERASED; chip.flash_size as usize],
+            eeprom: vec![ERASED; chip.eeprom_size as usize],
+            chip,
+            uid,
+            bootloader_version: BootloaderVersion([0, 2, 9, 0]),
+            config,
+            key: [0; 8],
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Bootloader version to report, 02.90 by default
+    pub fn bootloader_version(mut self, version: BootloaderVersion) -> Self {
+        self.bootloader_version = version;
+        self
+    }
+
+    pub fn chip(&self) -> &Chip {
+        &self.chip
+    }
+
+    pub fn uid(&self) -> &[u8] {
+        &self.uid[..self.chip.uid_size(self.bootloader_version)]
+    }
+
+    fn code_flash_protected(&self) -> bool {
+        self.chip.support_code_flash_protect() && self.config[0] != RDPR_UNPROTECTED
+    }
+
+    /// Answer a raw request, `CMD LEN_LO LEN_HI PAYLOAD...`, with a raw response
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let cmd = request.first().copied().unwrap_or_default();
+        let payload = request.get(3..).unwrap_or_default();
+        tracing::debug!(
+            "emulator <= {} {}",
+            commands::name(cmd),
+            hex::encode(payload)
+        );
+        let resp = self
+            .execute(cmd, payload)
+            .unwrap_or_else(|| vec![STATUS_FAILED, 0x00]);
+
+        let mut raw = vec![cmd, 0x00];
+        raw.extend_from_slice(&(resp.len() as u16).to_le_bytes());
+        raw.extend_from_slice(&resp);
+        raw
+    }
+
+    /// Response payload of a command, `None` if it failed
+    fn execute(&mut self, cmd: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        const OK: [u8; 2] = [0x00, 0x00];
+
+        match cmd {
+            commands::IDENTIFY => Some(vec![self.chip.chip_id, self.chip.device_type]),
+            commands::ISP_END => {
+                let reason = *payload.first()?;
+                tracing::info!("ISP session ended, reason {}", reason);
+                self.key = [0; 8];
+                Some(OK.to_vec())
+            }
+            commands::ISP_KEY => {
+                if payload.is_empty() {
+                    return None;
+                }
+                self.key = xor_key(self.uid(), payload, self.chip.chip_id);
+                let checksum = self.key.iter().fold(0_u8, |acc, &x| acc.wrapping_add(x));
+                Some(vec![checksum, 0x00])
+            }
+            commands::ERASE => {
+                let sectors = payload.pread_with::<u32>(0, LE).ok()?;
+                if self.code_flash_protected() {
+                    return None;
+                }
+                let end = (sectors as usize * SECTOR_SIZE).min(self.flash.len());
+                self.flash[..end].fill(ERASED);
+                tracing::info!("Erased {} code flash sectors", sectors);
+                Some(OK.to_vec())
+            }
+            commands::PROGRAM => {
+                if self.code_flash_protected() {
+                    return None;
+                }
+                let (address, data) = self.decode(payload)?;
+                let target = self.flash.get_mut(address..address + data.len())?;
+                target.copy_from_slice(&data);
+                Some(OK.to_vec())
+            }
+            commands::VERIFY => {
+                let (address, data) = self.decode(payload)?;
+                let matched = self.flash.get(address..address + data.len())? == &data[..];
+                Some(vec![if matched { 0x00 } else { STATUS_FAILED }, 0x00])
+            }
+            commands::READ_CONFIG => {
+                let mask = *payload.first()?;
+                if mask == CFG_MASK_ALL
+                    && self
+                        .chip
+                        .quirk(Quirk::NoMaskAllConfigRead, self.bootloader_version)
+                        .is_some()
+                {
+                    return None;
+                }
+                let mut resp = vec![mask, 0x00];
+                if mask & CFG_MASK_RDPR_USER_DATA_WPR != 0 {
+                    resp.extend_from_slice(&self.config);
+                }
+                if mask & CFG_MASK_BTVER != 0 {
+                    resp.extend_from_slice(&self.bootloader_version.0);
+                }
+                if mask & CFG_MASK_UID != 0 {
+                    resp.extend_from_slice(&self.uid);
+                }
+                Some(resp)
+            }
+            commands::WRITE_CONFIG => {
+                let mask = *payload.first()?;
+                let data = payload.get(2..2 + CONFIG_SIZE)?;
+                if mask & CFG_MASK_RDPR_USER_DATA_WPR != 0 {
+                    let was_protected = self.code_flash_protected();
+                    self.config.copy_from_slice(data);
+                    // NOTE: the same as the real chips, unprotecting erases the code flash
+                    if was_protected && !self.code_flash_protected() {
+                        self.flash.fill(ERASED);
+                        tracing::info!("Code flash unprotected and erased");
+                    }
+                }
+                Some(OK.to_vec())
+            }
+            commands::DATA_ERASE => {
+                let address = payload.pread_with::<u32>(0, LE).ok()? as usize;
+                let sectors = *payload.get(4)? as usize;
+                let end = (address + sectors * 1024).min(self.eeprom.len());
+                self.eeprom.get_mut(address..end)?.fill(ERASED);
+                Some(OK.to_vec())
+            }
+            commands::DATA_PROGRAM => {
+                let (address, data) = self.decode(payload)?;
+                let target = self.eeprom.get_mut(address..address + data.len())?;
+                target.copy_from_slice(&data);
+                Some(OK.to_vec())
+            }
+            commands::DATA_READ => {
+                let address = payload.pread_with::<u32>(0, LE).ok()? as usize;
+                let len = payload.pread_with::<u16>(4, LE).ok()? as usize;
+                let mut resp = OK.to_vec();
+                resp.extend_from_slice(self.eeprom.get(address..address + len)?);
+                Some(resp)
+            }
+            commands::SET_BAUD => Some(OK.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Address and XOR decoded data of `Program`, `Verify` and `DataProgram`
+    fn decode(&self, payload: &[u8]) -> Option<(usize, Vec<u8>)> {
+        let address = payload.pread_with::<u32>(0, LE).ok()? as usize;
+        let data = payload.get(5..)?;
+        let decoded = data
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x ^ self.key[i % 8])
+            .collect();
+        Some((address, decoded))
+    }
+
+    /// Answer one request in the serial framing. Returns the command code, `None` at end of stream.
+    ///
+    /// Requests with a bad prefix or checksum are dropped, as the real bootloader does.
+    pub fn serve_request(&mut self, stream: &mut (impl Read + Write)) -> Result<Option<u8>> {
+        loop {
+            let request = match framed::read_request(stream) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(None),
+                Err(e) if e.is::<FrameError>() => {
+                    tracing::warn!("Dropped request: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            framed::write_response(stream, &self.handle(&request))?;
+            return Ok(Some(request[0]));
+        }
+    }
+
+    /// Answer requests in the serial framing until the end of stream
+    pub fn serve(&mut self, stream: &mut (impl Read + Write)) -> Result<()> {
+        while self.serve_request(stream)?.is_some() {}
+        Ok(())
+    }
+}
+
+impl Transport for Emulator {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        let response = self.handle(raw);
+        self.responses.push_back(response);
+        Ok(())
+    }
+
+    fn recv_raw(&mut self, _timeout: Duration) -> Result<Vec<u8>> {
+        self.responses
+            .pop_front()
+            .ok_or_else(|| anyhow::format_err!("no request sent to the emulator"))
+    }
+}
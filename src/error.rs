@@ -0,0 +1,109 @@
+//! Typed errors, for callers to tell failure classes apart.
+//!
+//! These are wrapped in `anyhow::Error`, use `downcast_ref::<Error>()` to check.
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// No ISP device or serial port found
+    NoDevice(String),
+    /// The connected chip is not the expected one
+    ChipMismatch { expected: String, found: String },
+    /// The operation is refused because code flash is protected
+    FlashProtected,
+    /// Code flash content differs from the firmware
+    VerifyFailed { address: u32 },
+    /// Firmware file cannot be read or parsed
+    BadFirmware(String),
+    /// Sending to or receiving from the device failed, the cause is the error of the link
+    Transport,
+    /// Cancelled via a [`crate::flashing::CancelToken`]
+    Cancelled,
+    /// The bootloader answered with a failure status, see [`crate::protocol::status_description`]
+    Status {
+        command: &'static str,
+        status: u8,
+        description: &'static str,
+    },
+    /// The bootloader is too old for the operation
+    Unsupported {
+        feature: &'static str,
+        bootloader: String,
+        hint: &'static str,
+    },
+}
+
+impl Error {
+    /// Process exit code of the failure class, for scripts and IDE integrations to branch on:
+    /// 1 any other error, 2 no device, 3 chip mismatch, 4 code flash protected, 5 verify failed,
+    /// 6 USB or serial communication error, 7 bad firmware file, 130 cancelled(as for SIGINT)
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::NoDevice(_) => 2,
+            Error::ChipMismatch { .. } => 3,
+            Error::FlashProtected => 4,
+            Error::VerifyFailed { .. } => 5,
+            Error::Transport => 6,
+            Error::BadFirmware(_) => 7,
+            Error::Cancelled => 130,
+            Error::Status { .. } | Error::Unsupported { .. } => 1,
+        }
+    }
+
+    /// Exit code of `err`, see [`Error::code`], looking through `.context()` layers
+    pub fn exit_code(err: &anyhow::Error) -> u8 {
+        if let Some(e) = err.chain().find_map(|e| e.downcast_ref::<Error>()) {
+            return e.code();
+        }
+        // NOTE: I/O errors may as well be of local files, only typed link errors count
+        #[cfg(not(target_arch = "wasm32"))]
+        if err
+            .chain()
+            .any(|e| e.is::<rusb::Error>() || e.is::<serialport::Error>())
+        {
+            return Error::Transport.code();
+        }
+        1
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoDevice(msg) => write!(f, "{}", msg),
+            Error::ChipMismatch { expected, found } => {
+                write!(f, "chip mismatch: has {}, provided {}", found, expected)
+            }
+            Error::FlashProtected => write!(
+                f,
+                "code flash is protected, use `wchisp config unprotect` first"
+            ),
+            Error::VerifyFailed { address } => {
+                write!(f, "Verify failed, mismatch in chunk at 0x{:08x}", address)
+            }
+            Error::BadFirmware(path) => write!(f, "cannot read firmware file {}", path),
+            Error::Transport => write!(f, "communication with the device failed"),
+            Error::Cancelled => write!(f, "cancelled, device reset"),
+            Error::Status {
+                command,
+                status,
+                description,
+            } => write!(
+                f,
+                "{} failed: {}, status 0x{:02x}",
+                command, description, status
+            ),
+            Error::Unsupported {
+                feature,
+                bootloader,
+                hint,
+            } => write!(
+                f,
+                "bootloader {} does not support {}, {}",
+                bootloader, feature, hint
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
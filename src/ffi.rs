@@ -0,0 +1,459 @@
+//! C bindings, enabled by the `ffi` feature.
+//!
+//! See `include/wchisp.h` for the C declarations. All functions return `0` on success,
+//! or the same failure class code as the `wchisp` process exit code. The message of the
+//! last error on the calling thread is available via [`wchisp_last_error`].
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ops::{Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use anyhow::Result;
+
+use crate::{
+    flashing::BoxedFlashing,
+    format::{extend_firmware_to_sector_boundary, read_firmware_segments, ReadOptions, Segment},
+    progress::{NoProgress, ProgressSink},
+    transport::UsbTransport,
+    Baudrate, Error, Flashing,
+};
+
+/// Erase the code flash before flashing
+pub const WCHISP_FLASH_ERASE: u32 = 1 << 0;
+/// Verify the code flash after flashing
+pub const WCHISP_FLASH_VERIFY: u32 = 1 << 1;
+/// Reset the chip after flashing
+pub const WCHISP_FLASH_RESET: u32 = 1 << 2;
+
+/// Progress callback, `phase` is only valid during the call
+pub type WchispProgressCallback =
+    Option<extern "C" fn(phase: *const c_char, done: u64, total: u64, user_data: *mut c_void)>;
+
+/// Opaque handle of a connected chip
+pub struct WchispHandle {
+    flashing: BoxedFlashing<'static>,
+}
+
+/// Chip info, see [`crate::flashing::ChipInfo`]
+#[repr(C)]
+pub struct WchispChipInfo {
+    /// NUL-terminated chip name
+    pub name: [c_char; 32],
+    pub chip_id: u8,
+    pub device_type: u8,
+    pub flash_size: u32,
+    pub eeprom_size: u32,
+    pub uid: [u8; 8],
+    pub uid_len: u32,
+    pub bootloader_version: [u8; 4],
+    /// 1 if protected, 0 if not, -1 if the chip doesn't support code flash protection
+    pub code_flash_protected: c_int,
+    /// NUL-terminated full part number, empty if not known
+    pub part_number: [c_char; 32],
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Failure class code of an error, the same as the process exit codes
+fn error_code(err: &anyhow::Error) -> c_int {
+    Error::exit_code(err).into()
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Run `f`, turning errors and panics into a return code
+fn ffi_call(f: impl FnOnce() -> Result<()>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            let code = error_code(&err);
+            set_last_error(format!("{:#}", err));
+            code
+        }
+        Err(_) => {
+            set_last_error("panic in wchisp".to_string());
+            1
+        }
+    }
+}
+
+unsafe fn str_arg<'s>(s: *const c_char) -> Result<&'s str> {
+    anyhow::ensure!(!s.is_null(), "null string argument");
+    Ok(CStr::from_ptr(s).to_str()?)
+}
+
+unsafe fn handle_arg<'h>(handle: *mut WchispHandle) -> Result<&'h mut WchispHandle> {
+    handle
+        .as_mut()
+        .ok_or_else(|| anyhow::format_err!("null handle"))
+}
+
+struct CallbackProgress {
+    callback: extern "C" fn(*const c_char, u64, u64, *mut c_void),
+    user_data: *mut c_void,
+    phase: CString,
+    done: u64,
+    total: u64,
+}
+
+// SAFETY: `user_data` is only passed back to the callback, the C caller owns what it points to
+unsafe impl Send for CallbackProgress {}
+
+impl CallbackProgress {
+    fn report(&self) {
+        (self.callback)(self.phase.as_ptr(), self.done, self.total, self.user_data);
+    }
+}
+
+impl ProgressSink for CallbackProgress {
+    fn begin(&mut self, phase: &'static str, total: u64) {
+        self.phase = CString::new(phase).unwrap_or_default();
+        self.done = 0;
+        self.total = total;
+        self.report();
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.done += delta;
+        self.report();
+    }
+
+    fn finish(&mut self) {
+        self.report();
+    }
+}
+
+fn progress_sink(
+    callback: WchispProgressCallback,
+    user_data: *mut c_void,
+) -> Box<dyn ProgressSink> {
+    match callback {
+        Some(callback) => Box::new(CallbackProgress {
+            callback,
+            user_data,
+            phase: CString::default(),
+            done: 0,
+            total: 0,
+        }),
+        None => Box::new(NoProgress),
+    }
+}
+
+/// The progress callback of one call, removed on drop, also on errors and panics, since the
+/// caller's `user_data` may not outlive the call
+struct CallbackGuard<'h>(&'h mut BoxedFlashing<'static>);
+
+impl<'h> CallbackGuard<'h> {
+    fn new(
+        flashing: &'h mut BoxedFlashing<'static>,
+        callback: WchispProgressCallback,
+        user_data: *mut c_void,
+    ) -> Self {
+        flashing.set_progress(progress_sink(callback, user_data));
+        CallbackGuard(flashing)
+    }
+}
+
+impl Deref for CallbackGuard<'_> {
+    type Target = BoxedFlashing<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl DerefMut for CallbackGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+impl Drop for CallbackGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set_progress(Box::new(NoProgress));
+    }
+}
+
+fn new_handle(mut flashing: BoxedFlashing<'static>) -> *mut WchispHandle {
+    flashing.set_progress(Box::new(NoProgress));
+    Box::into_raw(Box::new(WchispHandle { flashing }))
+}
+
+/// Message of the last error on the calling thread, or NULL.
+///
+/// The string is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn wchisp_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Number of connected WCH ISP USB devices, or a negative failure class code.
+#[no_mangle]
+pub extern "C" fn wchisp_probe_usb() -> c_int {
+    let mut n = 0;
+    let code = ffi_call(|| {
+        n = UsbTransport::scan_devices()? as c_int;
+        Ok(())
+    });
+    if code == 0 {
+        n
+    } else {
+        -code
+    }
+}
+
+/// Open the USB device at `index`, or the first one if `index` is negative.
+///
+/// # Safety
+///
+/// `out` must be a valid pointer, the handle must be released by [`wchisp_close`].
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_open_usb(index: c_int, out: *mut *mut WchispHandle) -> c_int {
+    ffi_call(|| {
+        anyhow::ensure!(!out.is_null(), "null output argument");
+        let device = usize::try_from(index).ok();
+        *out = new_handle(Flashing::new_from_usb(device)?.boxed());
+        Ok(())
+    })
+}
+
+/// Open the serial port `port`, or the first one if NULL, `baudrate` of 0 means the default.
+///
+/// # Safety
+///
+/// `port` must be NULL or a NUL-terminated string, `out` must be a valid pointer,
+/// the handle must be released by [`wchisp_close`].
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_open_serial(
+    port: *const c_char,
+    baudrate: u32,
+    out: *mut *mut WchispHandle,
+) -> c_int {
+    ffi_call(|| {
+        anyhow::ensure!(!out.is_null(), "null output argument");
+        let port = if port.is_null() {
+            None
+        } else {
+            Some(str_arg(port)?)
+        };
+        let baudrate = match baudrate {
+            0 => Baudrate::default(),
+            baudrate => Baudrate::try_from(baudrate)?,
+        };
+        *out = new_handle(Flashing::new_from_serial(port, Some(baudrate))?.boxed());
+        Ok(())
+    })
+}
+
+/// Close a handle, NULL is ignored.
+///
+/// # Safety
+///
+/// `handle` must be NULL or returned by `wchisp_open_*`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_close(handle: *mut WchispHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Get info about the connected chip.
+///
+/// # Safety
+///
+/// `handle` must be valid, `info` must point to a `WchispChipInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_info(
+    handle: *mut WchispHandle,
+    info: *mut WchispChipInfo,
+) -> c_int {
+    ffi_call(|| {
+        let handle = handle_arg(handle)?;
+        let info = info
+            .as_mut()
+            .ok_or_else(|| anyhow::format_err!("null output argument"))?;
+        let chip = handle.flashing.info();
+
+        info.name = [0; 32];
+        for (dst, src) in info.name.iter_mut().zip(chip.name.bytes().take(31)) {
+            *dst = src as c_char;
+        }
+        info.part_number = [0; 32];
+        let part_number = chip.part_number.as_deref().unwrap_or_default();
+        for (dst, src) in info
+            .part_number
+            .iter_mut()
+            .zip(part_number.bytes().take(31))
+        {
+            *dst = src as c_char;
+        }
+        info.chip_id = chip.chip_id;
+        info.device_type = chip.device_type;
+        info.flash_size = chip.flash_size;
+        info.eeprom_size = chip.eeprom_size;
+        info.uid = [0; 8];
+        let uid_len = chip.uid.len().min(8);
+        info.uid[..uid_len].copy_from_slice(&chip.uid[..uid_len]);
+        info.uid_len = uid_len as u32;
+        info.bootloader_version = chip.bootloader_version.0;
+        info.code_flash_protected = match chip.code_flash_protected {
+            Some(protected) => protected as c_int,
+            None => -1,
+        };
+        Ok(())
+    })
+}
+
+/// Flash `len` bytes of `data` at `address`, with `WCHISP_FLASH_*` flags.
+///
+/// # Safety
+///
+/// `handle` must be valid, `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_flash(
+    handle: *mut WchispHandle,
+    data: *const u8,
+    len: usize,
+    address: u32,
+    flags: u32,
+    callback: WchispProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let handle = handle_arg(handle)?;
+        anyhow::ensure!(!data.is_null(), "null data argument");
+        let binary = std::slice::from_raw_parts(data, len).to_vec();
+        flash_images(
+            handle,
+            &[Segment::new(address, binary)],
+            flags,
+            callback,
+            user_data,
+        )
+    })
+}
+
+/// Flash a firmware file(ELF, ihex or binary) at `address`, with `WCHISP_FLASH_*` flags.
+///
+/// # Safety
+///
+/// `handle` must be valid, `path` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_flash_file(
+    handle: *mut WchispHandle,
+    path: *const c_char,
+    address: u32,
+    flags: u32,
+    callback: WchispProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let handle = handle_arg(handle)?;
+        let path = str_arg(path)?;
+        let opts = ReadOptions::default();
+        let mut segments = read_firmware_segments(path, &opts)?;
+        for segment in &mut segments {
+            segment.address += address;
+        }
+        flash_images(handle, &segments, flags, callback, user_data)
+    })
+}
+
+fn flash_images(
+    handle: &mut WchispHandle,
+    segments: &[Segment],
+    flags: u32,
+    callback: WchispProgressCallback,
+    user_data: *mut c_void,
+) -> Result<()> {
+    let mut flashing = CallbackGuard::new(&mut handle.flashing, callback, user_data);
+    flashing
+        .session()
+        .erase(flags & WCHISP_FLASH_ERASE != 0)
+        .verify(flags & WCHISP_FLASH_VERIFY != 0)
+        .reset(flags & WCHISP_FLASH_RESET != 0)
+        .run(segments)?;
+    Ok(())
+}
+
+/// Verify `len` bytes of `data` at `address`.
+///
+/// # Safety
+///
+/// `handle` must be valid, `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_verify(
+    handle: *mut WchispHandle,
+    data: *const u8,
+    len: usize,
+    address: u32,
+    callback: WchispProgressCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let handle = handle_arg(handle)?;
+        anyhow::ensure!(!data.is_null(), "null data argument");
+        let mut binary = std::slice::from_raw_parts(data, len).to_vec();
+        let sector_size = handle.flashing.chip.sector_size() as usize;
+        extend_firmware_to_sector_boundary(&mut binary, sector_size, 0x00);
+        CallbackGuard::new(&mut handle.flashing, callback, user_data).verify(address, &binary)
+    })
+}
+
+/// Reset the chip.
+///
+/// # Safety
+///
+/// `handle` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_reset(handle: *mut WchispHandle) -> c_int {
+    ffi_call(|| handle_arg(handle)?.flashing.reset())
+}
+
+/// Read a config register or field by name, e.g. `RDPR_USER.IWDG_SW`.
+///
+/// # Safety
+///
+/// `handle` must be valid, `name` must be a NUL-terminated string, `value` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_config_get(
+    handle: *mut WchispHandle,
+    name: *const c_char,
+    value: *mut u32,
+) -> c_int {
+    ffi_call(|| {
+        let handle = handle_arg(handle)?;
+        let name = str_arg(name)?;
+        anyhow::ensure!(!value.is_null(), "null output argument");
+        *value = handle.flashing.read_config()?.get(name)?;
+        Ok(())
+    })
+}
+
+/// Reset config registers to default.
+///
+/// # Safety
+///
+/// `handle` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_config_reset(handle: *mut WchispHandle) -> c_int {
+    ffi_call(|| handle_arg(handle)?.flashing.reset_config())
+}
+
+/// Unprotect code flash.
+///
+/// # Safety
+///
+/// `handle` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn wchisp_config_unprotect(handle: *mut WchispHandle) -> c_int {
+    ffi_call(|| handle_arg(handle)?.flashing.unprotect(true))
+}
@@ -1,28 +1,343 @@
 //! Chip flashing routine
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::{Ok, Result};
-use indicatif::ProgressBar;
+use anyhow::{Context, Ok, Result};
 use scroll::{Pread, Pwrite, LE};
 
 use crate::{
-    constants::{CFG_MASK_ALL, CFG_MASK_RDPR_USER_DATA_WPR},
-    device::{parse_number, ChipDB},
+    constants::{
+        commands, CFG_MASK_ALL, CFG_MASK_BTVER, CFG_MASK_RDPR_USER_DATA_WPR, CFG_MASK_UID,
+        CHUNK_SIZE, REQUEST_HEADER_SIZE, SECTOR_SIZE,
+    },
+    device::{parse_number, write_protected_ranges, Capability, ChipDB, ConfigRegister, Quirk},
+    format::{extend_firmware_to_sector_boundary, split_window, ImageDigest, Segment},
+    progress::{self, ProgressSink},
+    transport::Link,
+    Chip, Command, Error, Response, Transport,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
     transport::{SerialTransport, UsbTransport},
-    Baudrate, Chip, Command, Transport,
+    Baudrate,
 };
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+use std::thread::sleep;
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+use crate::transport::webusb::sleep;
+
+pub use crate::device::BootloaderVersion;
+pub use crate::protocol::EndReason;
+
+/// Length of the ISP key seed, the same as WCHISPTool
+pub const KEY_SEED_LEN: usize = 0x1e;
+/// Default number of retries of a failed program chunk
+const DEFAULT_CHUNK_RETRIES: u32 = 3;
+/// Program commands in flight when pipelined, see [`Flashing::set_pipelined`]
+const PIPELINE_DEPTH: usize = 2;
+/// Serial baudrate of the bootloader before `SetBaud`
+const DEFAULT_BAUDRATE: u32 = 115_200;
+
+/// Cancels long running operations of a [`Flashing`] from another thread or a signal handler.
+///
+/// A cancelled operation resets the device and fails with [`Error::Cancelled`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Config registers read from the chip, see [`Flashing::read_config`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSnapshot {
+    /// Raw bytes of all config registers
+    pub raw: Vec<u8>,
+    /// Registers decoded by the chip definition
+    pub registers: Vec<RegisterValue>,
+    /// Code flash ranges write-protected by the WPR register, merged, `None` if the chip has no
+    /// WPR, see [`Chip::write_protected_ranges`]
+    pub write_protected: Option<Vec<Range<u32>>>,
+    definitions: Vec<ConfigRegister>,
+    /// `(block, flash_size)` to decode WPR with
+    write_protect: Option<(u32, u32)>,
+}
+
+impl ConfigSnapshot {
+    fn decode(chip: &Chip, raw: Vec<u8>) -> Result<Self> {
+        let write_protect = chip
+            .write_protect_block()
+            .map(|block| (block, chip.flash_size));
+        Self::decode_with(chip.config_registers.clone(), write_protect, raw)
+    }
+
+    fn decode_with(
+        definitions: Vec<ConfigRegister>,
+        write_protect: Option<(u32, u32)>,
+        raw: Vec<u8>,
+    ) -> Result<Self> {
+        let mut registers = vec![];
+        for reg_def in &definitions {
+            let n = raw.pread_with::<u32>(reg_def.offset, LE)?;
+
+            // byte fields
+            let mut fields = vec![];
+            for field_def in &reg_def.fields {
+                let (msb, lsb) = (field_def.bit_range[0], field_def.bit_range[1]);
+                let b = (n >> lsb) & field_mask(msb, lsb);
+                fields.push(FieldValue {
+                    name: field_def.name.clone(),
+                    bit_range: (msb, lsb),
+                    value: b,
+                    explanation: explain(&field_def.explaination, b),
+                });
+            }
+            registers.push(RegisterValue {
+                name: reg_def.name.clone(),
+                offset: reg_def.offset,
+                value: n,
+                explanation: explain(&reg_def.explaination, n),
+                fields,
+            });
+        }
+
+        // NOTE: WPR is the 4th register word, after RDPR_USER and DATA
+        let write_protected = write_protect.and_then(|(block, flash_size)| {
+            let wpr = raw.pread_with::<u32>(8, LE).ok()?;
+            Some(write_protected_ranges(block, flash_size, wpr))
+        });
+        Ok(ConfigSnapshot {
+            raw,
+            registers,
+            write_protected,
+            definitions,
+            write_protect,
+        })
+    }
+
+    /// Value of a register or a field, by `REGISTER` or `REGISTER.FIELD` name, e.g. `RDPR_USER.IWDG_SW`
+    pub fn get(&self, name: &str) -> Result<u32> {
+        let (reg_name, field_name) = split_config_name(name);
+        let reg = self
+            .registers
+            .iter()
+            .find(|reg| reg.name.eq_ignore_ascii_case(reg_name))
+            .ok_or_else(|| anyhow::format_err!("unknown config register: {}", reg_name))?;
+        match field_name {
+            None => Ok(reg.value),
+            Some(field_name) => {
+                let field = reg
+                    .fields
+                    .iter()
+                    .find(|field| field.name.eq_ignore_ascii_case(field_name))
+                    .ok_or_else(|| anyhow::format_err!("unknown config field: {}", name))?;
+                Ok(field.value)
+            }
+        }
+    }
+
+    /// Set a register or a field, by the same name as [`ConfigSnapshot::get`].
+    ///
+    /// Only the snapshot is changed, decoded values are updated accordingly.
+    pub fn set(&mut self, name: &str, value: u32) -> Result<()> {
+        let (reg_name, field_name) = split_config_name(name);
+        let reg_def = self
+            .definitions
+            .iter()
+            .find(|reg| reg.name.eq_ignore_ascii_case(reg_name))
+            .ok_or_else(|| anyhow::format_err!("unknown config register: {}", reg_name))?;
+        let mut n = self.raw.pread_with::<u32>(reg_def.offset, LE)?;
+        match field_name {
+            None => n = value,
+            Some(field_name) => {
+                let field_def = reg_def
+                    .fields
+                    .iter()
+                    .find(|field| field.name.eq_ignore_ascii_case(field_name))
+                    .ok_or_else(|| anyhow::format_err!("unknown config field: {}", name))?;
+                let (msb, lsb) = (field_def.bit_range[0], field_def.bit_range[1]);
+                let mask = field_mask(msb, lsb);
+                anyhow::ensure!(
+                    value & !mask == 0,
+                    "value 0x{:X} too large for {}[{}:{}]",
+                    value,
+                    name,
+                    msb,
+                    lsb
+                );
+                n = (n & !(mask << lsb)) | (value << lsb);
+            }
+        }
+        let offset = reg_def.offset;
+        self.raw.pwrite_with(n, offset, LE)?;
+
+        let definitions = std::mem::take(&mut self.definitions);
+        *self = ConfigSnapshot::decode_with(
+            definitions,
+            self.write_protect,
+            std::mem::take(&mut self.raw),
+        )?;
+        Ok(())
+    }
+}
+
+/// Split `REGISTER.FIELD` into its parts
+fn split_config_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('.') {
+        Some((reg, field)) => (reg, Some(field)),
+        None => (name, None),
+    }
+}
+
+/// Mask of a `[msb:lsb]` bit field, not shifted
+fn field_mask(msb: u8, lsb: u8) -> u32 {
+    let bit_width = (msb - lsb) as u32 + 1;
+    u32::MAX >> (32 - bit_width)
+}
+
+/// A decoded config register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisterValue {
+    pub name: String,
+    /// Offset in the raw config bytes
+    pub offset: usize,
+    pub value: u32,
+    pub explanation: Option<String>,
+    pub fields: Vec<FieldValue>,
+}
+
+/// A decoded bit field of a config register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldValue {
+    pub name: String,
+    /// `(msb, lsb)`, both inclusive
+    pub bit_range: (u8, u8),
+    pub value: u32,
+    pub explanation: Option<String>,
+}
 
-pub struct Flashing<'a> {
-    transport: Box<dyn Transport + 'a>,
+impl fmt::Display for ConfigSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for reg in &self.registers {
+            writeln!(f, "{}: 0x{:08X}", reg.name, reg.value)?;
+            if let Some(explanation) = &reg.explanation {
+                writeln!(f, "  `- {}", explanation)?;
+            }
+            for field in &reg.fields {
+                writeln!(
+                    f,
+                    "  {:<7} {} 0x{:X} (0b{:b})",
+                    format!("[{:}:{:}]", field.bit_range.0, field.bit_range.1),
+                    field.name,
+                    field.value,
+                    field.value
+                )?;
+                if let Some(explanation) = &field.explanation {
+                    writeln!(f, "    `- {}", explanation)?;
+                }
+            }
+        }
+        match self.write_protected.as_deref() {
+            None => (),
+            Some([]) => writeln!(f, "Write protected: none")?,
+            Some(ranges) => {
+                for range in ranges {
+                    writeln!(
+                        f,
+                        "Write protected: 0x{:08x}..0x{:08x} ({}KiB)",
+                        range.start,
+                        range.end,
+                        (range.end - range.start) / 1024
+                    )?;
+                }
+            }
+        }
+        fmt::Result::Ok(())
+    }
+}
+
+/// Pick the explanation of `value`, `_` matches any value
+fn explain(explanations: &BTreeMap<String, String>, value: u32) -> Option<String> {
+    explanations
+        .iter()
+        .find(|(val, _)| *val == "_" || Some(value) == parse_number(val))
+        .map(|(_, explanation)| explanation.clone())
+}
+
+/// Info about the connected chip, see [`Flashing::info`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChipInfo {
+    /// Chip's name, without variants surfix
+    pub name: String,
+    /// Full part number, e.g. `CH32V103R8T6`, if the chip ID tells it, see [`Chip::part_number`]
+    pub part_number: Option<String>,
+    pub chip_id: u8,
+    pub device_type: u8,
+    /// Code flash size in bytes
+    pub flash_size: u32,
+    /// Data EEPROM size in bytes, 0 if not supported
+    pub eeprom_size: u32,
+    /// Chip unique identifier
+    pub uid: Vec<u8>,
+    pub bootloader_version: BootloaderVersion,
+    /// `None` if the chip doesn't support code flash protection
+    pub code_flash_protected: Option<bool>,
+}
+
+/// Called with the chip, its UID and the current raw config registers before each config write
+pub type ConfigBackupHook = Box<dyn FnMut(&Chip, &[u8], &[u8]) -> Result<()> + Send>;
+
+/// A connected chip in ISP mode, over transport `T`.
+///
+/// Use [`BoxedFlashing`] when the transport is only known at runtime.
+pub struct Flashing<T: Transport> {
+    transport: T,
     pub chip: Chip,
     /// Chip unique identifier
     chip_uid: Vec<u8>,
-    // BTVER
-    bootloader_version: [u8; 4],
+    bootloader_version: BootloaderVersion,
     code_flash_protected: bool,
+    /// ISP key seed, sent via `IspKey`
+    key_seed: Vec<u8>,
+    progress: Box<dyn ProgressSink>,
+    cancel: CancelToken,
+    chunk_retries: u32,
+    /// Number of program chunks retried so far
+    retried_chunks: u32,
+    /// Log and skip erase, program and config writes
+    dry_run: bool,
+    /// Whether a write was skipped in dry run, so that verifying it makes no sense
+    dry_run_skipped: bool,
+    /// Keep [`PIPELINE_DEPTH`] program chunks in flight
+    pipelined: bool,
+    /// Verify each program chunk right after programming it
+    verify_each: bool,
+    config_backup: Option<ConfigBackupHook>,
 }
 
-impl<'a> Flashing<'a> {
+/// [`Flashing`] over a transport chosen at runtime, e.g. USB or serial by a command line flag
+pub type BoxedFlashing<'a> = Flashing<Box<dyn Transport + Send + 'a>>;
+
+impl BoxedFlashing<'_> {
     pub fn get_chip(transport: &mut impl Transport) -> Result<Chip> {
         let identify = Command::identify(0, 0);
         let resp = transport.transfer(identify)?;
@@ -32,65 +347,413 @@ impl<'a> Flashing<'a> {
 
         Ok(chip)
     }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Flashing<SerialTransport> {
+    pub fn new_from_serial(port: Option<&str>, baudrate: Option<Baudrate>) -> Result<Self> {
+        let baudrate = baudrate.unwrap_or_default();
 
-    pub fn new_from_transport(mut transport: impl Transport + 'a) -> Result<Self> {
+        let transport = match port {
+            Some(port) => SerialTransport::open(port, baudrate)?,
+            None => SerialTransport::open_any(baudrate)?,
+        };
+
+        Self::new_from_transport(transport)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Flashing<UsbTransport> {
+    pub fn new_from_usb(device: Option<usize>) -> Result<Self> {
+        let transport = match device {
+            Some(device) => UsbTransport::open_nth(device)?,
+            None => UsbTransport::open_any()?,
+        };
+
+        Self::new_from_transport(transport)
+    }
+}
+
+impl<T: Transport> Flashing<T> {
+    pub fn new_from_transport(mut transport: T) -> Result<Self> {
         let identify = Command::identify(0, 0);
         let resp = transport.transfer(identify)?;
         anyhow::ensure!(resp.is_ok(), "idenfity chip failed");
 
-        let chip = Flashing::get_chip(&mut transport)?;
-        log::debug!("found chip: {}", chip);
+        let chip = BoxedFlashing::get_chip(&mut transport)?;
+        tracing::debug!("found chip: {}", chip);
 
-        let read_conf = Command::read_config(CFG_MASK_ALL);
-        let resp = transport.transfer(read_conf)?;
-        anyhow::ensure!(resp.is_ok(), "read_config failed");
+        Self::open(transport, chip)
+    }
 
-        log::debug!("read_config: {}", hex::encode(&resp.payload()[2..]));
-        let code_flash_protected = chip.support_code_flash_protect() && resp.payload()[2] != 0xa5;
-        let mut btver = [0u8; 4];
-        btver.copy_from_slice(&resp.payload()[14..18]);
-
-        if chip.support_code_flash_protect()
-            && resp.payload()[2 + 8..2 + 8 + 4] != [0xff, 0xff, 0xff, 0xff]
-        {
-            log::warn!(
-                "WRP register: {}",
-                hex::encode(&resp.payload()[2 + 8..2 + 8 + 4])
+    /// Use `chip` instead of looking up the identified chip in the database, for new parts.
+    ///
+    /// See [`ChipDB::unknown_chip`].
+    pub fn new_from_transport_with_chip(mut transport: T, chip: Chip) -> Result<Self> {
+        let identify = Command::identify(0, 0);
+        let resp = transport.transfer(identify)?;
+        anyhow::ensure!(resp.is_ok(), "idenfity chip failed");
+        if let [chip_id, device_type, ..] = resp.payload() {
+            if (*chip_id, *device_type) != (chip.chip_id, chip.device_type) {
+                tracing::warn!(
+                    "Identified chip 0x{:02x}{:02x}, but using {}",
+                    chip_id,
+                    device_type,
+                    chip
+                );
+            }
+        }
+
+        Self::open(transport, chip)
+    }
+
+    /// Fall back to [`ChipDB::generic_chip`] when the identified chip is not in the database
+    pub fn new_from_transport_or_generic(mut transport: T, flash_size: u32) -> Result<Self> {
+        let identify = Command::identify(0, 0);
+        let resp = transport.transfer(identify)?;
+        anyhow::ensure!(resp.is_ok(), "idenfity chip failed");
+        let [chip_id, device_type, ..] = *resp.payload() else {
+            anyhow::bail!("idenfity chip failed: short response");
+        };
+
+        let chip_db = ChipDB::load()?;
+        let chip = chip_db.find_chip(chip_id, device_type).unwrap_or_else(|e| {
+            let chip = chip_db.generic_chip(chip_id, device_type, flash_size);
+            tracing::warn!("{}, using a generic profile: {}", e, chip);
+            tracing::warn!(
+                "Flash size is not checked against the chip, config registers are unavailable"
+            );
+            chip
+        });
+
+        Self::open(transport, chip)
+    }
+
+    /// Read the config registers and start a session on an identified chip
+    fn open(mut transport: T, chip: Chip) -> Result<Self> {
+        // NOTE: the bootloader may answer Identify on an unsupported link, but not much else
+        if let Some(link) = transport.link().filter(|link| !chip.support_link(*link)) {
+            let supported: Vec<_> = [Link::Usb, Link::Serial]
+                .into_iter()
+                .filter(|link| chip.support_link(*link))
+                .map(|link| link.to_string())
+                .collect();
+            anyhow::bail!(
+                "{} does not support ISP over {}, use {} instead",
+                chip,
+                link,
+                supported.join(" or ")
             );
         }
+        let raw = Self::read_config_all(&mut transport, &chip, None)?;
+        anyhow::ensure!(raw.len() >= 16, "read_config failed: short response");
+
+        tracing::debug!("read_config: {}", hex::encode(&raw));
+        let code_flash_protected = chip.support_code_flash_protect() && raw[0] != 0xa5;
+        let mut btver = [0u8; 4];
+        btver.copy_from_slice(&raw[12..16]);
+        let bootloader_version = BootloaderVersion(btver);
+
+        // NOTE: BTVER is read at the default baudrate first, old bootloaders can't switch
+        let max_baudrate = match chip.min_bootloader_version(Capability::SetBaud) {
+            Some(min) if bootloader_version < min => {
+                if transport.link() == Some(Link::Serial) {
+                    tracing::debug!(
+                        "{} not supported by bootloader {}, {}",
+                        Capability::SetBaud.description(),
+                        bootloader_version,
+                        Capability::SetBaud.hint()
+                    );
+                }
+                Some(DEFAULT_BAUDRATE)
+            }
+            _ => chip.max_baudrate(),
+        };
+        if let Some(baudrate) = transport.negotiate_baudrate(max_baudrate)? {
+            tracing::debug!("Serial baudrate: {}", baudrate);
+        }
+
+        if chip.support_code_flash_protect() && raw[8..12] != [0xff, 0xff, 0xff, 0xff] {
+            if chip.write_protect_block().is_some() {
+                let wpr = raw.pread_with::<u32>(8, LE)?;
+                for range in chip.write_protected_ranges(wpr) {
+                    tracing::warn!(
+                        "Code flash write protected: 0x{:08x}..0x{:08x}",
+                        range.start,
+                        range.end
+                    );
+                }
+            } else {
+                tracing::warn!("WRP register: {}", hex::encode(&raw[8..12]));
+            }
+        }
 
         // NOTE: just read all remain bytes as chip_uid
-        let chip_uid = resp.payload()[18..].to_vec();
+        let chip_uid = raw[16..].to_vec();
 
         let f = Flashing {
-            transport: Box::new(transport),
+            transport,
             chip,
             chip_uid,
-            bootloader_version: btver,
+            bootloader_version,
             code_flash_protected,
+            key_seed: vec![0; KEY_SEED_LEN],
+            progress: progress::default_sink(),
+            cancel: CancelToken::default(),
+            chunk_retries: DEFAULT_CHUNK_RETRIES,
+            retried_chunks: 0,
+            dry_run: false,
+            dry_run_skipped: false,
+            pipelined: false,
+            verify_each: false,
+            config_backup: None,
         };
         f.check_chip_uid()?;
         Ok(f)
     }
 
-    pub fn new_from_serial(port: Option<&str>, baudrate: Option<Baudrate>) -> Result<Self> {
-        let baudrate = baudrate.unwrap_or_default();
+    /// Box the transport, to store sessions over different transports in one place
+    pub fn boxed<'a>(self) -> BoxedFlashing<'a>
+    where
+        T: Send + 'a,
+    {
+        Flashing {
+            transport: Box::new(self.transport),
+            chip: self.chip,
+            chip_uid: self.chip_uid,
+            bootloader_version: self.bootloader_version,
+            code_flash_protected: self.code_flash_protected,
+            key_seed: self.key_seed,
+            progress: self.progress,
+            cancel: self.cancel,
+            chunk_retries: self.chunk_retries,
+            retried_chunks: self.retried_chunks,
+            dry_run: self.dry_run,
+            dry_run_skipped: self.dry_run_skipped,
+            pipelined: self.pipelined,
+            verify_each: self.verify_each,
+            config_backup: self.config_backup,
+        }
+    }
 
-        let transport = match port {
-            Some(port) => SerialTransport::open(port, baudrate)?,
-            None => SerialTransport::open_any(baudrate)?,
-        };
+    /// Use a random ISP key seed instead of the all-zero one, like WCHISPTool does.
+    pub fn use_random_key_seed(&mut self) {
+        self.key_seed = rand::random::<[u8; KEY_SEED_LEN]>().to_vec();
+    }
 
-        Self::new_from_transport(transport)
+    /// Use the exact ISP key seed `seed` of 0x1e bytes, e.g. to reproduce a WCHISPTool session.
+    pub fn set_key_seed(&mut self, seed: [u8; KEY_SEED_LEN]) {
+        self.key_seed = seed.to_vec();
     }
 
-    pub fn new_from_usb(device: Option<usize>) -> Result<Self> {
-        let transport = match device {
-            Some(device) => UsbTransport::open_nth(device)?,
-            None => UsbTransport::open_any()?,
+    /// Set where progress of long running operations is reported, a progress bar by default.
+    pub fn set_progress(&mut self, progress: Box<dyn ProgressSink>) {
+        self.progress = progress;
+    }
+
+    /// Set the token checked between chunks of long running operations.
+    pub fn set_cancel_token(&mut self, cancel: CancelToken) {
+        self.cancel = cancel;
+    }
+
+    /// Set how many times a failed or timed out program chunk is retried, 3 by default.
+    pub fn set_chunk_retries(&mut self, retries: u32) {
+        self.chunk_retries = retries;
+    }
+
+    /// Number of program chunks retried so far
+    pub fn retried_chunks(&self) -> u32 {
+        self.retried_chunks
+    }
+
+    /// Send the next program chunk while waiting for the response to the previous one, instead of
+    /// one round trip per chunk. Faster on high-latency links like USB hubs and network bridges.
+    ///
+    /// On the first error programming goes on in lock-step from the first unconfirmed chunk.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+    }
+
+    /// Verify each program chunk right after programming it, failing with the address of the
+    /// first mismatched byte. Localizes failures on marginal boards, pipelining is not used.
+    pub fn set_verify_each(&mut self, verify_each: bool) {
+        self.verify_each = verify_each;
+    }
+
+    /// Back up the config registers before each write, the write fails if `hook` fails.
+    pub fn set_config_backup(&mut self, hook: ConfigBackupHook) {
+        self.config_backup = Some(hook);
+    }
+
+    /// Log erase, program and config writes instead of doing them, reads work as usual.
+    ///
+    /// Verifying after a skipped write is skipped as well.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Whether to skip a write, logging `what` would be done
+    fn skip_in_dry_run(&mut self, what: fmt::Arguments) -> bool {
+        if self.dry_run {
+            tracing::info!("Dry run: would {}", what);
+            self.dry_run_skipped = true;
+        }
+        self.dry_run
+    }
+
+    /// Whether to skip a verify of data skipped in dry run
+    fn skip_verify_in_dry_run(&self, what: fmt::Arguments) -> bool {
+        let skip = self.dry_run && self.dry_run_skipped;
+        if skip {
+            tracing::info!("Dry run: would {}", what);
+        }
+        skip
+    }
+
+    /// Write the RDPR, USER, DATA and WPR registers. Returns `false` if skipped in dry run.
+    fn write_config_registers(&mut self, raw: Vec<u8>) -> Result<bool> {
+        if self.dry_run {
+            let registers = ConfigSnapshot::decode(&self.chip, raw.clone())
+                .map(|config| {
+                    config
+                        .registers
+                        .iter()
+                        .map(|reg| format!("{}=0x{:08x}", reg.name, reg.value))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default();
+            let registers = if registers.is_empty() {
+                hex::encode(&raw)
+            } else {
+                registers
+            };
+            return Ok(!self.skip_in_dry_run(format_args!("write {}", registers)));
+        }
+        if let Some(mut hook) = self.config_backup.take() {
+            let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
+            let result = self.transport.transfer(read_conf).and_then(|resp| {
+                anyhow::ensure!(resp.is_ok(), "read_config failed");
+                hook(&self.chip, self.chip_uid(), &resp.payload()[2..])
+            });
+            self.config_backup = Some(hook);
+            result.context("config backup failed, not writing config registers")?;
+        }
+        let write_conf = Command::write_config(CFG_MASK_RDPR_USER_DATA_WPR, raw);
+        let resp = self.transport.transfer(write_conf)?;
+        anyhow::ensure!(resp.is_ok(), "write_config failed");
+        resp.check_status(commands::WRITE_CONFIG)?;
+        Ok(true)
+    }
+
+    /// Config registers, BTVER and UID, the same as a `CFG_MASK_ALL` read without its header.
+    ///
+    /// Bootloaders with [`Quirk::NoMaskAllConfigRead`] are read one mask at a time, `version` is
+    /// read first if not known yet.
+    fn read_config_all(
+        transport: &mut T,
+        chip: &Chip,
+        version: Option<BootloaderVersion>,
+    ) -> Result<Vec<u8>> {
+        let mut read = |mask: u8| -> Result<Vec<u8>> {
+            let resp = transport.transfer(Command::read_config(mask))?;
+            anyhow::ensure!(resp.is_ok(), "read_config failed");
+            let mut raw = resp.into_payload();
+            raw.drain(..2.min(raw.len()));
+            Ok(raw)
         };
 
-        Self::new_from_transport(transport)
+        let version = match version {
+            Some(version) => Some(version),
+            None if chip.may_have_quirk(Quirk::NoMaskAllConfigRead) => {
+                let raw = read(CFG_MASK_BTVER)?;
+                let btver: [u8; 4] = raw
+                    .get(..4)
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(|| anyhow::format_err!("read_config failed: short BTVER"))?;
+                Some(BootloaderVersion(btver))
+            }
+            None => None,
+        };
+        match version {
+            Some(version) if chip.quirk(Quirk::NoMaskAllConfigRead, version).is_some() => {
+                tracing::debug!("read_config: one mask at a time, BTVER {}", version);
+                let mut raw = read(CFG_MASK_RDPR_USER_DATA_WPR)?;
+                raw.extend(read(CFG_MASK_BTVER)?);
+                raw.extend(read(CFG_MASK_UID)?);
+                Ok(raw)
+            }
+            _ => read(CFG_MASK_ALL),
+        }
+    }
+
+    /// Fail with a clear error if the bootloader is too old for `capability`.
+    fn require(&self, capability: Capability) -> Result<()> {
+        match self.chip.min_bootloader_version(capability) {
+            Some(min) if self.bootloader_version < min => anyhow::bail!(Error::Unsupported {
+                feature: capability.description(),
+                bootloader: self.bootloader_version.to_string(),
+                hint: capability.hint(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait after a code flash erase before programming, the chip's `erase_settle` or longer
+    /// with [`Quirk::EraseDelay`]
+    pub fn erase_delay(&self) -> Duration {
+        let delay = self
+            .chip
+            .quirk(Quirk::EraseDelay, self.bootloader_version)
+            .and_then(|rule| rule.delay);
+        let settle = self.chip.timing().erase_settle;
+        Duration::from_millis(delay.map_or(settle, |delay| delay.max(settle)))
+    }
+
+    /// Data bytes per Program and Verify command, the chip's chunk size capped to what fits a
+    /// single bulk packet with the request header, never below the default chunk size
+    pub fn chunk_size(&self) -> usize {
+        match self.transport.max_packet_size() {
+            Some(size) => self
+                .chip
+                .chunk_size()
+                .min(size.saturating_sub(REQUEST_HEADER_SIZE).max(CHUNK_SIZE)),
+            None => self.chip.chunk_size(),
+        }
+    }
+
+    /// Reset the device and fail if cancelled, so that it does not stay in the bootloader mid-program.
+    fn check_cancelled(&mut self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            self.progress.finish();
+            tracing::warn!("Cancelled, resetting device");
+            let _ = self.reset();
+            anyhow::bail!(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Start a complete erase -> flash -> verify -> reset session, see [`FlashSession`].
+    pub fn session(&mut self) -> FlashSession<'_, T> {
+        FlashSession {
+            flashing: self,
+            erase: true,
+            erase_all: false,
+            verify: true,
+            reset: true,
+            end_reason: EndReason::Reboot,
+            offset: 0,
+            retries: 0,
+            pad: Some(0x00),
+            eeprom: None,
+            data_segments: vec![],
+            progress: None,
+        }
     }
 
     /// Reidentify chip using correct chip uid
@@ -98,40 +761,64 @@ impl<'a> Flashing<'a> {
         let identify = Command::identify(self.chip.chip_id, self.chip.device_type);
         let resp = self.transport.transfer(identify)?;
 
-        anyhow::ensure!(resp.payload()[0] == self.chip.chip_id, "chip id mismatch");
-        anyhow::ensure!(
-            resp.payload()[1] == self.chip.device_type,
-            "device type mismatch"
-        );
+        if resp.payload()[0] != self.chip.chip_id || resp.payload()[1] != self.chip.device_type {
+            return Err(anyhow::Error::new(Error::ChipMismatch {
+                expected: self.chip.to_string(),
+                found: format!("[0x{:02x}{:02x}]", resp.payload()[0], resp.payload()[1]),
+            })
+            .context("chip id or device type mismatch"));
+        }
 
-        let read_conf = Command::read_config(CFG_MASK_ALL);
-        let _ = self.transport.transfer(read_conf)?;
+        let _ = Self::read_config_all(
+            &mut self.transport,
+            &self.chip,
+            Some(self.bootloader_version),
+        )?;
 
         Ok(())
     }
 
+    /// Check the connected chip against a name prefix, glob or `/regex/`, see [`Chip::matches_name`]
     pub fn check_chip_name(&self, name: &str) -> Result<()> {
-        if !self.chip.name.starts_with(name) {
-            anyhow::bail!(
-                "chip name mismatch: has {}, provided {}",
-                self.chip.name,
-                name
-            );
+        if !self.chip.matches_name(name)? {
+            anyhow::bail!(Error::ChipMismatch {
+                expected: name.to_string(),
+                found: self.chip.name.clone(),
+            });
         }
         Ok(())
     }
 
+    /// Info about the connected chip
+    pub fn info(&self) -> ChipInfo {
+        ChipInfo {
+            name: self.chip.name.clone(),
+            part_number: self.chip.part_number().map(str::to_string),
+            chip_id: self.chip.chip_id,
+            device_type: self.chip.device_type(),
+            flash_size: self.chip.flash_size,
+            eeprom_size: self.chip.eeprom_size,
+            uid: self.chip_uid().to_vec(),
+            bootloader_version: self.bootloader_version,
+            code_flash_protected: self
+                .chip
+                .support_code_flash_protect()
+                .then_some(self.code_flash_protected),
+        }
+    }
+
     pub fn dump_info(&mut self) -> Result<()> {
+        let info = self.info();
         if self.chip.eeprom_size > 0 {
-            if self.chip.eeprom_size % 1024 != 0 {
-                log::info!(
+            if !self.chip.eeprom_size.is_multiple_of(1024) {
+                tracing::info!(
                     "Chip: {} (Code Flash: {}KiB, Data EEPROM: {} Bytes)",
                     self.chip,
                     self.chip.flash_size / 1024,
                     self.chip.eeprom_size
                 );
             } else {
-                log::info!(
+                tracing::info!(
                     "Chip: {} (Code Flash: {}KiB, Data EEPROM: {}KiB)",
                     self.chip,
                     self.chip.flash_size / 1024,
@@ -139,30 +826,24 @@ impl<'a> Flashing<'a> {
                 );
             }
         } else {
-            log::info!(
+            tracing::info!(
                 "Chip: {} (Code Flash: {}KiB)",
                 self.chip,
                 self.chip.flash_size / 1024,
             );
         }
-        log::info!(
+        tracing::info!(
             "Chip UID: {}",
-            self.chip_uid
+            info.uid
                 .iter()
                 .map(|x| format!("{:02X}", x))
                 .collect::<Vec<_>>()
                 .join("-")
         );
-        log::info!(
-            "BTVER(bootloader ver): {:x}{:x}.{:x}{:x}",
-            self.bootloader_version[0],
-            self.bootloader_version[1],
-            self.bootloader_version[2],
-            self.bootloader_version[3]
-        );
+        tracing::info!("BTVER(bootloader ver): {}", info.bootloader_version);
 
-        if self.chip.support_code_flash_protect() {
-            log::info!("Code Flash protected: {}", self.code_flash_protected);
+        if let Some(protected) = info.code_flash_protected {
+            tracing::info!("Code Flash protected: {}", protected);
         }
         self.dump_config()?;
 
@@ -174,6 +855,7 @@ impl<'a> Flashing<'a> {
         if !force && !self.code_flash_protected {
             return Ok(());
         }
+        self.require(Capability::ConfigMaskRead)?;
         let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
         let resp = self.transport.transfer(read_conf)?;
         anyhow::ensure!(resp.is_ok(), "read_config failed");
@@ -185,110 +867,276 @@ impl<'a> Flashing<'a> {
         // WPR register
         config[8..12].copy_from_slice(&[0xff; 4]);
 
-        let write_conf = Command::write_config(CFG_MASK_RDPR_USER_DATA_WPR, config);
-        let resp = self.transport.transfer(write_conf)?;
-        anyhow::ensure!(resp.is_ok(), "write_config failed");
+        if !self.write_config_registers(config)? {
+            return Ok(());
+        }
 
-        log::info!("Code Flash unprotected");
+        tracing::info!("Code Flash unprotected");
         self.reset()?;
         Ok(())
     }
 
+    /// Write-protect or unprotect code flash `sectors` of [`Chip::sector_size`] via the WPR
+    /// register, other sectors keep their protection. Takes effect after a reset.
+    pub fn protect_sectors(&mut self, sectors: Range<u32>, protect: bool) -> Result<()> {
+        let mask = self.chip.write_protect_mask(sectors.clone())?;
+        self.require(Capability::ConfigMaskRead)?;
+        let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
+        let resp = self.transport.transfer(read_conf)?;
+        anyhow::ensure!(resp.is_ok(), "read_config failed");
+
+        let mut config = resp.payload()[2..14].to_vec(); // 4 x u32
+        let wpr = config.pread_with::<u32>(8, LE)?;
+        // NOTE: a cleared bit protects its block
+        let wpr = if protect { wpr & !mask } else { wpr | mask };
+        config.pwrite_with(wpr, 8, LE)?;
+        tracing::info!("New WPR: 0x{:08x}", wpr);
+
+        if !self.write_config_registers(config)? {
+            return Ok(());
+        }
+        tracing::info!(
+            "Code flash sectors {}..{} {}, after reset",
+            sectors.start,
+            sectors.end,
+            if protect { "protected" } else { "unprotected" }
+        );
+        Ok(())
+    }
+
+    /// End the ISP session and reboot to the application
     pub fn reset(&mut self) -> Result<()> {
-        let isp_end = Command::isp_end(1);
+        self.end_session(EndReason::Reboot)
+    }
+
+    /// End the ISP session, [`EndReason::Finish`] keeps the bootloader alive
+    pub fn end_session(&mut self, reason: EndReason) -> Result<()> {
+        let isp_end = Command::isp_end(reason as u8);
         let resp = self.transport.transfer(isp_end)?;
         anyhow::ensure!(resp.is_ok(), "isp_end failed");
 
-        log::info!("Device reset");
+        match reason {
+            EndReason::Reboot => tracing::info!("Device reset"),
+            EndReason::Finish => tracing::info!("ISP session finished, bootloader stays active"),
+        }
         Ok(())
     }
 
     // unprotect -> erase -> flash -> verify -> reset
-    /// Program the code flash.
-    pub fn flash(&mut self, raw: &[u8]) -> Result<()> {
-        let key = self.xor_key();
-        let key_checksum = key.iter().fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
+    /// Program the code flash, starting at `start_address`.
+    #[tracing::instrument(level = "debug", skip_all, fields(start_address, len = raw.len()))]
+    pub fn flash(&mut self, start_address: u32, raw: &[u8]) -> Result<()> {
+        self.check_code_flash_range(start_address, raw.len())?;
+        if self.skip_in_dry_run(format_args!(
+            "program {} bytes at 0x{:08x}",
+            raw.len(),
+            start_address
+        )) {
+            return Ok(());
+        }
 
-        // NOTE: use all-zero key seed for now.
-        let isp_key = Command::isp_key(vec![0; 0x1e]);
-        let resp = self.transport.transfer(isp_key)?;
-        anyhow::ensure!(resp.is_ok(), "isp_key failed");
-        anyhow::ensure!(resp.payload()[0] == key_checksum, "isp_key checksum failed");
+        let mut key = self.send_isp_key(true)?;
 
-        const CHUNK: usize = 56;
-        let mut address = 0x0;
+        let chunk_size = self.chunk_size();
+        let mut address = start_address;
 
-        let bar = ProgressBar::new(raw.len() as _);
-        for ch in raw.chunks(CHUNK) {
-            self.flash_chunk(address, ch, key)?;
+        self.progress.begin("flash", raw.len() as _);
+        let retried_chunks = self.retried_chunks;
+        if self.pipelined && !self.verify_each {
+            if let Err((confirmed, e)) = self.flash_pipelined(start_address, raw, chunk_size, key) {
+                tracing::warn!(
+                    "Pipelined program failed after 0x{:08x}: {:#}, going on in lock-step",
+                    confirmed,
+                    e
+                );
+                key = self.send_isp_key(true)?;
+                address = confirmed;
+            } else {
+                address = start_address + raw.len() as u32;
+            }
+        }
+        for ch in raw[(address - start_address) as usize..].chunks(chunk_size) {
+            self.check_cancelled()?;
+            self.retry_chunk(true, |f| f.flash_chunk(address, ch, key))?;
+            if self.verify_each {
+                self.verify_programmed_chunk(address, ch, key)?;
+            }
             address += ch.len() as u32;
-            bar.inc(ch.len() as _);
+            self.progress.inc(ch.len() as _);
         }
         // NOTE: require a write action of empty data for success flashing
-        self.flash_chunk(address, &[], key)?;
-        bar.finish();
-
-        log::info!("Code flash {} bytes written", address);
+        self.retry_chunk(true, |f| f.flash_chunk(address, &[], key))?;
+        self.progress.finish();
+
+        tracing::info!("Code flash {} bytes written", address - start_address);
+        if self.retried_chunks > retried_chunks {
+            tracing::warn!(
+                "{} program chunk(s) retried",
+                self.retried_chunks - retried_chunks
+            );
+        }
 
         Ok(())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(len = raw.len()))]
     pub fn write_eeprom(&mut self, raw: &[u8]) -> Result<()> {
-        let key = self.xor_key();
-        // let key_checksum = key.iter().fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
+        self.write_eeprom_at(0, raw)
+    }
 
-        // NOTE: use all-zero key seed for now.
-        let isp_key = Command::isp_key(vec![0; 0x1e]);
-        let resp = self.transport.transfer(isp_key)?;
-        anyhow::ensure!(resp.is_ok(), "isp_key failed");
-        // anyhow::ensure!(resp.payload()[0] == key_checksum, "isp_key checksum failed");
+    /// Write EEPROM(data flash) content, starting at `start_address` of the data flash.
+    #[tracing::instrument(level = "debug", skip_all, fields(start_address, len = raw.len()))]
+    pub fn write_eeprom_at(&mut self, start_address: u32, raw: &[u8]) -> Result<()> {
+        if self.skip_in_dry_run(format_args!(
+            "write {} bytes of EEPROM at 0x{:x}",
+            raw.len(),
+            start_address
+        )) {
+            return Ok(());
+        }
+        // NOTE: checksum is not checked for EEPROM writing
+        let key = self.send_isp_key(false)?;
 
         const CHUNK: usize = 56;
-        let mut address = 0x0;
+        let mut address = start_address;
 
-        let bar = ProgressBar::new(raw.len() as _);
+        self.progress.begin("write_eeprom", raw.len() as _);
+        let retried_chunks = self.retried_chunks;
         for ch in raw.chunks(CHUNK) {
-            self.write_data_chunk(address, ch, key)?;
+            self.check_cancelled()?;
+            self.retry_chunk(false, |f| f.write_data_chunk(address, ch, key))?;
             address += ch.len() as u32;
-            bar.inc(ch.len() as _);
+            self.progress.inc(ch.len() as _);
         }
         // NOTE: require a write action of empty data for success flashing
-        self.flash_chunk(address, &[], key)?;
-        bar.finish();
+        self.retry_chunk(false, |f| f.flash_chunk(address, &[], key))?;
+        self.progress.finish();
+
+        if self.retried_chunks > retried_chunks {
+            tracing::warn!(
+                "{} EEPROM chunk(s) retried",
+                self.retried_chunks - retried_chunks
+            );
+        }
 
         Ok(())
     }
 
-    pub fn verify(&mut self, raw: &[u8]) -> Result<()> {
-        let key = self.xor_key();
-        let key_checksum = key.iter().fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
-        // NOTE: use all-zero key seed for now.
-        let isp_key = Command::isp_key(vec![0; 0x1e]);
-        let resp = self.transport.transfer(isp_key)?;
-        anyhow::ensure!(resp.is_ok(), "isp_key failed");
-        anyhow::ensure!(resp.payload()[0] == key_checksum, "isp_key checksum failed");
+    /// Verify the code flash content, starting at `start_address`.
+    #[tracing::instrument(level = "debug", skip_all, fields(start_address, len = raw.len()))]
+    pub fn verify(&mut self, start_address: u32, raw: &[u8]) -> Result<()> {
+        self.check_code_flash_range(start_address, raw.len())?;
+        if self.skip_verify_in_dry_run(format_args!(
+            "verify {} bytes at 0x{:08x}",
+            raw.len(),
+            start_address
+        )) {
+            return Ok(());
+        }
 
-        const CHUNK: usize = 56;
-        let mut address = 0x0;
-        let bar = ProgressBar::new(raw.len() as _);
-        for ch in raw.chunks(CHUNK) {
+        let key = self.send_isp_key(true)?;
+
+        let chunk_size = self.chunk_size();
+        let mut address = start_address;
+        self.progress.begin("verify", raw.len() as _);
+        for ch in raw.chunks(chunk_size) {
+            self.check_cancelled()?;
             self.verify_chunk(address, ch, key)?;
             address += ch.len() as u32;
-            bar.inc(ch.len() as _);
+            self.progress.inc(ch.len() as _);
         }
-        bar.finish();
+        self.progress.finish();
 
         Ok(())
     }
 
+    /// Verify the first and last code flash sectors of the image and `samples` random chunks in
+    /// between, instead of the whole image. Much faster on slow links, but may miss corruption.
+    #[tracing::instrument(level = "debug", skip_all, fields(start_address, len = raw.len()))]
+    pub fn verify_sampled(&mut self, start_address: u32, raw: &[u8], samples: usize) -> Result<()> {
+        self.check_code_flash_range(start_address, raw.len())?;
+        if self.skip_verify_in_dry_run(format_args!(
+            "verify {} bytes at 0x{:08x}",
+            raw.len(),
+            start_address
+        )) {
+            return Ok(());
+        }
+
+        let key = self.send_isp_key(true)?;
+
+        let chunk_size = self.chunk_size();
+        let chunks: Vec<&[u8]> = raw.chunks(chunk_size).collect();
+        let sector_size = self.chip.sector_size() as usize;
+        let last_sector = raw.len().saturating_sub(sector_size);
+        let (mut selected, middle): (Vec<usize>, Vec<usize>) = (0..chunks.len())
+            .partition(|&i| i * chunk_size < sector_size || (i + 1) * chunk_size > last_sector);
+        selected.extend(
+            rand::seq::index::sample(
+                &mut rand::thread_rng(),
+                middle.len(),
+                samples.min(middle.len()),
+            )
+            .into_iter()
+            .map(|i| middle[i]),
+        );
+        selected.sort_unstable();
+
+        let total: usize = selected.iter().map(|&i| chunks[i].len()).sum();
+        tracing::debug!("verifying {} of {} chunks", selected.len(), chunks.len());
+        self.progress.begin("verify", total as _);
+        for i in selected {
+            self.check_cancelled()?;
+            let address = start_address + (i * chunk_size) as u32;
+            self.verify_chunk(address, chunks[i], key)?;
+            self.progress.inc(chunks[i].len() as _);
+        }
+        self.progress.finish();
+
+        Ok(())
+    }
+
+    /// Compare the code flash against `raw` starting at `start_address`, without stopping at
+    /// the first mismatch. Returns the mismatched regions, merged, in chunk granularity.
+    #[tracing::instrument(level = "debug", skip_all, fields(start_address, len = raw.len()))]
+    pub fn compare(&mut self, start_address: u32, raw: &[u8]) -> Result<Vec<Range<u32>>> {
+        self.check_code_flash_range(start_address, raw.len())?;
+
+        let key = self.send_isp_key(true)?;
+
+        let chunk_size = self.chunk_size();
+        let mut address = start_address;
+        let mut mismatches: Vec<Range<u32>> = vec![];
+        self.progress.begin("compare", raw.len() as _);
+        for ch in raw.chunks(chunk_size) {
+            self.check_cancelled()?;
+            let end = address + ch.len() as u32;
+            match self.verify_chunk(address, ch, key) {
+                Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::VerifyFailed { .. })) => {
+                    match mismatches.last_mut() {
+                        Some(last) if last.end == address => last.end = end,
+                        _ => mismatches.push(address..end),
+                    }
+                }
+                result => result?,
+            }
+            address = end;
+            self.progress.inc(ch.len() as _);
+        }
+        self.progress.finish();
+
+        Ok(mismatches)
+    }
+
     pub fn reset_config(&mut self) -> Result<()> {
+        self.require(Capability::ConfigMaskRead)?;
         let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
         let resp = self.transport.transfer(read_conf)?;
         anyhow::ensure!(resp.is_ok(), "read_config failed");
 
         let mut raw = resp.payload()[2..].to_vec();
 
-        log::info!("Current config registers: {}", hex::encode(&raw));
+        tracing::info!("Current config registers: {}", hex::encode(&raw));
 
         for reg_desc in &self.chip.config_registers {
             if let Some(reset) = reg_desc.reset {
@@ -296,27 +1144,70 @@ impl<'a> Flashing<'a> {
             }
         }
 
-        log::info!("Reset config registers:   {}", hex::encode(&raw));
-        let write_conf = Command::write_config(CFG_MASK_RDPR_USER_DATA_WPR, raw);
-        let resp = self.transport.transfer(write_conf)?;
-        anyhow::ensure!(resp.is_ok(), "write_config failed");
+        tracing::info!("Reset config registers:   {}", hex::encode(&raw));
+        if !self.write_config_registers(raw)? {
+            return Ok(());
+        }
+
+        // read back
+        let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
+        let resp = self.transport.transfer(read_conf)?;
+        anyhow::ensure!(resp.is_ok(), "read_config failed");
+
+        Ok(())
+    }
+
+    /// Write all config registers of `config`, e.g. modified by [`ConfigSnapshot::set`].
+    pub fn write_config(&mut self, config: &ConfigSnapshot) -> Result<()> {
+        self.require(Capability::ConfigMaskRead)?;
+        let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
+        let resp = self.transport.transfer(read_conf)?;
+        anyhow::ensure!(resp.is_ok(), "read_config failed");
+
+        let mut raw = resp.payload()[2..].to_vec();
+
+        tracing::info!("Current config registers: {}", hex::encode(&raw));
+
+        for reg in &config.registers {
+            if reg.offset + 4 <= raw.len() {
+                raw.pwrite_with(reg.value, reg.offset, scroll::LE)?;
+            }
+        }
+
+        self.write_config_raw(raw)
+    }
+
+    /// Write the raw RDPR, USER, DATA and WPR registers, e.g. restored from a backup.
+    pub fn write_config_raw(&mut self, raw: Vec<u8>) -> Result<()> {
+        self.require(Capability::ConfigMaskRead)?;
+        tracing::info!("New config registers:     {}", hex::encode(&raw));
+        if !self.write_config_registers(raw.clone())? {
+            return Ok(());
+        }
 
         // read back
         let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
         let resp = self.transport.transfer(read_conf)?;
         anyhow::ensure!(resp.is_ok(), "read_config failed");
+        if resp.payload()[2..] != raw[..] {
+            tracing::warn!(
+                "Config registers read back differ: {}, some take effect after reset",
+                hex::encode(&resp.payload()[2..])
+            );
+        }
 
         Ok(())
     }
 
     pub fn enable_debug(&mut self) -> Result<()> {
+        self.require(Capability::ConfigMaskRead)?;
         let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
         let resp = self.transport.transfer(read_conf)?;
         anyhow::ensure!(resp.is_ok(), "read_config failed");
 
         let mut raw = resp.payload()[2..].to_vec();
 
-        log::info!("Current config registers: {}", hex::encode(&raw));
+        tracing::info!("Current config registers: {}", hex::encode(&raw));
 
         for reg_desc in &self.chip.config_registers {
             if let Some(reset) = reg_desc.reset {
@@ -327,13 +1218,13 @@ impl<'a> Flashing<'a> {
             }
         }
 
-        log::info!(
+        tracing::info!(
             "Reset config registers to debug enabled:   {}",
             hex::encode(&raw)
         );
-        let write_conf = Command::write_config(CFG_MASK_RDPR_USER_DATA_WPR, raw);
-        let resp = self.transport.transfer(write_conf)?;
-        anyhow::ensure!(resp.is_ok(), "write_config failed");
+        if !self.write_config_registers(raw)? {
+            return Ok(());
+        }
 
         // read back
         let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
@@ -344,17 +1235,21 @@ impl<'a> Flashing<'a> {
     }
 
     /// Dump EEPROM, i.e. data flash.
+    #[tracing::instrument(level = "debug", skip_all)]
     pub fn dump_eeprom(&mut self) -> Result<Vec<u8>> {
         const CHUNK: usize = 0x3a;
 
         if self.chip.eeprom_size == 0 {
             anyhow::bail!("Chip does not support EEPROM");
         }
-        let bar = ProgressBar::new(self.chip.eeprom_size as _);
+        self.require(Capability::DataRead)?;
+        self.progress
+            .begin("read_eeprom", self.chip.eeprom_size as _);
 
         let mut ret: Vec<u8> = Vec::with_capacity(self.chip.eeprom_size as _);
         let mut address = 0x0;
-        while address < self.chip.eeprom_size as u32 {
+        while address < self.chip.eeprom_size {
+            self.check_cancelled()?;
             let chunk_size = u16::min(CHUNK as u16, self.chip.eeprom_size as u16 - address as u16);
 
             let cmd = Command::data_read(address, chunk_size);
@@ -371,14 +1266,14 @@ impl<'a> Flashing<'a> {
             ret.extend_from_slice(&resp.payload()[2..]);
             address += chunk_size as u32;
 
-            bar.inc(chunk_size as _);
+            self.progress.inc(chunk_size as _);
             if chunk_size < CHUNK as u16 {
-                bar.finish();
+                self.progress.finish();
                 break;
             }
         }
         anyhow::ensure!(
-            ret.len() == self.chip.eeprom_size as _,
+            ret.len() == self.chip.eeprom_size as usize,
             "EEPROM size mismatch, expected {}, got {}",
             self.chip.eeprom_size,
             ret.len()
@@ -386,55 +1281,235 @@ impl<'a> Flashing<'a> {
         Ok(ret)
     }
 
+    /// Find the EEPROM(data flash) size by binary searching the last readable address,
+    /// ignoring the size in the chip database. Returns 0 if nothing is readable.
+    pub fn probe_eeprom_size(&mut self) -> Result<u32> {
+        // NOTE: no known chip has more than 32KiB, this is only an upper bound of the search
+        const MAX_EEPROM_SIZE: u32 = 1 << 20;
+
+        self.require(Capability::DataRead)?;
+        if !self.eeprom_readable(0)? {
+            return Ok(0);
+        }
+        // invariant: `lo` is readable, `hi` is not
+        let (mut lo, mut hi) = (0, MAX_EEPROM_SIZE);
+        while hi - lo > 1 {
+            self.check_cancelled()?;
+            let mid = lo + (hi - lo) / 2;
+            if self.eeprom_readable(mid)? {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(hi)
+    }
+
+    fn eeprom_readable(&mut self, address: u32) -> Result<bool> {
+        let resp = self.transport.transfer(Command::data_read(address, 1))?;
+        tracing::debug!("data_read 0x{:08x}: {:?}", address, resp);
+        Ok(resp.is_ok() && resp.payload().len() == 3 && resp.payload()[0] == 0x00)
+    }
+
+    fn check_code_flash_range(&self, start_address: u32, len: usize) -> Result<()> {
+        let end_address = start_address as u64 + len as u64;
+        anyhow::ensure!(
+            end_address <= self.chip.flash_size as u64,
+            "firmware out of code flash range: 0x{:08x}..0x{:08x}, code flash size is {}KiB",
+            start_address,
+            end_address,
+            self.chip.flash_size / 1024
+        );
+        Ok(())
+    }
+
+    /// Run a chunk operation, retrying if it fails or times out.
+    ///
+    /// The ISP key is re-sent before each retry, to resync the bootloader after a lost packet.
+    fn retry_chunk(
+        &mut self,
+        check_checksum: bool,
+        mut op: impl FnMut(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match op(self) {
+                Err(e) if attempt < self.chunk_retries && e.downcast_ref::<Error>().is_none() => {
+                    attempt += 1;
+                    self.retried_chunks += 1;
+                    tracing::warn!(
+                        "{:#}, retrying chunk ({}/{})",
+                        e,
+                        attempt,
+                        self.chunk_retries
+                    );
+                    if let Err(e) = self.send_isp_key(check_checksum) {
+                        tracing::warn!("Re-sending ISP key failed: {:#}", e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Program `raw` with up to [`PIPELINE_DEPTH`] chunks in flight. On error, returns the address
+    /// up to which all chunks were confirmed, with no response left pending.
+    fn flash_pipelined(
+        &mut self,
+        start_address: u32,
+        raw: &[u8],
+        chunk_size: usize,
+        key: [u8; 8],
+    ) -> std::result::Result<(), (u32, anyhow::Error)> {
+        let timeout = Duration::from_millis(self.chip.timing().program);
+        let mut confirmed = start_address;
+        let mut in_flight = std::collections::VecDeque::with_capacity(PIPELINE_DEPTH);
+        let mut chunks = raw.chunks(chunk_size).peekable();
+
+        let result = (|| -> Result<()> {
+            let mut address = start_address;
+            while chunks.peek().is_some() || !in_flight.is_empty() {
+                if in_flight.len() < PIPELINE_DEPTH && !self.cancel.is_cancelled() {
+                    if let Some(ch) = chunks.next() {
+                        let xored = ch.iter().enumerate().map(|(i, x)| x ^ key[i % 8]);
+                        let cmd = Command::program(address, rand::random(), xored.collect());
+                        self.transport
+                            .send_raw(&cmd.into_raw()?)
+                            .context(Error::Transport)?;
+                        in_flight.push_back(ch.len());
+                        address += ch.len() as u32;
+                        continue;
+                    }
+                }
+                let Some(len) = in_flight.front().copied() else {
+                    // NOTE: cancelled, the lock-step loop resets the device
+                    anyhow::bail!("cancelled");
+                };
+                let resp = self.transport.recv_raw(timeout).context(Error::Transport)?;
+                in_flight.pop_front();
+                anyhow::ensure!(
+                    resp.first() == Some(&commands::PROGRAM),
+                    "response command type mismatch"
+                );
+                Response::from_raw(resp)?
+                    .check_status(commands::PROGRAM)
+                    .with_context(|| format!("program 0x{:08x} failed", confirmed))?;
+                confirmed += len as u32;
+                self.progress.inc(len as _);
+            }
+            Ok(())
+        })();
+
+        result.map_err(|e| {
+            // NOTE: drop late responses of the chunks still in flight, they are re-sent anyway
+            for _ in 0..in_flight.len() {
+                let _ = self.transport.recv_raw(timeout);
+            }
+            (confirmed, e)
+        })
+    }
+
+    #[tracing::instrument(level = "trace", skip(self, raw, key), fields(len = raw.len()))]
     fn flash_chunk(&mut self, address: u32, raw: &[u8], key: [u8; 8]) -> Result<()> {
         let xored = raw.iter().enumerate().map(|(i, x)| x ^ key[i % 8]);
         let padding = rand::random();
         let cmd = Command::program(address, padding, xored.collect());
         let resp = self
             .transport
-            .transfer_with_wait(cmd, Duration::from_millis(300))?;
+            .transfer_with_wait(cmd, Duration::from_millis(self.chip.timing().program))?;
         anyhow::ensure!(resp.is_ok(), "program 0x{:08x} failed", address);
+        if let Err(e) = resp.check_status(commands::PROGRAM) {
+            if self.code_flash_protected {
+                anyhow::bail!(Error::FlashProtected);
+            }
+            return Err(e.context(format!("program 0x{:08x} failed", address)));
+        }
         Ok(())
     }
 
+    #[tracing::instrument(level = "trace", skip(self, raw, key), fields(len = raw.len()))]
     fn write_data_chunk(&mut self, address: u32, raw: &[u8], key: [u8; 8]) -> Result<()> {
         let xored = raw.iter().enumerate().map(|(i, x)| x ^ key[i % 8]);
         let padding = rand::random();
         let cmd = Command::data_program(address, padding, xored.collect());
-        // NOTE: EEPROM write might be slow. Use 5ms timeout.
+        // NOTE: EEPROM write might be slow
         let resp = self
             .transport
-            .transfer_with_wait(cmd, Duration::from_millis(5))?;
+            .transfer_with_wait(cmd, Duration::from_millis(self.chip.timing().data_program))?;
         anyhow::ensure!(resp.is_ok(), "program data 0x{:08x} failed", address);
+        resp.check_status(commands::DATA_PROGRAM)
+            .with_context(|| format!("program data 0x{:08x} failed", address))?;
         Ok(())
     }
 
+    #[tracing::instrument(level = "trace", skip(self, raw, key), fields(len = raw.len()))]
     fn verify_chunk(&mut self, address: u32, raw: &[u8], key: [u8; 8]) -> Result<()> {
         let xored = raw.iter().enumerate().map(|(i, x)| x ^ key[i % 8]);
         let padding = rand::random();
         let cmd = Command::verify(address, padding, xored.collect());
         let resp = self.transport.transfer(cmd)?;
         anyhow::ensure!(resp.is_ok(), "verify response failed");
-        anyhow::ensure!(resp.payload()[0] == 0x00, "Verify failed, mismatch");
+        anyhow::ensure!(resp.payload()[0] == 0x00, Error::VerifyFailed { address });
         Ok(())
     }
 
+    /// Verify a chunk, on mismatch find the first mismatched byte by verifying ever shorter
+    /// prefixes of the chunk
+    fn verify_programmed_chunk(&mut self, address: u32, raw: &[u8], key: [u8; 8]) -> Result<()> {
+        match self.verify_chunk(address, raw, key) {
+            Err(e) if matches!(e.downcast_ref::<Error>(), Some(Error::VerifyFailed { .. })) => {
+                // NOTE: prefixes of `good` bytes match, of `bad` bytes do not
+                let (mut good, mut bad) = (0, raw.len());
+                while bad - good > 1 {
+                    let mid = (good + bad) / 2;
+                    match self.verify_chunk(address, &raw[..mid], key) {
+                        Err(e) if e.downcast_ref::<Error>().is_some() => bad = mid,
+                        Err(e) => return Err(e),
+                        _ => good = mid,
+                    }
+                }
+                let address = address + good as u32;
+                self.progress.finish();
+                tracing::error!("Programmed data mismatch at 0x{:08x}", address);
+                anyhow::bail!(Error::VerifyFailed { address })
+            }
+            result => result,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
     pub fn erase_code(&mut self, mut sectors: u32) -> Result<()> {
+        self.check_cancelled()?;
         let min_sectors = self.chip.min_erase_sector_number();
         if sectors < min_sectors {
             sectors = min_sectors;
-            log::warn!(
+            tracing::warn!(
                 "erase_code: set min number of erased sectors to {}",
                 sectors
             );
         }
+        if self.skip_in_dry_run(format_args!("erase {} code flash sectors", sectors)) {
+            return Ok(());
+        }
+        let timeout = self.chip.timing().erase_timeout(sectors);
+        tracing::debug!(
+            "erase_code: {} sectors, waiting up to {}ms",
+            sectors,
+            timeout
+        );
         let erase = Command::erase(sectors);
         let resp = self
             .transport
-            .transfer_with_wait(erase, Duration::from_millis(5000))?;
+            .transfer_with_wait(erase, Duration::from_millis(timeout))?;
         anyhow::ensure!(resp.is_ok(), "erase failed");
+        if let Err(e) = resp.check_status(commands::ERASE) {
+            if self.code_flash_protected {
+                anyhow::bail!(Error::FlashProtected);
+            }
+            return Err(e);
+        }
 
-        log::info!("Erased {} code flash sectors", sectors);
+        tracing::info!("Erased {} code flash sectors", sectors);
         Ok(())
     }
 
@@ -442,83 +1517,97 @@ impl<'a> Flashing<'a> {
         if self.chip.eeprom_size == 0 {
             anyhow::bail!("chip doesn't support data EEPROM");
         }
-        let sectors = (self.chip.eeprom_size / 1024).max(1) as u16;
-        let erase = Command::data_erase(sectors as _);
+        let sectors = (self.chip.eeprom_size / 1024).max(1);
+        self.erase_data_sectors(0, sectors)
+    }
+
+    /// Erase `sectors` 1KiB sectors of data flash, starting at `start_address`.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn erase_data_sectors(&mut self, start_address: u32, sectors: u32) -> Result<()> {
+        if self.chip.eeprom_size == 0 {
+            anyhow::bail!("chip doesn't support data EEPROM");
+        }
+        anyhow::ensure!(
+            start_address.is_multiple_of(1024),
+            "data flash erase must start at a 1KiB sector boundary, got 0x{:x}",
+            start_address
+        );
+        let total_sectors = self.chip.eeprom_size.div_ceil(1024);
+        anyhow::ensure!(
+            sectors > 0 && start_address / 1024 + sectors <= total_sectors,
+            "data flash erase out of range: {} sectors from 0x{:x}, data flash has {} sectors",
+            sectors,
+            start_address,
+            total_sectors
+        );
+        if self.skip_in_dry_run(format_args!(
+            "erase {} data flash sectors from 0x{:x}",
+            sectors, start_address
+        )) {
+            return Ok(());
+        }
+        let erase = Command::data_erase_at(start_address, sectors);
         let resp = self
             .transport
-            .transfer_with_wait(erase, Duration::from_millis(1000))?;
+            .transfer_with_wait(erase, Duration::from_millis(self.chip.timing().data_erase))?;
         anyhow::ensure!(resp.is_ok(), "erase_data failed");
+        resp.check_status(commands::DATA_ERASE)?;
 
-        log::info!("Erased {} data flash sectors", sectors);
+        tracing::info!(
+            "Erased {} data flash sectors from 0x{:x}",
+            sectors,
+            start_address
+        );
         Ok(())
     }
 
-    pub fn dump_config(&mut self) -> Result<()> {
+    /// Read and decode config registers
+    pub fn read_config(&mut self) -> Result<ConfigSnapshot> {
         // CH32X03x chips do not support bit mask read
         // let read_conf = Command::read_config(CFG_MASK_RDPR_USER_DATA_WPR);
-        let read_conf = Command::read_config(CFG_MASK_ALL);
-        let resp = self.transport.transfer(read_conf)?;
-        anyhow::ensure!(resp.is_ok(), "read_config failed");
-
-        let raw = &resp.payload()[2..];
-        log::info!("Current config registers: {}", hex::encode(&raw));
+        let raw = Self::read_config_all(
+            &mut self.transport,
+            &self.chip,
+            Some(self.bootloader_version),
+        )?;
+        ConfigSnapshot::decode(&self.chip, raw)
+    }
 
-        for reg_def in &self.chip.config_registers {
-            let n = raw.pread_with::<u32>(reg_def.offset, LE)?;
-            println!("{}: 0x{:08X}", reg_def.name, n);
+    pub fn dump_config(&mut self) -> Result<()> {
+        let config = self.read_config()?;
+        tracing::info!("Current config registers: {}", hex::encode(&config.raw));
+        print!("{}", config);
+        Ok(())
+    }
 
-            for (val, expain) in &reg_def.explaination {
-                if val == "_" || Some(n) == parse_number(val) {
-                    println!("  `- {}", expain);
-                    break;
-                }
-            }
+    /// Send the ISP key seed, returns the XOR key.
+    fn send_isp_key(&mut self, check_checksum: bool) -> Result<[u8; 8]> {
+        let key = self.xor_key();
+        tracing::debug!("ISP key seed: {}", hex::encode(&self.key_seed));
+        tracing::debug!("XOR key: {}", hex::encode(key));
+        let key_checksum = key.iter().fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
 
-            // byte fields
-            for field_def in &reg_def.fields {
-                let bit_width = (field_def.bit_range[0] - field_def.bit_range[1]) as u32 + 1;
-                let b = (n >> field_def.bit_range[1]) & (2_u32.pow(bit_width) - 1);
-                println!(
-                    "  {:<7} {} 0x{:X} (0b{:b})",
-                    format!("[{:}:{:}]", field_def.bit_range[0], field_def.bit_range[1]),
-                    field_def.name,
-                    b,
-                    b
-                );
-                for (val, expain) in &field_def.explaination {
-                    if val == "_" || Some(b) == parse_number(val) {
-                        println!("    `- {}", expain);
-                        break;
-                    }
-                }
-            }
+        let isp_key = Command::isp_key(self.key_seed.clone());
+        let resp = self.transport.transfer(isp_key)?;
+        anyhow::ensure!(resp.is_ok(), "isp_key failed");
+        if check_checksum {
+            anyhow::ensure!(resp.payload()[0] == key_checksum, "isp_key checksum failed");
         }
-
-        Ok(())
+        Ok(key)
     }
 
-    // NOTE: XOR key for all-zero key seed
+    /// XOR key derived from the key seed, see [`Command::IspKey`].
     fn xor_key(&self) -> [u8; 8] {
-        let checksum = self
-            .chip_uid()
-            .iter()
-            .fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
-        let mut key = [checksum; 8];
-        key.last_mut()
-            .map(|x| *x = x.overflowing_add(self.chip.chip_id).0);
-        key
+        xor_key(self.chip_uid(), &self.key_seed, self.chip.chip_id)
     }
 
     pub fn chip_uid(&self) -> &[u8] {
-        let uid_size = self.chip.uid_size();
-        //if self.bootloader_version < [0, 2, 4, 0] {
-        //    uid_size = 4
-        //}
-        &self.chip_uid[..uid_size]
+        let uid_size = self.chip.uid_size(self.bootloader_version);
+        &self.chip_uid[..uid_size.min(self.chip_uid.len())]
     }
 
     fn check_chip_uid(&self) -> Result<()> {
-        if self.chip.uid_size() == 8 {
+        if self.chip.uid_size(self.bootloader_version) == 8 {
             let raw = self.chip_uid();
             let checked = raw
                 .pread_with::<u16>(0, LE)?
@@ -532,3 +1621,322 @@ impl<'a> Flashing<'a> {
         Ok(())
     }
 }
+
+/// Builder of a complete flashing session, the same steps as `wchisp flash`.
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let mut flashing = wchisp::Flashing::new_from_usb(None)?;
+/// let segments = wchisp::format::read_firmware_segments("firmware.elf", &Default::default())?;
+/// let digest = flashing.session().verify(true).retries(2).run(&segments)?;
+/// println!("Image {}", digest);
+/// # Ok(())
+/// # }
+/// ```
+pub struct FlashSession<'f, T: Transport> {
+    flashing: &'f mut Flashing<T>,
+    erase: bool,
+    erase_all: bool,
+    verify: bool,
+    reset: bool,
+    end_reason: EndReason,
+    offset: u32,
+    retries: u32,
+    pad: Option<u8>,
+    eeprom: Option<Segment>,
+    data_segments: Vec<Segment>,
+    progress: Option<Box<dyn ProgressSink>>,
+}
+
+impl<T: Transport> FlashSession<'_, T> {
+    /// Erase the code flash before flashing, on by default
+    pub fn erase(mut self, erase: bool) -> Self {
+        self.erase = erase;
+        self
+    }
+
+    /// Allow erasing when images do not start at 0, off by default.
+    ///
+    /// The erase command always starts at the beginning of code flash, so content below the
+    /// images, e.g. a resident bootloader, is erased too. Without this, such sessions fail
+    /// before anything is written unless erase is off.
+    pub fn erase_all(mut self, erase_all: bool) -> Self {
+        self.erase_all = erase_all;
+        self
+    }
+
+    /// Verify the code flash after flashing, on by default
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Reset the chip after flashing, on by default
+    pub fn reset(mut self, reset: bool) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// How the session ends when resetting, [`EndReason::Reboot`] by default
+    pub fn end_reason(mut self, reason: EndReason) -> Self {
+        self.end_reason = reason;
+        self
+    }
+
+    /// Offset added to the address of every image
+    pub fn offset(mut self, offset: u32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Number of times a failed erase, flash or verify step is retried.
+    ///
+    /// Typed failures like [`Error::FlashProtected`] are never retried.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Pad every image to the chip's sector size with this byte, 0x00 by default.
+    /// `None` flashes images at their exact length
+    pub fn pad(mut self, pad: Option<u8>) -> Self {
+        self.pad = pad;
+        self
+    }
+
+    /// EEPROM(data flash) content written after the code flash, e.g. [`crate::format::Firmware::eeprom`].
+    ///
+    /// It goes to its offset in the chip's EEPROM window, or to the start of the EEPROM if its
+    /// address is outside of the window.
+    pub fn eeprom(mut self, eeprom: Option<Segment>) -> Self {
+        self.eeprom = eeprom;
+        self
+    }
+
+    /// Image parts already taken out of the EEPROM window, with addresses relative to it,
+    /// see [`crate::format::Firmware::take_window`]. Written to the data flash like the parts
+    /// of code flash segments inside the window.
+    pub fn data_segments(mut self, data_segments: Vec<Segment>) -> Self {
+        self.data_segments = data_segments;
+        self
+    }
+
+    /// Where the session reports progress to
+    pub fn progress(mut self, progress: Box<dyn ProgressSink>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Run the session on code flash segments, see [`crate::format::read_firmware_segments`].
+    ///
+    /// Returns the digest of all programmed images. In dry run nothing is programmed, it is the
+    /// digest of the images that would be.
+    pub fn run(self, segments: &[Segment]) -> Result<ImageDigest> {
+        let FlashSession {
+            flashing,
+            erase,
+            erase_all,
+            verify,
+            reset,
+            end_reason,
+            offset,
+            retries,
+            pad,
+            eeprom,
+            data_segments: taken,
+            progress,
+        } = self;
+        if let Some(progress) = progress {
+            flashing.set_progress(progress);
+        }
+        let segments: Vec<Segment> = segments
+            .iter()
+            .map(|segment| Segment::new(offset + segment.address, segment.data.clone()))
+            .collect();
+        // NOTE: parts of images in the EEPROM window go to the data flash, not the code flash
+        let window = flashing.chip.eeprom_window();
+        let (mut padded, mut data_segments) = match &window {
+            Some(window) => split_window(segments, window),
+            None => (segments, vec![]),
+        };
+        data_segments.extend(taken);
+        if let Some(Segment { address, data }) = eeprom {
+            let address = match &window {
+                Some(window) if window.contains(&address) => address - window.start,
+                _ => 0,
+            };
+            anyhow::ensure!(
+                address as u64 + data.len() as u64 <= flashing.chip.eeprom_size as u64,
+                "EEPROM content of {} bytes at 0x{:x} does not fit the {} bytes EEPROM",
+                data.len(),
+                address,
+                flashing.chip.eeprom_size
+            );
+            data_segments.push(Segment::new(address, data));
+        }
+        for segment in &data_segments {
+            anyhow::ensure!(
+                segment.end_address() <= flashing.chip.eeprom_size as u64,
+                "EEPROM segment 0x{:x}..0x{:x} does not fit the {} bytes EEPROM",
+                segment.address,
+                segment.end_address(),
+                flashing.chip.eeprom_size
+            );
+            tracing::info!(
+                "Image part at EEPROM 0x{:08x}..0x{:08x} goes to data flash",
+                segment.address,
+                segment.end_address()
+            );
+        }
+        let start_address = padded.iter().map(|segment| segment.address).min();
+        if let Some(start_address) = start_address.filter(|&addr| erase && addr != 0) {
+            anyhow::ensure!(
+                erase_all,
+                "erase always starts at the beginning of code flash, 0x00000000..0x{:08x} below the image would be erased too, use --no-erase to keep it or --erase-all to erase anyway",
+                start_address
+            );
+            tracing::warn!(
+                "Erasing 0x00000000..0x{:08x} below the image too",
+                start_address
+            );
+        }
+        if let Some(pad_byte) = pad {
+            let sector_size = flashing.chip.sector_size() as usize;
+            for segment in &mut padded {
+                extend_firmware_to_sector_boundary(&mut segment.data, sector_size, pad_byte);
+            }
+        }
+        let images: Vec<(u32, &[u8])> = padded
+            .iter()
+            .map(|segment| (segment.address, &segment.data[..]))
+            .collect();
+
+        if erase {
+            tracing::info!("Erasing...");
+            let end_address = images
+                .iter()
+                .map(|(addr, binary)| *addr as usize + binary.len())
+                .max()
+                .unwrap_or(0);
+            let sectors = (end_address / SECTOR_SIZE + 1) as u32;
+            with_retries(retries, || flashing.erase_code(sectors))?;
+
+            if !flashing.is_dry_run() {
+                sleep(flashing.erase_delay());
+                tracing::info!("Erase done");
+            }
+        } else {
+            tracing::warn!("Skipping erase");
+        }
+
+        tracing::info!("Writing to code flash...");
+        for (address, binary) in &images {
+            with_retries(retries, || flashing.flash(*address, binary))?;
+        }
+        if !flashing.is_dry_run() {
+            sleep(Duration::from_millis(flashing.chip.timing().program_settle));
+        }
+
+        if verify {
+            tracing::info!("Verifying...");
+            for (address, binary) in &images {
+                with_retries(retries, || flashing.verify(*address, binary))?;
+            }
+            // NOTE: verify of skipped writes is skipped too, see `Flashing::set_dry_run`
+            if !flashing.is_dry_run() {
+                tracing::info!("Verify OK");
+            }
+        } else {
+            tracing::warn!("Skipping verify");
+        }
+
+        if !data_segments.is_empty() {
+            flashing.reidenfity()?;
+            if erase {
+                let start = data_segments.iter().map(|s| s.address).min().unwrap_or(0) / 1024;
+                let end = data_segments
+                    .iter()
+                    .map(|s| s.end_address() as u32)
+                    .max()
+                    .unwrap_or(0)
+                    .div_ceil(1024);
+                with_retries(retries, || {
+                    flashing.erase_data_sectors(start * 1024, end - start)
+                })?;
+            }
+            tracing::info!("Writing to EEPROM(data flash)...");
+            for segment in &data_segments {
+                with_retries(retries, || {
+                    flashing.write_eeprom_at(segment.address, &segment.data)
+                })?;
+            }
+            if verify && !flashing.is_dry_run() && flashing.require(Capability::DataRead).is_ok() {
+                let eeprom = flashing.dump_eeprom()?;
+                for Segment { address, data } in &data_segments {
+                    let start = *address as usize;
+                    let Some(written) = eeprom.get(start..start + data.len()) else {
+                        anyhow::bail!(Error::VerifyFailed { address: *address });
+                    };
+                    if let Some(pos) = written.iter().zip(data).position(|(a, b)| a != b) {
+                        anyhow::bail!(Error::VerifyFailed {
+                            address: *address + pos as u32
+                        });
+                    }
+                }
+                tracing::info!("EEPROM verify OK");
+            }
+        }
+
+        let digest = ImageDigest::of(
+            images
+                .iter()
+                .map(|(_, binary)| *binary)
+                .chain(data_segments.iter().map(|s| &s.data[..])),
+        );
+
+        if reset {
+            tracing::info!("Now end the session and skip any communication errors");
+            let _ = flashing.end_session(end_reason);
+        } else {
+            tracing::warn!("Skipping reset");
+        }
+        Ok(digest)
+    }
+}
+
+/// XOR key of a chip UID, key seed and chip id, see [`Command::IspKey`]. `seed` must not be empty.
+pub(crate) fn xor_key(uid: &[u8], seed: &[u8], chip_id: u8) -> [u8; 8] {
+    let checksum = uid.iter().fold(0_u8, |acc, &x| acc.overflowing_add(x).0);
+    let mut key = [checksum; 8];
+
+    // NOTE: byte positions used by WCHISPTool, all-zero seed leaves the key unchanged
+    let len = seed.len();
+    let positions = [
+        len / 7 * 4,
+        len / 5,
+        len / 7,
+        len / 7 * 6,
+        len / 7 * 3,
+        len / 5 * 3,
+        len / 7 * 5,
+    ];
+    for (k, pos) in key.iter_mut().zip(positions) {
+        *k ^= seed[pos];
+    }
+    key[7] = key[0].overflowing_add(chip_id).0;
+    key
+}
+
+/// Run `step`, retrying up to `retries` times unless it fails with a typed [`Error`].
+fn with_retries(retries: u32, mut step: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match step() {
+            Err(e) if attempt < retries && e.downcast_ref::<Error>().is_none() => {
+                attempt += 1;
+                tracing::warn!("{:#}, retrying ({}/{})", e, attempt, retries);
+            }
+            result => return result,
+        }
+    }
+}
@@ -1,12 +1,15 @@
 //! Firmware file formats
 use std::str;
-use std::{borrow::Cow, path::Path};
+use std::{borrow::Cow, ops::Range, path::Path};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use object::{
-    elf::FileHeader32, elf::PT_LOAD, read::elf::FileHeader, read::elf::ProgramHeader, Endianness,
-    Object, ObjectSection,
+    elf::FileHeader32, elf::FileHeader64, elf::PT_LOAD, read::elf::FileHeader,
+    read::elf::ProgramHeader, Endian, Endianness, Object, ObjectSection, ObjectSymbol,
 };
+use serde::{Deserialize, Serialize};
+
+use crate::device::Core;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FirmwareFormat {
@@ -16,22 +19,505 @@ pub enum FirmwareFormat {
     Binary,
 }
 
+/// Formats [`Firmware::write`] can write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// Flat binary from the base address, gaps filled
+    Binary,
+    IntelHex,
+    /// Motorola S-record, with 32-bit addresses
+    SRecord,
+}
+
+impl OutputFormat {
+    /// Format by file extension, `None` if unknown
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        match &*ext {
+            "bin" => Some(OutputFormat::Binary),
+            "ihex" | "ihe" | "h86" | "hex" | "a43" | "a90" => Some(OutputFormat::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(OutputFormat::SRecord),
+            _ => None,
+        }
+    }
+}
+
+/// Which ELF program header address the segment data is programmed to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ElfAddress {
+    /// Load address, `p_paddr`, the same as `objcopy -O binary`
+    #[default]
+    Physical,
+    /// Run address, `p_vaddr`
+    Virtual,
+}
+
+/// Options for reading firmware files
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub elf_address: ElfAddress,
+    /// Byte used to fill gaps between merged sections
+    pub pad_byte: u8,
+    /// Only program these ELF sections of loadable segments, by name, e.g. `.text`
+    pub only_sections: Option<Vec<String>>,
+    /// Never program these ELF sections, by name
+    pub exclude_sections: Vec<String>,
+    /// Values patched into the image at ELF symbols, by symbol name
+    pub symbols: Vec<(String, SymbolValue)>,
+}
+
+/// A value patched into an ELF symbol, see [`ReadOptions::symbols`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolValue {
+    /// An integer in the ELF byte order, as wide as the symbol
+    Int(u64),
+    /// Raw bytes, as many as the symbol size
+    Bytes(Vec<u8>),
+}
+
+impl ReadOptions {
+    fn filters_sections(&self) -> bool {
+        self.only_sections.is_some() || !self.exclude_sections.is_empty()
+    }
+
+    fn section_selected(&self, name: &str) -> bool {
+        self.only_sections
+            .as_ref()
+            .is_none_or(|only| only.iter().any(|s| s == name))
+            && !self.exclude_sections.iter().any(|s| s == name)
+    }
+}
+
+/// A firmware image read from file
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    pub format: FirmwareFormat,
+    /// Lowest address of all sections in the file
+    pub base_address: u32,
+    /// Entry point address, if recorded in the file
+    pub entry_point: Option<u32>,
+    /// `(address, data)` sections, relative to `base_address`, ordered by address
+    pub sections: Vec<(u32, Vec<u8>)>,
+    /// The `.eeprom` ELF section at its own address, EEPROM(data flash) content, not in `sections`
+    pub eeprom: Option<Segment>,
+}
+
+impl Firmware {
+    /// Total bytes of all sections, gaps excluded
+    pub fn total_size(&self) -> usize {
+        self.sections.iter().map(|(_, data)| data.len()).sum()
+    }
+
+    /// End address of the last section, relative to `base_address`
+    pub fn end_address(&self) -> u32 {
+        self.sections
+            .last()
+            .map(|(addr, data)| addr + data.len() as u32)
+            .unwrap_or(0)
+    }
+
+    /// Absolute address of each section
+    fn absolute_sections(&self) -> impl Iterator<Item = (Range<u32>, &[u8])> {
+        self.sections.iter().map(|(addr, data)| {
+            let start = self.base_address + addr;
+            (start..start + data.len() as u32, &data[..])
+        })
+    }
+
+    fn byte_at(&self, address: u32) -> Option<u8> {
+        self.absolute_sections()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(range, data)| data[(address - range.start) as usize])
+    }
+
+    /// Regions where `other` differs from `self`, by absolute address, merged and ordered.
+    ///
+    /// Set `base_address` first to compare images without address info, e.g. a `.bin` against an ELF.
+    pub fn diff(&self, other: &Firmware) -> Vec<(Range<u32>, DiffKind)> {
+        let mut covered: Vec<Range<u32>> = self
+            .absolute_sections()
+            .chain(other.absolute_sections())
+            .map(|(range, _)| range)
+            .collect();
+        covered.sort_by_key(|range| range.start);
+
+        let mut regions: Vec<(Range<u32>, DiffKind)> = vec![];
+        let mut next = 0;
+        for range in covered {
+            // NOTE: sections of both images overlap, skip what was already compared
+            for address in range.start.max(next)..range.end {
+                let kind = match (self.byte_at(address), other.byte_at(address)) {
+                    (Some(a), Some(b)) if a == b => continue,
+                    (Some(_), Some(_)) => DiffKind::Changed,
+                    (Some(_), None) => DiffKind::OnlyLeft,
+                    (None, _) => DiffKind::OnlyRight,
+                };
+                match regions.last_mut() {
+                    Some((last, last_kind)) if last.end == address && *last_kind == kind => {
+                        last.end = address + 1
+                    }
+                    _ => regions.push((address..address + 1, kind)),
+                }
+            }
+            next = next.max(range.end);
+        }
+        regions
+    }
+
+    /// Take the parts of all sections inside `window` out of the image, e.g. of
+    /// [`crate::Chip::eeprom_window`], and return them with addresses relative to `window.start`.
+    ///
+    /// `window` is matched against absolute section addresses, so the image can still be placed
+    /// at any offset afterwards.
+    pub fn take_window(&mut self, window: &Range<u32>) -> Vec<Segment> {
+        let segments = self
+            .absolute_sections()
+            .map(|(range, data)| Segment::new(range.start, data.to_vec()))
+            .collect();
+        let (outside, inside) = split_window(segments, window);
+        self.sections = outside
+            .into_iter()
+            .map(|segment| (segment.address - self.base_address, segment.data))
+            .collect();
+        inside
+    }
+}
+
+impl Firmware {
+    /// All sections as one flat image from `base_address`, gaps filled with `fill`
+    pub fn to_binary(&self, fill: u8) -> Vec<u8> {
+        let mut binary = vec![fill; self.end_address() as usize];
+        for (addr, data) in &self.sections {
+            binary[*addr as usize..*addr as usize + data.len()].copy_from_slice(data);
+        }
+        binary
+    }
+
+    /// Intel HEX text, with the entry point as start linear address
+    pub fn to_ihex(&self) -> Result<String> {
+        use ihex::Record;
+
+        let mut records = vec![];
+        let mut upper = None;
+        for (range, data) in self.absolute_sections() {
+            for (i, chunk) in data.chunks(16).enumerate() {
+                let address = range.start + (i * 16) as u32;
+                // NOTE: records must not cross a 64KiB boundary
+                let split = (0x1_0000 - (address & 0xffff) as usize).min(chunk.len());
+                for (address, part) in [
+                    (address, &chunk[..split]),
+                    (address + split as u32, &chunk[split..]),
+                ] {
+                    if part.is_empty() {
+                        continue;
+                    }
+                    if upper != Some(address >> 16) {
+                        upper = Some(address >> 16);
+                        records.push(Record::ExtendedLinearAddress((address >> 16) as u16));
+                    }
+                    records.push(Record::Data {
+                        offset: address as u16,
+                        value: part.to_vec(),
+                    });
+                }
+            }
+        }
+        if let Some(entry) = self.entry_point {
+            records.push(Record::StartLinearAddress(entry));
+        }
+        records.push(Record::EndOfFile);
+        Ok(ihex::create_object_file_representation(&records)?)
+    }
+
+    /// Motorola S-record text, S3 data records and an S7 record with the entry point
+    pub fn to_srec(&self) -> String {
+        fn record(kind: u8, address: u32, data: &[u8]) -> String {
+            let mut bytes = vec![(4 + data.len() + 1) as u8];
+            bytes.extend_from_slice(&address.to_be_bytes());
+            bytes.extend_from_slice(data);
+            let checksum = !bytes.iter().fold(0_u8, |acc, &x| acc.wrapping_add(x));
+            format!("S{}{}{:02X}\n", kind, hex::encode_upper(&bytes), checksum)
+        }
+
+        let mut text = String::new();
+        for (range, data) in self.absolute_sections() {
+            for (i, chunk) in data.chunks(32).enumerate() {
+                text += &record(3, range.start + (i * 32) as u32, chunk);
+            }
+        }
+        text += &record(7, self.entry_point.unwrap_or(self.base_address), &[]);
+        text
+    }
+
+    /// Write the image to `path` in `format`, gaps of binary output are filled with `fill`
+    pub fn write<P: AsRef<Path>>(&self, path: P, format: OutputFormat, fill: u8) -> Result<()> {
+        let path = path.as_ref();
+        let raw = match format {
+            OutputFormat::Binary => self.to_binary(fill),
+            OutputFormat::IntelHex => self.to_ihex()?.into_bytes(),
+            OutputFormat::SRecord => self.to_srec().into_bytes(),
+        };
+        std::fs::write(path, raw).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// A code flash image at `address`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub address: u32,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn new(address: u32, data: Vec<u8>) -> Self {
+        Segment { address, data }
+    }
+
+    /// Address after the last byte
+    pub fn end_address(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// How a region differs between two firmware images, see [`Firmware::diff`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiffKind {
+    /// Both images have data here, but not the same
+    Changed,
+    /// Only the left image has data here
+    OnlyLeft,
+    /// Only the right image has data here
+    OnlyRight,
+}
+
+/// Checksums of a firmware image, as programmed to the chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageDigest {
+    pub crc32: u32,
+    pub sha256: [u8; 32],
+}
+
+impl ImageDigest {
+    /// Digest of all chunks, in the given order, i.e. gaps between sections are not included
+    pub fn of<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        use sha2::Digest;
+
+        let mut crc = crc32fast::Hasher::new();
+        let mut sha = sha2::Sha256::new();
+        for chunk in chunks {
+            crc.update(chunk);
+            sha.update(chunk);
+        }
+        ImageDigest {
+            crc32: crc.finalize(),
+            sha256: sha.finalize().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CRC32: 0x{:08x}, SHA-256: {}",
+            self.crc32,
+            hex::encode(self.sha256)
+        )
+    }
+}
+
+/// Name of the ELF section with EEPROM(data flash) content, as in AVR toolchains
+pub const EEPROM_SECTION: &str = ".eeprom";
+
+/// Sections closer than this are merged into one, with the gap filled.
+const SECTION_MERGE_GAP: u32 = 1024;
+
+/// SRAM address range of all supported chips, segments loaded here are not flashable.
+const RAM_ADDRESS_RANGE: Range<u32> = 0x2000_0000..0x4000_0000;
+
+/// Code flash is also mapped here on CH32 chips, firmware is often linked to it.
+const FLASH_ALIAS_ADDRESS: u32 = 0x0800_0000;
+
 pub fn read_firmware_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let sections = read_firmware_sections(path, &ReadOptions::default())?;
+    merge_sections(
+        sections
+            .iter()
+            .map(|(addr, data)| (*addr, data.into()))
+            .collect(),
+    )
+}
+
+/// Read firmware as separate segments, ordered by address.
+///
+/// Addresses are relative to the lowest section address, so that the image
+/// can be placed at any offset. Far-apart sections are kept separate, so the
+/// gaps between them are not programmed.
+pub fn read_firmware_segments<P: AsRef<Path>>(path: P, opts: &ReadOptions) -> Result<Vec<Segment>> {
+    Ok(read_firmware(path, opts)?
+        .sections
+        .into_iter()
+        .map(|(address, data)| Segment { address, data })
+        .collect())
+}
+
+/// Read firmware as separate `(address, data)` sections, see [`read_firmware_segments`].
+pub fn read_firmware_sections<P: AsRef<Path>>(
+    path: P,
+    opts: &ReadOptions,
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    Ok(read_firmware(path, opts)?.sections)
+}
+
+/// Read firmware file, with format, base address and entry point info.
+pub fn read_firmware<P: AsRef<Path>>(path: P, opts: &ReadOptions) -> Result<Firmware> {
     let p = path.as_ref();
+    parse_firmware(p, opts).with_context(|| crate::Error::BadFirmware(p.display().to_string()))
+}
+
+fn parse_firmware(p: &Path, opts: &ReadOptions) -> Result<Firmware> {
     let raw = std::fs::read(p)?;
+    anyhow::ensure!(!raw.is_empty(), "empty file");
 
     let format = guess_format(p, &raw);
-    log::info!("Read {} as {:?} format", p.display(), format);
-    match format {
-        FirmwareFormat::PlainHex => Ok(hex::decode(
-            raw.into_iter()
-                .filter(|&c| c != b'\r' || c != b'\n')
-                .collect::<Vec<u8>>(),
-        )?),
-        FirmwareFormat::IntelHex => Ok(read_ihex(str::from_utf8(&raw)?)?),
-        FirmwareFormat::ELF => Ok(objcopy_binary(&raw)?),
-        FirmwareFormat::Binary => Ok(raw),
+    tracing::info!("Read {} as {:?} format", p.display(), format);
+    let mut eeprom = None;
+    let (sections, entry_point) = match format {
+        FirmwareFormat::PlainHex => (vec![(0, read_hex(str::from_utf8(&raw)?)?.into())], None),
+        FirmwareFormat::IntelHex => ihex_sections(str::from_utf8(&raw)?)?,
+        FirmwareFormat::ELF => {
+            eeprom = elf_eeprom_section(&raw)?;
+            elf_sections(&raw, opts)?
+        }
+        FirmwareFormat::Binary => (vec![(0, raw.into())], None),
+    };
+    if format != FirmwareFormat::ELF && opts.filters_sections() {
+        tracing::warn!("Section filters only apply to ELF files, ignored");
+    }
+    anyhow::ensure!(
+        format == FirmwareFormat::ELF || opts.symbols.is_empty(),
+        "symbols can only be set in ELF files"
+    );
+    let base_address = sections.iter().map(|(addr, _)| *addr).min().unwrap_or(0);
+    let sections = split_sections(sections, opts.pad_byte)?;
+    Ok(Firmware {
+        format,
+        base_address,
+        entry_point,
+        sections,
+        eeprom,
+    })
+}
+
+/// Split segments at `window`, into the parts outside of it, and the parts inside of it with
+/// addresses relative to its start
+pub(crate) fn split_window(
+    segments: Vec<Segment>,
+    window: &Range<u32>,
+) -> (Vec<Segment>, Vec<Segment>) {
+    let (mut outside, mut inside) = (vec![], vec![]);
+    for Segment { address, data } in segments {
+        let clamp = |at: u32| (at as u64).clamp(address as u64, address as u64 + data.len() as u64);
+        let (start, stop) = (clamp(window.start), clamp(window.end));
+        let (a, b) = (
+            (start - address as u64) as usize,
+            (stop - address as u64) as usize,
+        );
+        if a > 0 {
+            outside.push(Segment::new(address, data[..a].to_vec()));
+        }
+        if b > a {
+            inside.push(Segment::new(
+                start as u32 - window.start,
+                data[a..b].to_vec(),
+            ));
+        }
+        if b < data.len() {
+            outside.push(Segment::new(stop as u32, data[b..].to_vec()));
+        }
+    }
+    (outside, inside)
+}
+
+/// Pad firmware with `pad_byte` to a multiple of `sector_size`.
+pub fn extend_firmware_to_sector_boundary(buf: &mut Vec<u8>, sector_size: usize, pad_byte: u8) {
+    if !buf.len().is_multiple_of(sector_size) {
+        let remain = sector_size - (buf.len() % sector_size);
+        buf.resize(buf.len() + remain, pad_byte);
+    }
+}
+
+/// Check that an image looks like firmware of `core`, returns a warning for each mismatch.
+///
+/// `image` is the data flashed first, its start must be the vector table of `core`.
+/// `entry_point` is as recorded in the file, it must be in code flash or its 0x0800_0000 alias.
+pub fn check_image_start(
+    core: Core,
+    flash_size: u32,
+    image: &[u8],
+    entry_point: Option<u32>,
+) -> Vec<String> {
+    let in_flash = |address: u32| {
+        let address = address.checked_sub(FLASH_ALIAS_ADDRESS).unwrap_or(address);
+        address < flash_size
+    };
+    let word = |index: usize| {
+        image
+            .get(index * 4..index * 4 + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+
+    let mut warnings = vec![];
+    match core {
+        Core::Riscv => {
+            let first = word(0).unwrap_or_default();
+            // NOTE: `j`/`jal`, `c.j`, or `auipc`/`lui` of a far jump to the reset handler
+            let plausible = first & 0x7f == 0x6f
+                || first & 0xe003 == 0xa001
+                || first & 0x7f == 0x17
+                || first & 0x7f == 0x37;
+            if !plausible {
+                warnings.push(format!(
+                    "image starts with 0x{:08x}, not a RISC-V jump to the reset handler",
+                    first
+                ));
+            }
+        }
+        Core::Arm => {
+            let (stack, reset) = (word(0).unwrap_or_default(), word(1).unwrap_or_default());
+            if !RAM_ADDRESS_RANGE.contains(&stack) {
+                warnings.push(format!(
+                    "initial stack pointer 0x{:08x} of the vector table is not in SRAM",
+                    stack
+                ));
+            }
+            if reset & 1 == 0 || !in_flash(reset & !1) {
+                warnings.push(format!(
+                    "reset vector 0x{:08x} is not a Thumb address in code flash",
+                    reset
+                ));
+            }
+        }
+        Core::E8051 => {
+            let first = image.first().copied().unwrap_or_default();
+            // NOTE: LJMP, AJMP or SJMP at the reset vector
+            if first != 0x02 && first & 0x1f != 0x01 && first != 0x80 {
+                warnings.push(format!(
+                    "image starts with 0x{:02x}, not an 8051 jump at the reset vector",
+                    first
+                ));
+            }
+        }
+    }
+    if let Some(entry) = entry_point.filter(|&entry| !in_flash(entry)) {
+        warnings.push(format!(
+            "entry point 0x{:08x} is outside of the {}KiB code flash",
+            entry,
+            flash_size / 1024
+        ));
     }
+    warnings
 }
 
 pub fn guess_format(path: &Path, raw: &[u8]) -> FirmwareFormat {
@@ -41,39 +527,83 @@ pub fn guess_format(path: &Path, raw: &[u8]) -> FirmwareFormat {
         .unwrap_or_default()
         .to_lowercase();
     if ["ihex", "ihe", "h86", "hex", "a43", "a90"].contains(&&*ext) {
+        // NOTE: hand-written `.hex` snippets are plain hex, Intel HEX records start with ':'
+        if !raw.starts_with(b":") && is_plain_hex(raw) {
+            return FirmwareFormat::PlainHex;
+        }
         return FirmwareFormat::IntelHex;
     }
 
     // FIXME: is this 4-byte possible to be some kind of assembly binary?
     if raw.starts_with(&[0x7f, b'E', b'L', b'F']) {
         FirmwareFormat::ELF
-    } else if raw[0] == b':'
+    } else if raw.first() == Some(&b':')
         && raw
             .iter()
             .all(|&c| (c as char).is_ascii_hexdigit() || c == b':' || c == b'\n' || c == b'\r')
     {
         FirmwareFormat::IntelHex
-    } else if raw
-        .iter()
-        .all(|&c| (c as char).is_ascii_hexdigit() || c == b'\n' || c == b'\r')
-    {
+    } else if is_plain_hex(raw) {
         FirmwareFormat::PlainHex
     } else {
         FirmwareFormat::Binary
     }
 }
 
+/// Lines of plain hex text without comments, `#` and `;` start a comment line
+fn plain_hex_lines(data: &str) -> impl Iterator<Item = (usize, &str)> {
+    data.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with(['#', ';']))
+}
+
+fn is_plain_hex(raw: &[u8]) -> bool {
+    let Ok(data) = str::from_utf8(raw) else {
+        return false;
+    };
+    let mut lines = plain_hex_lines(data).peekable();
+    lines.peek().is_some()
+        && lines.all(|(_, line)| {
+            line.chars()
+                .all(|c| c.is_ascii_hexdigit() || c.is_whitespace())
+        })
+}
+
+/// Parse plain hex text, e.g. `de ad be ef`. Whitespace is ignored, `#` and `;` start a comment
+/// line.
 pub fn read_hex(data: &str) -> Result<Vec<u8>> {
-    Ok(hex::decode(data)?)
+    let mut binary = vec![];
+    // NOTE: a byte may not span lines, so odd digit counts are reported at the offending line
+    for (lineno, line) in plain_hex_lines(data) {
+        let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        anyhow::ensure!(
+            digits.len().is_multiple_of(2),
+            "plain hex line {}: odd number of hex digits({})",
+            lineno,
+            digits.len()
+        );
+        binary.extend(hex::decode(&digits).with_context(|| format!("plain hex line {}", lineno))?);
+    }
+    anyhow::ensure!(!binary.is_empty(), "empty plain hex file");
+    Ok(binary)
 }
 
 pub fn read_ihex(data: &str) -> Result<Vec<u8>> {
+    merge_sections(ihex_sections(data)?.0)
+}
+
+type Sections<'a> = Vec<(u32, Cow<'a, [u8]>)>;
+
+/// Returns sections and the entry point.
+fn ihex_sections(data: &str) -> Result<(Sections<'static>, Option<u32>)> {
     use ihex::Record;
 
     let mut base_address = 0;
+    let mut entry_point = None;
 
     let mut records = vec![];
-    for record in ihex::Reader::new(&data) {
+    for record in ihex::Reader::new(data) {
         let record = record?;
         use Record::*;
         match record {
@@ -86,26 +616,45 @@ pub fn read_ihex(data: &str) -> Result<Vec<u8>> {
             ExtendedSegmentAddress(address) => {
                 base_address = (address as u32) * 16;
             }
-            StartSegmentAddress { .. } => (),
+            StartSegmentAddress { cs, ip } => {
+                entry_point = Some((cs as u32) * 16 + ip as u32);
+            }
             ExtendedLinearAddress(address) => {
                 base_address = (address as u32) << 16;
             }
-            StartLinearAddress(_) => (),
+            StartLinearAddress(address) => {
+                entry_point = Some(address);
+            }
         };
     }
-    merge_sections(records)
+    if records.is_empty() {
+        anyhow::bail!("empty Intel HEX file");
+    }
+    Ok((records, entry_point))
 }
 
 /// Simulates `objcopy -O binary`.
 pub fn objcopy_binary(elf_data: &[u8]) -> Result<Vec<u8>> {
+    merge_sections(elf_sections(elf_data, &ReadOptions::default())?.0)
+}
+
+/// Returns sections and the entry point.
+fn elf_sections<'a>(elf_data: &'a [u8], opts: &ReadOptions) -> Result<(Sections<'a>, Option<u32>)> {
     let file_kind = object::FileKind::parse(elf_data)?;
 
     match file_kind {
-        object::FileKind::Elf32 => (),
-        _ => anyhow::bail!("cannot read file as ELF32 format"),
+        object::FileKind::Elf32 => elf_sections_of::<FileHeader32<Endianness>>(elf_data, opts),
+        object::FileKind::Elf64 => elf_sections_of::<FileHeader64<Endianness>>(elf_data, opts),
+        _ => anyhow::bail!("cannot read file as ELF32 or ELF64 format"),
     }
-    let elf_header = FileHeader32::<Endianness>::parse(elf_data)?;
-    let binary = object::read::elf::ElfFile::<FileHeader32<Endianness>>::parse(elf_data)?;
+}
+
+fn elf_sections_of<'a, Elf: FileHeader<Endian = Endianness>>(
+    elf_data: &'a [u8],
+    opts: &ReadOptions,
+) -> Result<(Sections<'a>, Option<u32>)> {
+    let elf_header = Elf::parse(elf_data)?;
+    let binary = object::read::elf::ElfFile::<Elf>::parse(elf_data)?;
 
     let mut sections = vec![];
 
@@ -124,7 +673,7 @@ pub fn objcopy_binary(elf_data: &[u8]) -> Result<Vec<u8>> {
             .data(endian, elf_data)
             .map_err(|_| anyhow::format_err!("Failed to access data for an ELF segment."))?;
         if !segment_data.is_empty() && segment.p_type(endian) == PT_LOAD {
-            log::info!(
+            tracing::info!(
                     "Found loadable segment, physical address: {:#010x}, virtual address: {:#010x}, flags: {:#x}",
                     p_paddr,
                     p_vaddr,
@@ -132,6 +681,9 @@ pub fn objcopy_binary(elf_data: &[u8]) -> Result<Vec<u8>> {
                 );
             let (segment_offset, segment_filesize) = segment.file_range(endian);
             let mut section_names = vec![];
+            // `(offset in segment, size)` of the selected sections
+            let mut selected = vec![];
+            let mut skipped = false;
             for section in binary.sections() {
                 let (section_offset, section_filesize) = match section.file_range() {
                     Some(range) => range,
@@ -145,32 +697,237 @@ pub fn objcopy_binary(elf_data: &[u8]) -> Result<Vec<u8>> {
                 if segment_offset <= section_offset
                     && segment_offset + segment_filesize >= section_offset + section_filesize
                 {
-                    log::debug!(
+                    tracing::debug!(
                         "Matching section: {:?} offset: 0x{:x} size: 0x{:x}",
                         section.name()?,
                         section_offset,
                         section_filesize
                     );
                     for (offset, relocation) in section.relocations() {
-                        log::debug!("Relocation: offset={}, relocation={:?}", offset, relocation);
+                        tracing::debug!(
+                            "Relocation: offset={}, relocation={:?}",
+                            offset,
+                            relocation
+                        );
+                    }
+                    let name = section.name()?;
+                    if name != EEPROM_SECTION && opts.section_selected(name) {
+                        selected.push((section_offset - segment_offset, section_filesize));
+                    } else {
+                        tracing::info!("Skipping section {}", name);
+                        skipped = true;
                     }
-                    section_names.push(section.name()?.to_owned());
+                    section_names.push(name.to_owned());
                 }
             }
+            tracing::info!("Section names: {:?}", section_names);
+
+            let address = match opts.elf_address {
+                ElfAddress::Physical => p_paddr,
+                ElfAddress::Virtual => p_vaddr,
+            };
+            let address = u32::try_from(address).map_err(|_| {
+                anyhow::format_err!("Segment address {:#x} out of 32-bit range", address)
+            })?;
+            if RAM_ADDRESS_RANGE.contains(&address) {
+                tracing::warn!(
+                    "Skipping segment loaded to RAM address {:#010x}, it cannot be flashed",
+                    address
+                );
+                continue;
+            }
             let section_data = &elf_data[segment_offset as usize..][..segment_filesize as usize];
-            sections.push((p_paddr as u32, section_data.into()));
-            log::info!("Section names: {:?}", section_names);
+            if skipped {
+                for (offset, size) in selected {
+                    let data = &section_data[offset as usize..][..size as usize];
+                    sections.push((address + offset as u32, data.into()));
+                }
+            } else {
+                sections.push((address, section_data.into()));
+            }
         }
     }
 
     if sections.is_empty() {
-        anyhow::bail!("empty ELF file");
+        anyhow::bail!("empty ELF file, or no flashable segments");
+    }
+    for (name, value) in &opts.symbols {
+        patch_symbol(
+            elf_header,
+            &binary,
+            elf_data,
+            opts.elf_address,
+            &mut sections,
+            name,
+            value,
+        )?;
+    }
+    tracing::debug!("found {} sections", sections.len());
+    let entry_point = u32::try_from(elf_header.e_entry(endian).into()).ok();
+    Ok((sections, entry_point))
+}
+
+/// Patch `value` into the loaded data of symbol `name`
+fn patch_symbol<Elf: FileHeader<Endian = Endianness>>(
+    elf_header: &Elf,
+    binary: &object::read::elf::ElfFile<Elf>,
+    elf_data: &[u8],
+    elf_address: ElfAddress,
+    sections: &mut Sections,
+    name: &str,
+    value: &SymbolValue,
+) -> Result<()> {
+    let endian = elf_header.endian()?;
+    let symbol = binary
+        .symbols()
+        .find(|symbol| symbol.name() == Ok(name))
+        .ok_or_else(|| anyhow::format_err!("symbol {} not found in the ELF symbol table", name))?;
+    let (address, size) = (symbol.address(), symbol.size());
+    let bytes = match value {
+        SymbolValue::Int(value) => {
+            anyhow::ensure!(
+                matches!(size, 1 | 2 | 4 | 8),
+                "symbol {} has {} bytes, not an integer, set its bytes instead",
+                name,
+                size
+            );
+            anyhow::ensure!(
+                size == 8 || *value < 1 << (size * 8),
+                "0x{:x} does not fit the {} bytes of symbol {}",
+                value,
+                size,
+                name
+            );
+            if endian.is_little_endian() {
+                value.to_le_bytes()[..size as usize].to_vec()
+            } else {
+                value.to_be_bytes()[8 - size as usize..].to_vec()
+            }
+        }
+        SymbolValue::Bytes(bytes) => {
+            anyhow::ensure!(
+                size == 0 || bytes.len() as u64 == size,
+                "symbol {} has {} bytes, got {}",
+                name,
+                size,
+                bytes.len()
+            );
+            bytes.clone()
+        }
+    };
+    let end = address + bytes.len() as u64;
+
+    // NOTE: symbols have run addresses, initialized data is loaded from its physical address
+    let load_address = elf_header
+        .program_headers(endian, elf_data)?
+        .iter()
+        .filter(|segment| segment.p_type(endian) == PT_LOAD)
+        .find_map(|segment| {
+            let p_vaddr: u64 = segment.p_vaddr(endian).into();
+            let p_filesz: u64 = segment.p_filesz(endian).into();
+            let base: u64 = match elf_address {
+                ElfAddress::Physical => segment.p_paddr(endian).into(),
+                ElfAddress::Virtual => p_vaddr,
+            };
+            (p_vaddr <= address && end <= p_vaddr + p_filesz).then(|| base + (address - p_vaddr))
+        })
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "symbol {} at {:#010x} has no loaded data, e.g. it is in .bss",
+                name,
+                address
+            )
+        })?;
+    let (start, data) = sections
+        .iter_mut()
+        .find(|(start, data)| {
+            *start as u64 <= load_address
+                && load_address + bytes.len() as u64 <= *start as u64 + data.len() as u64
+        })
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "symbol {} at {:#010x} is not in a programmed section",
+                name,
+                load_address
+            )
+        })?;
+    let offset = (load_address - *start as u64) as usize;
+    data.to_mut()[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    tracing::info!(
+        "Symbol {} at {:#010x} set to {}",
+        name,
+        load_address,
+        hex::encode(&bytes)
+    );
+    Ok(())
+}
+
+/// The `.eeprom` section, if it has data
+fn elf_eeprom_section(elf_data: &[u8]) -> Result<Option<Segment>> {
+    let file = object::File::parse(elf_data)?;
+    let Some(section) = file.section_by_name(EEPROM_SECTION) else {
+        return Ok(None);
+    };
+    let data = section.data()?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let address = u32::try_from(section.address()).map_err(|_| {
+        anyhow::format_err!(
+            "{} address {:#x} out of 32-bit range",
+            EEPROM_SECTION,
+            section.address()
+        )
+    })?;
+    tracing::info!(
+        "Found {} section, {} bytes @ {:#010x}",
+        EEPROM_SECTION,
+        data.len(),
+        address
+    );
+    Ok(Some(Segment::new(address, data.to_vec())))
+}
+
+/// Merge adjacent sections, keep far-apart ones separate.
+///
+/// Returned addresses are relative to the lowest section address.
+fn split_sections(mut sections: Sections, pad_byte: u8) -> Result<Vec<(u32, Vec<u8>)>> {
+    sections.sort(); // order by start address
+
+    let start_address = sections.first().unwrap().0;
+
+    let mut ret: Vec<(u32, Vec<u8>)> = vec![];
+    for (addr, sect) in sections {
+        let addr = addr - start_address;
+        match ret.last_mut() {
+            Some((last_addr, last))
+                if addr < *last_addr + last.len() as u32 + SECTION_MERGE_GAP =>
+            {
+                let sect_start = (addr - *last_addr) as usize;
+                anyhow::ensure!(
+                    sect_start >= last.len(),
+                    "section overlap at 0x{:08x}",
+                    addr + start_address
+                );
+                last.resize(sect_start, pad_byte);
+                last.extend_from_slice(&sect);
+            }
+            _ => ret.push((addr, sect.into_owned())),
+        }
+    }
+    if ret.len() > 1 {
+        for (addr, sect) in &ret {
+            tracing::info!(
+                "Firmware segment: 0x{:08x}..0x{:08x}",
+                addr,
+                *addr as usize + sect.len()
+            );
+        }
     }
-    log::debug!("found {} sections", sections.len());
-    merge_sections(sections)
+    Ok(ret)
 }
 
-fn merge_sections(mut sections: Vec<(u32, Cow<[u8]>)>) -> Result<Vec<u8>> {
+fn merge_sections(mut sections: Sections) -> Result<Vec<u8>> {
     sections.sort(); // order by start address
 
     let start_address = sections.first().unwrap().0;
@@ -187,3 +944,62 @@ fn merge_sections(mut sections: Vec<(u32, Cow<[u8]>)>) -> Result<Vec<u8>> {
     }
     Ok(binary)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_hex_whitespace() {
+        let data = "de ad\tbe ef\r\n  01 02\n\n0304  \n";
+        assert_eq!(
+            read_hex(data).unwrap(),
+            [0xde, 0xad, 0xbe, 0xef, 0x01, 0x02, 0x03, 0x04]
+        );
+    }
+
+    #[test]
+    fn plain_hex_comments() {
+        let data = "# header\n; vector table\nAA BB\n  # indented\nCC\tDD\n";
+        assert_eq!(read_hex(data).unwrap(), [0xaa, 0xbb, 0xcc, 0xdd]);
+        assert!(is_plain_hex(data.as_bytes()));
+    }
+
+    #[test]
+    fn plain_hex_odd_digits() {
+        let err = read_hex("# comment\n00 11\n22 3\n44\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "plain hex line 3: odd number of hex digits(3)"
+        );
+    }
+
+    #[test]
+    fn plain_hex_empty() {
+        assert!(read_hex("").is_err());
+        assert!(read_hex("# only a comment\n\n").is_err());
+        assert!(!is_plain_hex(b""));
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(
+            guess_format(Path::new("empty.bin"), b""),
+            FirmwareFormat::Binary
+        );
+        assert_eq!(
+            guess_format(Path::new("empty.hex"), b""),
+            FirmwareFormat::IntelHex
+        );
+
+        let path = std::env::temp_dir().join(format!("wchisp-empty-{}.bin", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let err = read_firmware(&path, &ReadOptions::default()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            err.downcast_ref::<crate::Error>(),
+            Some(crate::Error::BadFirmware(_))
+        ));
+        assert_eq!(err.root_cause().to_string(), "empty file");
+    }
+}
@@ -1,13 +1,27 @@
 //! WCH ISP Protocol implementation.
+//!
+//! Diagnostics are emitted via `tracing`, with spans for each operation.
+//! Install any `tracing` subscriber to receive them.
 
+pub mod bundle;
 pub mod constants;
 pub mod device;
+pub mod emulator;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod flashing;
 pub mod format;
+pub mod progress;
 pub mod protocol;
+#[cfg(feature = "python")]
+mod python;
 pub mod transport;
 
 pub use self::device::Chip;
-pub use self::flashing::Flashing;
+pub use self::error::Error;
+pub use self::flashing::{BoxedFlashing, Flashing};
 pub use self::protocol::{Command, Response};
-pub use self::transport::{Baudrate, Transport};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::transport::Baudrate;
+pub use self::transport::Transport;
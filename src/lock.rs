@@ -0,0 +1,103 @@
+//! Advisory device locks, so that concurrent wchisp processes never share a bootloader
+use std::{
+    fs::{File, TryLockError},
+    io::{Read, Seek, Write},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use wchisp::{transport::Link, Transport};
+
+/// An exclusive lock on a device, e.g. `usb-001-005` or `serial-/dev/ttyUSB0`, released on drop
+pub struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// Lock `device`, waiting for the other process if `wait`, failing right away otherwise
+    pub fn acquire(device: &str, wait: bool) -> Result<Self> {
+        let name: String = device
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = std::env::temp_dir().join(format!("wchisp-{}.lock", name));
+        // NOTE: the lock file might be created by another user, locking works read-only as well
+        let (mut file, writable) = match File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(file) => (file, true),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                (File::open(&path)?, false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => "another wchisp process".to_string(),
+                    pid => format!("wchisp process {}", pid),
+                };
+                if !wait {
+                    anyhow::bail!(
+                        "{} is in use by {}, use --wait-lock to wait for it",
+                        device,
+                        holder
+                    );
+                }
+                tracing::info!("{} is in use by {}, waiting...", device, holder);
+                file.lock()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        if writable {
+            file.set_len(0)?;
+            file.rewind()?;
+            write!(file, "{}", std::process::id())?;
+        }
+        tracing::debug!("Locked {} via {}", device, path.display());
+        Ok(DeviceLock { _file: file })
+    }
+}
+
+/// Transport wrapper holding a [`DeviceLock`] for as long as the session lives
+pub struct LockedTransport<T> {
+    inner: T,
+    _lock: DeviceLock,
+}
+
+impl<T: Transport> LockedTransport<T> {
+    pub fn new(inner: T, lock: DeviceLock) -> Self {
+        LockedTransport { inner, _lock: lock }
+    }
+}
+
+impl<T: Transport> Transport for LockedTransport<T> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        self.inner.send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.inner.recv_raw(timeout)
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        self.inner.link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.inner.negotiate_baudrate(max_baudrate)
+    }
+}
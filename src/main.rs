@@ -1,78 +1,398 @@
-use std::{thread::sleep, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    ops::Range,
+    path::PathBuf,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use clap::{Parser, Subcommand};
+use clap::{
+    parser::ValueSource, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use hxdmp::hexdump;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+mod backup;
+mod boot;
+mod chips;
+mod doctor;
+mod emulate;
+mod lock;
+mod serve;
+mod settings;
+mod watch;
 
+use lock::{DeviceLock, LockedTransport};
+use settings::{Board, Selector, Settings};
 use wchisp::{
-    constants::SECTOR_SIZE,
-    transport::{SerialTransport, UsbTransport},
-    Baudrate, Flashing,
+    bundle::{Bundle, Segment, SignatureStatus},
+    device::{parse_number, Chip, ChipDB},
+    emulator::Emulator,
+    flashing::{BootloaderVersion, CancelToken, EndReason, KEY_SEED_LEN},
+    format::{
+        extend_firmware_to_sector_boundary, DiffKind, ElfAddress, Firmware, ImageDigest,
+        OutputFormat, ReadOptions, SymbolValue, EEPROM_SECTION,
+    },
+    progress::{BarProgress, JsonProgress, LineProgress, NoProgress},
+    transport::{
+        CaptureLink, FlowControl, LegacyTransport, Parity, PcapTransport, SerialFraming,
+        SerialTransport, StopBits, TcpTransport, TimeoutTransport, TraceTransport,
+        UsbDeviceSummary, UsbTransport,
+    },
+    Baudrate, BoxedFlashing, Flashing, Transport,
 };
 
+/// Random chunks checked by `verify --quick`, besides the first and last sectors
+const QUICK_VERIFY_SAMPLES: usize = 64;
+/// How long `--power-cycle` keeps the hub port off
+const POWER_CYCLE_OFF: Duration = Duration::from_secs(2);
+/// Wait after `--power-cycle` for the board to boot and enumerate
+const POWER_CYCLE_SETTLE: Duration = Duration::from_secs(1);
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[clap(group(clap::ArgGroup::new("transport").args(&["usb", "serial"])))]
+#[clap(group(clap::ArgGroup::new("chip_override").args(&["chip_id", "force_generic"])))]
 struct Cli {
     /// Turn debugging information on
     #[arg(long = "verbose", short = 'v')]
     debug: bool,
 
-    /// Use the USB transport layer
-    #[arg(long, short, default_value_t = true, default_value_if("serial", clap::builder::ArgPredicate::IsPresent, "false"), conflicts_with_all = ["serial", "port", "baudrate"])]
+    /// Use the USB transport layer. `WCHISP_TRANSPORT=usb|serial` selects the default
+    #[arg(long, short, default_value_t = true, default_value_if("serial", clap::builder::ArgPredicate::IsPresent, "false"), conflicts_with_all = ["serial", "port", "baudrate", "parity", "stop_bits", "flow_control"])]
     usb: bool,
 
     /// Use the Serial transport layer
     #[arg(long, short, conflicts_with_all = ["usb", "device"])]
     serial: bool,
 
-    /// Optional USB device index to operate on
-    #[arg(long, short, value_name = "INDEX", default_value = None, requires = "usb")]
-    device: Option<usize>,
+    /// Optional USB device index to operate on, or a board name from `wchisp.toml`.
+    /// Defaults to `WCHISP_DEVICE`
+    #[arg(long, short, value_name = "INDEX|BOARD", default_value = None)]
+    device: Option<String>,
 
-    /// Select the serial port
+    /// Settings file with board names, defaults to `wchisp.toml` in the current directory
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+
+    /// Select the serial port, or `tcp://HOST:PORT` for a network bridge or `wchisp emulate`.
+    /// Defaults to `WCHISP_PORT`
     #[arg(long, short, requires = "serial")]
     port: Option<String>,
 
-    /// Select the serial baudrate
+    /// Select the serial baudrate, defaults to `WCHISP_BAUDRATE`
     #[arg(long, short, ignore_case = true, value_enum, requires = "serial")]
     baudrate: Option<Baudrate>,
 
+    /// Serial parity: none, odd or even
+    #[arg(long, value_parser = parse_parity, default_value = "none", requires = "serial")]
+    parity: Parity,
+
+    /// Serial stop bits: 1 or 2
+    #[arg(long, value_parser = parse_stop_bits, default_value = "1", requires = "serial")]
+    stop_bits: StopBits,
+
+    /// Serial flow control: none, rts-cts or xon-xoff
+    #[arg(long, value_parser = parse_flow_control, default_value = "none", requires = "serial")]
+    flow_control: FlowControl,
+
+    /// Chip ID of a part not in the chip database yet, e.g. 0x5e.
+    /// Used with `--device-type` and `--flash-size` instead of the database lookup
+    #[arg(long, value_name = "ID", value_parser = parse_byte, requires_all = ["device_type", "flash_size"])]
+    chip_id: Option<u8>,
+
+    /// Device type of a part not in the chip database yet, e.g. 0x23
+    #[arg(long, value_name = "TYPE", value_parser = parse_byte, requires = "chip_id")]
+    device_type: Option<u8>,
+
+    /// Code flash size of a part not in the chip database yet, e.g. 62K
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "chip_override")]
+    flash_size: Option<u32>,
+
+    /// Use a generic profile of the identified chip family when the chip is not in the database,
+    /// with the size from `--flash-size` and without config register support
+    #[arg(long, requires = "flash_size", conflicts_with = "chip_id")]
+    force_generic: bool,
+
+    /// Use a random ISP key seed instead of the all-zero one
+    #[arg(long)]
+    random_key: bool,
+
+    /// Use this exact ISP key seed of 0x1e bytes, in hex, e.g. to reproduce a WCHISPTool session
+    #[arg(long, value_name = "HEX", value_parser = parse_key_seed, conflicts_with = "random_key")]
+    key_seed: Option<[u8; KEY_SEED_LEN]>,
+
+    /// Log erase, program and config writes instead of doing them, reads work as usual, e.g. to
+    /// check scripts and manifests against real hardware
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write all protocol packets to the file, as JSON lines
+    #[arg(long, value_name = "FILE")]
+    trace: Option<String>,
+
+    /// Capture all protocol packets to the file, in pcapng format
+    #[arg(long, value_name = "FILE")]
+    pcap: Option<String>,
+
+    /// Wait at least MS milliseconds for every response, instead of 1s, e.g. for slow USB hubs or
+    /// long serial cables. Longer waits of slow operations like erase are kept
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+
+    /// Wait MS milliseconds after a code flash erase before programming, instead of the chip's
+    /// default, e.g. for marginal supplies
+    #[arg(long, value_name = "MS")]
+    erase_settle: Option<u64>,
+
+    /// Wait MS milliseconds after programming before verifying, instead of the chip's default
+    #[arg(long, value_name = "MS")]
+    program_settle: Option<u64>,
+
+    /// Retry finding and opening the device N times with backoff, e.g. while udev is settling
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retry: u32,
+
+    /// Wait for another wchisp process using the same device, instead of failing
+    #[arg(long)]
+    wait_lock: bool,
+
+    /// Shell command to run before connecting, e.g. to toggle BOOT0 via a relay
+    #[arg(long, value_name = "CMD")]
+    pre_cmd: Option<String>,
+
+    /// Power-cycle a USB hub port before connecting, e.g. `1-1.4:2`, so that a board with BOOT0
+    /// strapped re-enters its bootloader. The hub location is as listed by `uhubctl`
+    #[arg(long, value_name = "HUB:PORT", value_parser = parse_hub_port)]
+    power_cycle: Option<(String, u8)>,
+
+    /// Shell command to run after the command, with `WCHISP_STATUS` set to `ok` or `error`
+    #[arg(long, value_name = "CMD")]
+    post_cmd: Option<String>,
+
+    /// Progress output format, `json` emits JSON lines on stderr.
+    /// Defaults to `bar` on a terminal, `line` otherwise
+    #[arg(long, value_enum)]
+    progress: Option<ProgressFormat>,
+
+    /// Do not report progress at all
+    #[arg(long, conflicts_with = "progress")]
+    no_progress: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressFormat {
+    /// Interactive progress bar
+    Bar,
+    /// A log line every 10%
+    Line,
+    /// JSON lines on stderr
+    Json,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Probe any connected devices
-    Probe {},
+    Probe {
+        /// With `--serial`, send an Identify on each port to find the ones with a WCH bootloader
+        #[arg(long)]
+        identify: bool,
+        /// Keep running, printing a line whenever a device appears or disappears
+        #[arg(long, conflicts_with = "identify")]
+        watch: bool,
+        /// With `--watch`, print events as JSON lines on stdout
+        #[arg(long, requires = "watch")]
+        json: bool,
+    },
+    /// Check for common environment problems, like missing drivers or udev rules
+    Doctor {},
+    /// Install udev rules for the ISP devices and reload udev, Linux only
+    InstallUdev {
+        /// Rules file to write
+        #[arg(long, value_name = "FILE", default_value = doctor::UDEV_RULES_PATH)]
+        path: String,
+        /// Give access to members of GROUP only, e.g. plugdev, instead of everyone
+        #[arg(long)]
+        group: Option<String>,
+        /// Print the rules instead of installing them
+        #[arg(long)]
+        print: bool,
+    },
+    /// Install the WinUSB driver for the ISP devices with pnputil, Windows only
+    InstallDriver {
+        /// Print the driver INF instead of installing it, e.g. for Zadig-less manual installs
+        #[arg(long)]
+        print: bool,
+    },
+    /// Serve the connected USB devices over TCP, as line based JSON requests
+    Serve {
+        /// Address to listen on, there is no authentication so only use trusted networks
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:4850")]
+        listen: String,
+    },
     /// Get info about current connected chip
     Info {
-        /// Chip name(prefix) check
-        #[arg(long)]
-        chip: Option<String>,
+        #[command(flatten)]
+        chip: ChipArgs,
     },
     /// Reset the target connected
-    Reset {},
+    Reset {
+        /// End the ISP session without rebooting, the bootloader stays active
+        #[arg(long)]
+        stay: bool,
+    },
     /// Erase code flash
-    Erase {},
+    Erase {
+        /// Number of 1KiB sectors to erase, defaults to the whole code flash
+        #[arg(long, value_name = "N")]
+        sectors: Option<u32>,
+        #[command(flatten)]
+        chip: ChipArgs,
+    },
     /// Download to code flash and reset
     Flash {
-        /// The path to the file to be downloaded to the code flash
-        path: String,
+        /// The path to the file to be downloaded to the code flash.
+        /// Multiple images can be given as `FILE@ADDR`, e.g. `boot.bin@0x0 app.bin@0x8000`
+        #[arg(required = true, num_args = 1.., value_name = "PATH[@ADDR]")]
+        paths: Vec<String>,
+        /// Start address(offset) in the code flash for images without an `@ADDR` suffix.
+        /// Erase always starts at 0, so a non-zero start needs `--no-erase` to keep the flash
+        /// below, e.g. a bootloader, or `--erase-all` to erase it anyway
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        offset: u32,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
         /// Do not erase the code flash before flashing
         #[clap(short = 'E', long)]
         no_erase: bool,
+        /// Erase from the beginning of code flash even if the images start above 0
+        #[arg(long, conflicts_with = "no_erase")]
+        erase_all: bool,
         /// Do not verify the code flash after flashing
         #[clap(short = 'V', long)]
         no_verify: bool,
         /// Do not reset the target after flashing
         #[clap(short = 'R', long)]
         no_reset: bool,
+        /// End the ISP session without rebooting to the application, the bootloader stays active
+        #[arg(long, conflicts_with = "no_reset")]
+        no_reboot_end: bool,
+        /// Do not write the `.eeprom` ELF section to the EEPROM(data flash)
+        #[arg(long)]
+        no_eeprom: bool,
+        /// Keep two program commands in flight instead of waiting for each response, faster on
+        /// high-latency links, falls back to lock-step on the first error
+        #[arg(long)]
+        pipeline: bool,
+        /// Verify each chunk right after programming it, stopping at the first mismatched byte.
+        /// Slower, for localizing failures on marginal boards
+        #[arg(long, conflicts_with = "pipeline")]
+        verify_each: bool,
+        /// Ed25519 public key a bundle must be signed with, hex or a file holding it. Repeatable
+        #[arg(long, value_name = "KEY|FILE", value_parser = parse_key)]
+        trusted_key: Vec<[u8; 32]>,
+        /// Flash bundles that are unsigned or signed by an untrusted key
+        #[arg(long)]
+        allow_unsigned: bool,
+        #[command(flatten)]
+        chip: ChipArgs,
     },
     /// Verify code flash content
-    Verify { path: String },
+    Verify {
+        /// The path to the file to be verified, `FILE@ADDR` is also accepted
+        #[arg(required_unless_present = "golden", num_args = 1.., value_name = "PATH[@ADDR]")]
+        paths: Vec<String>,
+        /// Start address(offset) in the code flash for images without an `@ADDR` suffix
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        offset: u32,
+        /// Compare the whole code flash against a reference image, reporting all mismatched regions
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["paths", "offset"])]
+        golden: Option<String>,
+        /// Write a JSON pass/fail report of the golden image compare
+        #[arg(long, value_name = "FILE", requires = "golden")]
+        report: Option<String>,
+        /// Only check the first and last sectors plus a random sample of chunks, for fast iteration
+        #[arg(long, conflicts_with = "golden")]
+        quick: bool,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+        #[command(flatten)]
+        chip: ChipArgs,
+    },
+    /// Run a software bootloader with in-memory flash, a hardware-free target for tests and CI
+    Emulate {
+        /// Chip to emulate, by name prefix, e.g. CH32V307
+        #[arg(long)]
+        chip: String,
+        /// TCP address to listen on
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:4000")]
+        listen: String,
+        /// Serve on a pseudo terminal instead of TCP, Unix only
+        #[arg(long, conflicts_with = "listen")]
+        pty: bool,
+        /// Initial code flash content, a raw binary at address 0
+        #[arg(long, value_name = "FILE")]
+        image: Option<String>,
+        /// Save the code flash content to FILE after each session
+        #[arg(long, value_name = "FILE")]
+        save: Option<String>,
+        /// Bootloader version to report
+        #[arg(long, value_name = "VER", value_parser = parse_bootloader_version, default_value = "02.90")]
+        bootloader_version: BootloaderVersion,
+    },
+    /// Show info about a firmware file, without connecting to the chip
+    Inspect {
+        /// The path to the firmware file
+        path: String,
+        /// Check whether the firmware fits the chip
+        #[arg(long)]
+        chip: Option<String>,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+    },
+    /// Compare two firmware files of any format by address, without connecting to the chip
+    Compare {
+        /// `FILE@ADDR` places a file without address info, e.g. `app.bin@0x08000000`
+        #[arg(value_name = "LEFT[@ADDR]")]
+        left: String,
+        #[arg(value_name = "RIGHT[@ADDR]")]
+        right: String,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+    },
+    /// Convert a firmware file to binary, Intel HEX or S-record, without connecting to the chip
+    Convert {
+        /// The firmware file to read, any supported format
+        input: String,
+        /// The file to write, the format is chosen by its extension: .bin, .hex or .srec
+        output: String,
+        /// Output format, overriding the extension of the output file
+        #[arg(long, value_enum)]
+        format: Option<ConvertFormat>,
+        /// Place the image at ADDR, the lowest address of the input by default.
+        /// Binary output has no address, it always starts at the image
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        offset: Option<u32>,
+        /// Byte used to fill gaps between sections, 0xff matches erased flash
+        #[arg(long, value_name = "BYTE", value_parser = parse_byte, default_value = "0xff")]
+        fill: u8,
+        /// Place ELF segments at their virtual address(p_vaddr), instead of the physical one
+        #[arg(long)]
+        elf_use_vaddr: bool,
+    },
+    /// Firmware bundle(.wchfw) operations, flash a bundle via `wchisp flash FILE.wchfw`
+    Bundle {
+        #[command(subcommand)]
+        command: BundleCommands,
+    },
     /// EEPROM(data flash) operations
     Eeprom {
         #[command(subcommand)]
@@ -83,24 +403,287 @@ enum Commands {
         #[command(subcommand)]
         command: Option<ConfigCommands>,
     },
+    /// Chip database operations
+    Chips {
+        #[command(subcommand)]
+        command: ChipsCommands,
+    },
+}
+
+/// Guard against operating on the wrong chip
+#[derive(Args)]
+struct ChipArgs {
+    /// Chip name check: a prefix, a glob like `CH32V30*`, or a regex like `/CH58[23]/`
+    #[arg(long)]
+    chip: Option<String>,
+}
+
+impl ChipArgs {
+    fn check(&self, flashing: &BoxedFlashing) -> Result<()> {
+        if let Some(expected_chip_name) = &self.chip {
+            flashing.check_chip_name(expected_chip_name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options of reading firmware files
+#[derive(Args)]
+struct FirmwareArgs {
+    /// Place ELF segments at their virtual address(p_vaddr)
+    #[arg(long, conflicts_with = "elf_use_paddr")]
+    elf_use_vaddr: bool,
+    /// Place ELF segments at their physical address(p_paddr), the default
+    #[arg(long)]
+    elf_use_paddr: bool,
+    /// Byte used to fill section gaps and pad to sector boundary, use 0xff to match erased flash
+    #[arg(long, value_name = "BYTE", value_parser = parse_byte, default_value = "0x00")]
+    pad_byte: u8,
+    /// Use images at their exact length, without padding to the chip's sector size
+    #[arg(long, conflicts_with = "pad_byte")]
+    no_pad: bool,
+    /// Only use these ELF sections of loadable segments, e.g. `.init,.vector,.text,.data`
+    #[arg(long, value_name = "SECTIONS", value_delimiter = ',')]
+    only_sections: Option<Vec<String>>,
+    /// Skip these ELF sections, e.g. a bootloader or debug section placed in a loadable segment
+    #[arg(long, value_name = "SECTIONS", value_delimiter = ',')]
+    exclude_sections: Vec<String>,
+    /// Set an integer ELF symbol in the image, e.g. `serial_number=0x1234`. Repeatable
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_symbol_int)]
+    set_symbol: Vec<(String, SymbolValue)>,
+    /// Set the bytes of an ELF symbol in the image, e.g. `mac=AA:BB:CC:DD:EE:FF`. Repeatable
+    #[arg(long, value_name = "NAME=BYTES", value_parser = parse_symbol_bytes)]
+    set_symbol_bytes: Vec<(String, SymbolValue)>,
+    /// Expected SHA-256 of the firmware file, checked before use. Only with a single file.
+    /// Either hex, or a manifest file in `sha256sum` format listing the firmware file name
+    #[arg(long, value_name = "HEX|FILE")]
+    sha256: Option<String>,
+}
+
+impl FirmwareArgs {
+    /// Check `--sha256` if given, returning the verified digest
+    fn check_sha256(&self, file: &FirmwareFile) -> Result<Option<String>> {
+        let Some(expected) = &self.sha256 else {
+            return Ok(None);
+        };
+        let expected = if std::path::Path::new(expected).is_file() {
+            manifest_sha256(expected, &file.name)?
+        } else {
+            expected.trim().to_ascii_lowercase()
+        };
+        file.check_sha256(&expected)?;
+        Ok(Some(expected))
+    }
+    /// Pad images to the chip's sector size, unless `--no-pad`
+    fn pad(&self, images: &mut Images, chip: &Chip) {
+        if !self.no_pad {
+            for segment in images {
+                extend_firmware_to_sector_boundary(
+                    &mut segment.data,
+                    chip.sector_size() as usize,
+                    self.pad_byte,
+                );
+            }
+        }
+    }
+
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions {
+            elf_address: if self.elf_use_vaddr {
+                ElfAddress::Virtual
+            } else {
+                ElfAddress::Physical
+            },
+            pad_byte: self.pad_byte,
+            only_sections: self.only_sections.clone(),
+            exclude_sections: self.exclude_sections.clone(),
+            symbols: self
+                .set_symbol
+                .iter()
+                .chain(&self.set_symbol_bytes)
+                .cloned()
+                .collect(),
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum ConfigCommands {
     /// Dump config register info
-    Info {},
+    Info {
+        /// Output format, `json` and `toml` print registers with decoded fields to stdout
+        #[arg(long, value_enum, default_value = "text")]
+        format: ConfigFormat,
+    },
     /// Reset config register to default
     Reset {},
     /// Enable SWD mode(simulation mode)
     EnableDebug {},
-    /// Set config register to new value
+    /// Set config registers or fields by name, e.g. `RDPR_USER.SRAM_CODE_MODE=0b11`
     Set {
-        /// New value of the config register
-        #[arg(value_name = "HEX")]
-        value: String,
+        /// `REGISTER=VALUE` or `REGISTER.FIELD=VALUE`, values in decimal, `0x` hex or `0b` binary
+        #[arg(value_name = "NAME=VALUE", required = true)]
+        assignments: Vec<String>,
     },
     /// Unprotect code flash
     Unprotect {},
+    /// Write-protect code flash sectors via the WPR register, e.g. `0..16` for the first 16
+    ProtectSectors {
+        /// Sectors of the code flash sector size, end exclusive
+        #[arg(value_name = "START..END", value_parser = parse_sector_range)]
+        sectors: Range<u32>,
+    },
+    /// Remove the write protection of code flash sectors
+    UnprotectSectors {
+        /// Sectors of the code flash sector size, end exclusive
+        #[arg(value_name = "START..END", value_parser = parse_sector_range)]
+        sectors: Range<u32>,
+    },
+    /// Save all config registers to a JSON file
+    Export {
+        /// The path of the JSON file to be written to
+        path: String,
+    },
+    /// Write config registers from a JSON file saved by `config export`
+    Import {
+        /// The path of the JSON file
+        path: String,
+    },
+    /// Restore config registers from a backup saved before each config write
+    Restore {
+        /// A backup file, or `last` for the newest backup of the connected chip
+        #[arg(default_value = "last", value_name = "FILE|last")]
+        backup: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChipsCommands {
+    /// Check chip family files, e.g. `devices/0x17-CH32V30x.yaml`, before contributing them
+    Validate {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+/// Config registers as saved by `config export`
+#[derive(Serialize, Deserialize)]
+struct ConfigFile {
+    chip: String,
+    registers: Vec<ConfigFileRegister>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConfigFileRegister {
+    name: String,
+    value: u32,
+    /// Decoded fields, for reference only, `value` is written on import
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, u32>,
+}
+
+/// Output format of `config info`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ConfigFormat {
+    Text,
+    Json,
+    Toml,
+}
+
+/// Decoded config registers printed by `config info --format json|toml`
+#[derive(Serialize)]
+struct ConfigReport {
+    chip: String,
+    uid: String,
+    raw: String,
+    registers: Vec<ConfigReportRegister>,
+    /// Code flash ranges write-protected by WPR, `start..end`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_protected: Option<Vec<Range<u32>>>,
+}
+
+#[derive(Serialize)]
+struct ConfigReportRegister {
+    name: String,
+    offset: usize,
+    value: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explanation: Option<String>,
+    fields: Vec<ConfigReportField>,
+}
+
+#[derive(Serialize)]
+struct ConfigReportField {
+    name: String,
+    /// `[msb, lsb]`, both inclusive
+    bit_range: [u8; 2],
+    value: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explanation: Option<String>,
+}
+
+/// Output format of `convert`
+#[derive(Clone, Copy, ValueEnum)]
+enum ConvertFormat {
+    Bin,
+    Hex,
+    Srec,
+}
+
+impl From<ConvertFormat> for OutputFormat {
+    fn from(format: ConvertFormat) -> Self {
+        match format {
+            ConvertFormat::Bin => OutputFormat::Binary,
+            ConvertFormat::Hex => OutputFormat::IntelHex,
+            ConvertFormat::Srec => OutputFormat::SRecord,
+        }
+    }
+}
+
+// NOTE: parsed once per run, the size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum BundleCommands {
+    /// Pack code flash images, an EEPROM image and config registers into a bundle
+    Create {
+        /// Code flash images, `FILE@ADDR` is also accepted
+        #[arg(required = true, num_args = 1.., value_name = "PATH[@ADDR]")]
+        paths: Vec<String>,
+        /// Start address(offset) in the code flash for images without an `@ADDR` suffix
+        #[arg(long, value_name = "ADDR", value_parser = parse_address, default_value = "0")]
+        offset: u32,
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+        /// The path of the bundle to be written to
+        #[arg(long, short, value_name = "FILE")]
+        output: String,
+        /// Target chip name: a prefix, a glob like `CH32V30*`, or a regex like `/CH58[23]/`
+        #[arg(long)]
+        chip: String,
+        /// Minimum bootloader version, e.g. `02.60`
+        #[arg(long, value_name = "VER", value_parser = parse_bootloader_version)]
+        min_bootloader_version: Option<BootloaderVersion>,
+        /// EEPROM(data flash) image, the whole data flash
+        #[arg(long, value_name = "FILE")]
+        eeprom: Option<String>,
+        /// Config registers, a JSON file saved by `config export`
+        #[arg(long, value_name = "FILE")]
+        registers: Option<String>,
+        /// Sign the bundle with an Ed25519 secret key file, created by `bundle keygen`
+        #[arg(long, value_name = "FILE")]
+        signing_key: Option<String>,
+    },
+    /// Generate an Ed25519 key pair for signing bundles
+    Keygen {
+        /// The path of the secret key file to be written to, the public key is printed
+        #[arg(long, short, value_name = "FILE")]
+        output: String,
+    },
+    /// Show the content of a bundle
+    Info {
+        /// The path of the bundle
+        path: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -110,8 +693,20 @@ enum EepromCommands {
         /// The path of the file to be written to
         path: Option<String>,
     },
-    /// Erase EEPROM data
-    Erase {},
+    /// Erase EEPROM data, the whole data flash by default
+    Erase {
+        /// Number of 1KiB sectors to erase, starting at `--from`
+        #[arg(long, value_name = "N", conflicts_with = "to")]
+        sectors: Option<u32>,
+        /// Start address of the erase, must be 1KiB aligned
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        from: Option<u32>,
+        /// End address(exclusive) of the erase, must be 1KiB aligned
+        #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+        to: Option<u32>,
+    },
+    /// Find the actual EEPROM size by reading, when the chip database may be wrong
+    Probe,
     /// Programming EEPROM data
     Write {
         /// The path to the file to be downloaded to the data flash
@@ -122,177 +717,732 @@ enum EepromCommands {
     },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(wchisp::Error::exit_code(&err))
+        }
+    }
+}
 
-    if cli.debug {
-        let _ = simplelog::TermLogger::init(
-            simplelog::LevelFilter::Debug,
-            simplelog::Config::default(),
-            simplelog::TerminalMode::Mixed,
-            simplelog::ColorChoice::Auto,
-        );
-    } else {
-        let _ = simplelog::TermLogger::init(
-            simplelog::LevelFilter::Info,
-            simplelog::Config::default(),
-            simplelog::TerminalMode::Mixed,
-            simplelog::ColorChoice::Auto,
-        );
+fn run() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    cli.apply_env(&matches)?;
+
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(if cli.debug {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        })
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .try_init();
+
+    let _span = tracing::debug_span!(
+        "wchisp",
+        command = matches.subcommand_name().unwrap_or("probe")
+    )
+    .entered();
+
+    let result = run_command(&cli);
+    if HOOKS_ARMED.load(Ordering::SeqCst) {
+        if let Some(cmd) = &cli.post_cmd {
+            let status = if result.is_ok() { "ok" } else { "error" };
+            let hooked = run_hook("post", cmd, status);
+            // NOTE: the command error is more interesting than the hook error
+            if result.is_ok() {
+                hooked?;
+            } else if let Err(e) = hooked {
+                tracing::warn!("{:#}", e);
+            }
+        }
     }
+    result
+}
 
+fn run_command(cli: &Cli) -> Result<()> {
     match &cli.command {
-        None | Some(Commands::Probe {}) => {
+        Some(Commands::Probe {
+            watch: true, json, ..
+        }) => {
+            watch::run(cli.usb, cli.serial, *json, cancel_token())?;
+        }
+        None | Some(Commands::Probe { .. }) => {
             if cli.usb {
-                let ndevices = UsbTransport::scan_devices()?;
-                log::info!(
+                let devices = UsbTransport::list_devices()?;
+                let ndevices = devices.len();
+                tracing::info!(
                     "Found {ndevices} USB device{}",
                     match ndevices {
                         1 => "",
                         _ => "s",
                     }
                 );
-                for i in 0..ndevices {
-                    let mut trans = UsbTransport::open_nth(i)?;
+                for device in &devices {
+                    // NOTE: never identify a device in use, the Identify would disturb its session
+                    let name = usb_device_name(device.bus_number, device.address);
+                    let _lock = match DeviceLock::acquire(&name, false) {
+                        Ok(lock) => lock,
+                        Err(e) => {
+                            tracing::info!("\tDevice #{}: {:#}", device.index, e);
+                            continue;
+                        }
+                    };
+                    let mut trans = UsbTransport::open(device)?;
                     let chip = Flashing::get_chip(&mut trans)?;
-                    log::info!("\tDevice #{i}: {chip}");
+                    tracing::info!(
+                        "\tDevice #{}: {chip} [{}]",
+                        device.index,
+                        trans.device_info()
+                    );
                 }
             }
             if cli.serial {
-                let ports = SerialTransport::scan_ports()?;
+                let ports = SerialTransport::list_ports()?;
                 let port_len = ports.len();
-                log::info!(
+                tracing::info!(
                     "Found {port_len} serial port{}:",
                     match port_len {
                         1 => "",
                         _ => "s",
                     }
                 );
+                let identify = matches!(cli.command, Some(Commands::Probe { identify: true, .. }));
                 for p in ports {
-                    log::info!("\t{p}");
+                    if !identify {
+                        tracing::info!("\t{p}");
+                        continue;
+                    }
+                    let _lock = match DeviceLock::acquire(&format!("serial-{}", p.name), false) {
+                        Ok(lock) => lock,
+                        Err(e) => {
+                            tracing::info!("\t{p}: {:#}", e);
+                            continue;
+                        }
+                    };
+                    // NOTE: the Identify is bounded by the serial read timeout, about 1s per port
+                    match SerialTransport::open(&p.name, Baudrate::Baud115200)
+                        .and_then(|mut trans| Flashing::get_chip(&mut trans))
+                    {
+                        Ok(chip) => tracing::info!("\t{p}: {chip}"),
+                        Err(e) => tracing::info!("\t{p}: no bootloader answered ({})", e),
+                    }
                 }
             }
 
-            log::info!("hint: use `wchisp info` to check chip info");
+            tracing::info!("hint: use `wchisp info` to check chip info");
+        }
+        Some(Commands::Doctor {}) => {
+            let problems = doctor::run()?;
+            if problems > 0 {
+                anyhow::bail!("{} problem(s) found", problems);
+            }
+        }
+        Some(Commands::InstallUdev { path, group, print }) => {
+            if *print {
+                print!("{}", doctor::udev_rules(group.as_deref()));
+                return Ok(());
+            }
+            #[cfg(target_os = "linux")]
+            doctor::install_udev(path, group.as_deref())?;
+            #[cfg(not(target_os = "linux"))]
+            anyhow::bail!(
+                "install-udev is only supported on Linux, not installing {}",
+                path
+            );
+        }
+        Some(Commands::InstallDriver { print }) => {
+            if *print {
+                print!("{}", doctor::winusb_inf());
+                return Ok(());
+            }
+            #[cfg(target_os = "windows")]
+            doctor::install_driver()?;
+            #[cfg(not(target_os = "windows"))]
+            anyhow::bail!(
+                "install-driver is only supported on Windows, use `install-udev` on Linux"
+            );
+        }
+        Some(Commands::Serve { listen }) => {
+            serve::run(listen)?;
         }
         Some(Commands::Info { chip }) => {
-            let mut flashing = get_flashing(&cli)?;
+            let mut flashing = get_flashing(cli)?;
 
-            if let Some(expected_chip_name) = chip {
-                flashing.check_chip_name(&expected_chip_name)?;
-            }
+            chip.check(&flashing)?;
             flashing.dump_info()?;
         }
-        Some(Commands::Reset {}) => {
-            let mut flashing = get_flashing(&cli)?;
+        Some(Commands::Reset { stay }) => {
+            let mut flashing = get_flashing(cli)?;
 
-            let _ = flashing.reset();
+            let _ = flashing.end_session(if *stay {
+                EndReason::Finish
+            } else {
+                EndReason::Reboot
+            });
         }
-        Some(Commands::Erase {}) => {
-            let mut flashing = get_flashing(&cli)?;
+        Some(Commands::Erase { sectors, chip }) => {
+            let mut flashing = get_flashing(cli)?;
 
-            let sectors = flashing.chip.flash_size / 1024;
+            chip.check(&flashing)?;
+            let max_sectors = flashing.chip.flash_size / 1024;
+            let sectors = sectors.unwrap_or(max_sectors);
+            anyhow::ensure!(
+                sectors <= max_sectors,
+                "--sectors must be at most {} for {}",
+                max_sectors,
+                flashing.chip.name
+            );
             flashing.erase_code(sectors)?;
         }
         // WRITE_CONFIG => READ_CONFIG => ISP_KEY => ERASE => PROGRAM => VERIFY => RESET
         Some(Commands::Flash {
-            path,
+            paths,
+            offset,
+            firmware,
             no_erase,
+            erase_all,
             no_verify,
             no_reset,
+            no_reboot_end,
+            no_eeprom,
+            pipeline,
+            verify_each,
+            trusted_key,
+            allow_unsigned,
+            chip,
         }) => {
-            let mut flashing = get_flashing(&cli)?;
+            // NOTE: the input is read and checked before touching the device
+            let open = || -> Result<BoxedFlashing> {
+                let mut flashing = get_flashing(cli)?;
+                chip.check(&flashing)?;
+                flashing.dump_info()?;
+                flashing.set_pipelined(*pipeline);
+                flashing.set_verify_each(*verify_each);
+                Ok(flashing)
+            };
+            let report = |digest: ImageDigest, file_sha256: Option<String>, dry_run: bool| {
+                match file_sha256 {
+                    // NOTE: nothing was written, don't print a digest as if it was flashed
+                    _ if dry_run => tracing::info!("Dry run: nothing flashed"),
+                    Some(sha256) => tracing::info!("Image {}, file SHA-256: {}", digest, sha256),
+                    None => tracing::info!("Image {}", digest),
+                }
+            };
 
-            flashing.dump_info()?;
+            let end_reason = if *no_reboot_end {
+                EndReason::Finish
+            } else {
+                EndReason::Reboot
+            };
+            if let [path] = &paths[..] {
+                if Bundle::is_bundle_path(path) {
+                    let file = FirmwareFile::fetch(path)?;
+                    let file_sha256 = firmware.check_sha256(&file)?;
+                    let bundle = Bundle::read(&file.path)?;
+                    match bundle.check_signature(trusted_key)? {
+                        SignatureStatus::Trusted => tracing::info!("Bundle signature OK"),
+                        SignatureStatus::Invalid => anyhow::bail!(wchisp::Error::BadFirmware(
+                            format!("{}: invalid bundle signature", path)
+                        )),
+                        status if *allow_unsigned => {
+                            tracing::warn!(
+                                "Flashing bundle without a trusted signature: {:?}",
+                                status
+                            )
+                        }
+                        SignatureStatus::Unsigned => anyhow::bail!(
+                            "bundle is not signed, use --allow-unsigned to flash it anyway"
+                        ),
+                        SignatureStatus::UntrustedKey => anyhow::bail!(
+                            "bundle is signed by an untrusted key {}, add it with --trusted-key",
+                            hex::encode(bundle.signer().unwrap_or_default())
+                        ),
+                    }
+                    let mut flashing = open()?;
+                    let digest = bundle.apply(
+                        &mut flashing,
+                        !*no_erase,
+                        *erase_all,
+                        !*no_verify,
+                        (!*no_reset).then_some(end_reason),
+                    )?;
+                    report(digest, file_sha256, flashing.is_dry_run());
+                    return Ok(());
+                }
+            }
+
+            let mut flashing = open()?;
+            let window = flashing.chip.eeprom_window();
+            let images = read_images(paths, *offset, firmware, window.as_ref())?;
+            if images.eeprom.is_some() && *no_eeprom {
+                tracing::warn!("Skipping the {} section", EEPROM_SECTION);
+            }
+            if let (Some(core), Some(first)) = (flashing.chip.core(), images.code.first()) {
+                let warnings = wchisp::format::check_image_start(
+                    core,
+                    flashing.chip.flash_size,
+                    &first.data,
+                    images.entry_point,
+                );
+                for warning in warnings {
+                    tracing::warn!(
+                        "{}, is the firmware built for {}?",
+                        warning,
+                        flashing.chip.name
+                    );
+                }
+            }
 
-            let mut binary = wchisp::format::read_firmware_from_file(path)?;
-            extend_firmware_to_sector_boundary(&mut binary);
-            log::info!("Firmware size: {}", binary.len());
+            let digest = flashing
+                .session()
+                .erase(!*no_erase)
+                .erase_all(*erase_all)
+                .verify(!*no_verify)
+                .reset(!*no_reset)
+                .end_reason(end_reason)
+                .pad((!firmware.no_pad).then_some(firmware.pad_byte))
+                .eeprom(images.eeprom.filter(|_| !*no_eeprom))
+                .data_segments(images.data)
+                .run(&images.code)?;
+            report(digest, images.sha256, flashing.is_dry_run());
+        }
+        Some(Commands::Verify {
+            golden: Some(golden),
+            report,
+            firmware,
+            chip,
+            ..
+        }) => {
+            let mut flashing = get_flashing(cli)?;
 
-            if *no_erase {
-                log::warn!("Skipping erase");
-            } else {
-                log::info!("Erasing...");
-                let sectors = binary.len() / SECTOR_SIZE + 1;
-                flashing.erase_code(sectors as u32)?;
+            chip.check(&flashing)?;
+            let mut images = read_images(std::slice::from_ref(golden), 0, firmware, None)?.code;
+            firmware.pad(&mut images, &flashing.chip);
+            let size: usize = images.iter().map(|segment| segment.data.len()).sum();
+            if size < flashing.chip.flash_size as usize {
+                tracing::warn!(
+                    "Golden image covers {} of {} bytes code flash",
+                    size,
+                    flashing.chip.flash_size
+                );
+            }
 
-                sleep(Duration::from_secs(1));
-                log::info!("Erase done");
+            tracing::info!("Comparing against golden image {}...", golden);
+            let mut mismatches = vec![];
+            for segment in &images {
+                mismatches.extend(flashing.compare(segment.address, &segment.data)?);
+            }
+            for region in &mismatches {
+                tracing::error!("Mismatch: 0x{:08x}..0x{:08x}", region.start, region.end);
+            }
+
+            if let Some(report) = report {
+                let digest = ImageDigest::of(images.iter().map(|segment| &segment.data[..]));
+                let json = serde_json::json!({
+                    "chip": flashing.chip.name,
+                    "uid": hex::encode(flashing.chip_uid()),
+                    "golden": golden,
+                    "golden_sha256": hex::encode(digest.sha256),
+                    "size": size,
+                    "pass": mismatches.is_empty(),
+                    "mismatches": mismatches
+                        .iter()
+                        .map(|region| serde_json::json!({ "start": region.start, "end": region.end }))
+                        .collect::<Vec<_>>(),
+                });
+                std::fs::write(report, serde_json::to_string_pretty(&json)? + "\n")?;
+                tracing::info!("Report written to {}", report);
             }
 
-            log::info!("Writing to code flash...");
-            flashing.flash(&binary)?;
-            sleep(Duration::from_millis(500));
+            match mismatches.first() {
+                None => tracing::info!("Golden compare OK"),
+                Some(region) => anyhow::bail!(wchisp::Error::VerifyFailed {
+                    address: region.start
+                }),
+            }
+        }
+        Some(Commands::Verify {
+            paths,
+            offset,
+            quick,
+            firmware,
+            chip,
+            ..
+        }) => {
+            let mut flashing = get_flashing(cli)?;
 
-            if *no_verify {
-                log::warn!("Skipping verify");
+            chip.check(&flashing)?;
+            let mut images = read_images(paths, *offset, firmware, None)?.code;
+            firmware.pad(&mut images, &flashing.chip);
+            if *quick {
+                tracing::info!("Verifying a sample of chunks...");
+                for segment in &images {
+                    flashing.verify_sampled(
+                        segment.address,
+                        &segment.data,
+                        QUICK_VERIFY_SAMPLES,
+                    )?;
+                }
+                tracing::info!("Quick verify OK, run a full verify before release");
             } else {
-                log::info!("Verifying...");
-                flashing.verify(&binary)?;
-                log::info!("Verify OK");
+                tracing::info!("Verifying...");
+                for segment in &images {
+                    flashing.verify(segment.address, &segment.data)?;
+                }
+                tracing::info!("Verify OK");
             }
+        }
+        Some(Commands::Inspect {
+            path,
+            chip,
+            firmware,
+        }) => {
+            let file = FirmwareFile::fetch(path)?;
+            firmware.check_sha256(&file)?;
+            let firmware = wchisp::format::read_firmware(&file.path, &firmware.read_options())?;
 
-            if *no_reset {
-                log::warn!("Skipping reset");
+            println!("Format: {:?}", firmware.format);
+            println!("Base address: 0x{:08x}", firmware.base_address);
+            match firmware.entry_point {
+                Some(entry) => println!("Entry point: 0x{:08x}", entry),
+                None => println!("Entry point: N/A"),
+            }
+            println!("Segments:");
+            for (addr, data) in &firmware.sections {
+                let start = firmware.base_address + addr;
+                println!(
+                    "  0x{:08x}..0x{:08x} {:>8} bytes",
+                    start,
+                    start as usize + data.len(),
+                    data.len()
+                );
+            }
+            println!(
+                "Flash usage: {} bytes ({} bytes span)",
+                firmware.total_size(),
+                firmware.end_address()
+            );
+
+            if let Some(chip_name) = chip {
+                let chip = ChipDB::load()?.find_chip_by_name(chip_name)?;
+                let fits = firmware.end_address() <= chip.flash_size;
+                println!(
+                    "Fits {} (Code Flash: {}KiB): {}",
+                    chip.name,
+                    chip.flash_size / 1024,
+                    if fits { "yes" } else { "no" }
+                );
+                if let (Some(core), Some((_, first))) = (chip.core(), firmware.sections.first()) {
+                    let warnings = wchisp::format::check_image_start(
+                        core,
+                        chip.flash_size,
+                        first,
+                        firmware.entry_point,
+                    );
+                    for warning in warnings {
+                        println!("Warning: {}", warning);
+                    }
+                }
+                if !fits {
+                    anyhow::bail!("firmware does not fit in {} code flash", chip.name);
+                }
+            }
+        }
+        Some(Commands::Emulate {
+            chip,
+            listen,
+            pty,
+            image,
+            save,
+            bootloader_version,
+        }) => {
+            let chip = ChipDB::load()?.find_chip_by_name(chip)?;
+            let mut emulator = Emulator::new(chip).bootloader_version(*bootloader_version);
+            if let Some(path) = image {
+                let raw = std::fs::read(path)?;
+                anyhow::ensure!(
+                    raw.len() <= emulator.flash.len(),
+                    "image larger than the {} bytes code flash",
+                    emulator.flash.len()
+                );
+                emulator.flash[..raw.len()].copy_from_slice(&raw);
+            }
+            let endpoint = if *pty {
+                emulate::Endpoint::Pty
             } else {
-                log::info!("Now reset device and skip any communication errors");
-                let _ = flashing.reset();
+                emulate::Endpoint::Tcp(listen)
+            };
+            emulate::run(emulator, endpoint, save.as_deref())?;
+        }
+        Some(Commands::Convert {
+            input,
+            output,
+            format,
+            offset,
+            fill,
+            elf_use_vaddr,
+        }) => {
+            let format = match format {
+                Some(format) => (*format).into(),
+                None => OutputFormat::from_path(std::path::Path::new(output)).ok_or_else(|| {
+                    anyhow::format_err!("unknown output format of {}, use --format", output)
+                })?,
+            };
+            let opts = ReadOptions {
+                elf_address: if *elf_use_vaddr {
+                    ElfAddress::Virtual
+                } else {
+                    ElfAddress::Physical
+                },
+                pad_byte: *fill,
+                ..Default::default()
+            };
+            let file = FirmwareFile::fetch(input)?;
+            let mut image = wchisp::format::read_firmware(&file.path, &opts)?;
+            if let Some(offset) = offset {
+                // NOTE: the entry point moves with the image
+                image.entry_point = image
+                    .entry_point
+                    .map(|entry| entry.wrapping_sub(image.base_address).wrapping_add(*offset));
+                image.base_address = *offset;
             }
+            image.write(output, format, *fill)?;
+            tracing::info!(
+                "{} written as {:?}, 0x{:08x}..0x{:08x}",
+                output,
+                format,
+                image.base_address,
+                image.base_address as u64 + image.end_address() as u64
+            );
         }
-        Some(Commands::Verify { path }) => {
-            let mut flashing = get_flashing(&cli)?;
+        Some(Commands::Compare {
+            left,
+            right,
+            firmware,
+        }) => {
+            anyhow::ensure!(
+                firmware.sha256.is_none(),
+                "--sha256 needs a single firmware file"
+            );
+            let read = |arg: &String| -> Result<(String, Firmware)> {
+                let (path, address) = match arg.rsplit_once('@') {
+                    Some((path, addr)) if parse_address(addr).is_ok() => {
+                        (path, Some(parse_address(addr)?))
+                    }
+                    _ => (arg.as_str(), None),
+                };
+                let file = FirmwareFile::fetch(path)?;
+                let mut image =
+                    wchisp::format::read_firmware(&file.path, &firmware.read_options())?;
+                if let Some(address) = address {
+                    image.base_address = address;
+                }
+                println!(
+                    "{}: {:?}, 0x{:08x}..0x{:08x}, {} bytes",
+                    file.name,
+                    image.format,
+                    image.base_address,
+                    image.base_address as u64 + image.end_address() as u64,
+                    image.total_size()
+                );
+                Ok((file.name.clone(), image))
+            };
+            let (left_name, left) = read(left)?;
+            let (right_name, right) = read(right)?;
 
-            let mut binary = wchisp::format::read_firmware_from_file(path)?;
-            extend_firmware_to_sector_boundary(&mut binary);
-            log::info!("Firmware size: {}", binary.len());
-            log::info!("Verifying...");
-            flashing.verify(&binary)?;
-            log::info!("Verify OK");
+            let regions = left.diff(&right);
+            if regions.is_empty() {
+                println!("Identical");
+                return Ok(());
+            }
+            println!("Differing regions:");
+            for (range, kind) in &regions {
+                let kind = match kind {
+                    DiffKind::Changed => "changed".to_string(),
+                    DiffKind::OnlyLeft => format!("only in {}", left_name),
+                    DiffKind::OnlyRight => format!("only in {}", right_name),
+                };
+                println!(
+                    "  0x{:08x}..0x{:08x} {:>8} bytes {}",
+                    range.start,
+                    range.end,
+                    range.len(),
+                    kind
+                );
+            }
+            anyhow::bail!("firmware files differ in {} regions", regions.len());
         }
+        Some(Commands::Bundle { command }) => match command {
+            BundleCommands::Create {
+                paths,
+                offset,
+                firmware,
+                output,
+                chip,
+                min_bootloader_version,
+                eeprom,
+                registers,
+                signing_key,
+            } => {
+                let mut bundle = Bundle::new(chip);
+                bundle.min_bootloader_version = *min_bootloader_version;
+                bundle.code = read_images(paths, *offset, firmware, None)?.code;
+                if let Some(path) = eeprom {
+                    bundle.eeprom = Some(std::fs::read(path)?);
+                }
+                if let Some(path) = registers {
+                    let file: ConfigFile = serde_json::from_slice(&std::fs::read(path)?)?;
+                    bundle.config = file
+                        .registers
+                        .into_iter()
+                        .map(|reg| (reg.name, reg.value))
+                        .collect();
+                }
+                if let Some(path) = signing_key {
+                    bundle.sign(&read_key(path)?)?;
+                    tracing::info!("Bundle signed");
+                }
+                bundle.write(output)?;
+                tracing::info!("Bundle written to {}", output);
+            }
+            BundleCommands::Keygen { output } => {
+                let secret_key = rand::random::<[u8; 32]>();
+                std::fs::write(output, hex::encode(secret_key) + "\n")?;
+                tracing::info!("Secret key written to {}, keep it private", output);
+                println!("{}", hex::encode(Bundle::public_key(&secret_key)));
+            }
+            BundleCommands::Info { path } => {
+                let bundle = Bundle::read(path)?;
+                println!("Chip: {}", bundle.chip);
+                match bundle.min_bootloader_version {
+                    Some(version) => println!("Bootloader: {} or later", version),
+                    None => println!("Bootloader: any"),
+                }
+                println!("Code flash:");
+                for segment in &bundle.code {
+                    println!(
+                        "  0x{:08x}..0x{:08x} ({} bytes)",
+                        segment.address,
+                        segment.address as usize + segment.data.len(),
+                        segment.data.len()
+                    );
+                }
+                match &bundle.eeprom {
+                    Some(eeprom) => println!("EEPROM: {} bytes", eeprom.len()),
+                    None => println!("EEPROM: N/A"),
+                }
+                println!("Config registers:");
+                for (name, value) in &bundle.config {
+                    println!("  {} = 0x{:08X}", name, value);
+                }
+                match bundle.check_signature(&[])? {
+                    SignatureStatus::Unsigned => println!("Signature: N/A"),
+                    SignatureStatus::Invalid => println!("Signature: INVALID"),
+                    _ => println!(
+                        "Signature: {}",
+                        hex::encode(bundle.signer().unwrap_or_default())
+                    ),
+                }
+            }
+        },
+        Some(Commands::Chips { command }) => match command {
+            ChipsCommands::Validate { paths } => {
+                let mut count = 0;
+                for path in paths {
+                    let problems = chips::validate(path)?;
+                    for problem in &problems {
+                        println!("{}", problem);
+                    }
+                    if problems.is_empty() {
+                        println!("{}: OK", path.display());
+                    }
+                    count += problems.len();
+                }
+                if count > 0 {
+                    anyhow::bail!("{} problems found", count);
+                }
+            }
+        },
         Some(Commands::Eeprom { command }) => {
-            let mut flashing = get_flashing(&cli)?;
+            let mut flashing = get_flashing(cli)?;
 
             match command {
                 None | Some(EepromCommands::Dump { .. }) => {
                     flashing.reidenfity()?;
 
-                    log::info!("Reading EEPROM(Data Flash)...");
+                    tracing::info!("Reading EEPROM(Data Flash)...");
 
                     let eeprom = flashing.dump_eeprom()?;
-                    log::info!("EEPROM data size: {}", eeprom.len());
+                    tracing::info!("EEPROM data size: {}", eeprom.len());
 
                     if let Some(EepromCommands::Dump {
                         path: Some(ref path),
                     }) = command
                     {
                         std::fs::write(path, eeprom)?;
-                        log::info!("EEPROM data saved to {}", path);
+                        tracing::info!("EEPROM data saved to {}", path);
                     } else {
                         let mut buf = vec![];
                         hexdump(&eeprom, &mut buf)?;
                         println!("{}", String::from_utf8_lossy(&buf));
                     }
                 }
-                Some(EepromCommands::Erase {}) => {
+                Some(EepromCommands::Erase { sectors, from, to }) => {
                     flashing.reidenfity()?;
 
-                    log::info!("Erasing EEPROM(Data Flash)...");
-                    flashing.erase_data()?;
-                    log::info!("EEPROM erased");
+                    tracing::info!("Erasing EEPROM(Data Flash)...");
+                    if sectors.is_none() && from.is_none() && to.is_none() {
+                        flashing.erase_data()?;
+                    } else {
+                        let from = from.unwrap_or(0);
+                        let sectors = match (sectors, to) {
+                            (Some(sectors), _) => *sectors,
+                            (None, to) => {
+                                let to = to.unwrap_or(flashing.chip.eeprom_size);
+                                anyhow::ensure!(
+                                    to > from && to.is_multiple_of(1024),
+                                    "--to must be a 1KiB aligned address after --from"
+                                );
+                                (to - from) / 1024
+                            }
+                        };
+                        flashing.erase_data_sectors(from, sectors)?;
+                    }
+                    tracing::info!("EEPROM erased");
+                }
+                Some(EepromCommands::Probe) => {
+                    flashing.reidenfity()?;
+
+                    tracing::info!("Probing EEPROM(Data Flash) size...");
+                    let size = flashing.probe_eeprom_size()?;
+                    let expected = flashing.chip.eeprom_size;
+                    println!("EEPROM size: {} bytes ({}KiB)", size, size as f32 / 1024.0);
+                    if size != expected {
+                        tracing::warn!(
+                            "Chip database says {} bytes for {}, please report it",
+                            expected,
+                            flashing.chip.name
+                        );
+                    }
                 }
                 Some(EepromCommands::Write { path, no_erase }) => {
                     flashing.reidenfity()?;
 
                     if *no_erase {
-                        log::warn!("Skipping erase");
+                        tracing::warn!("Skipping erase");
                     } else {
-                        log::info!("Erasing EEPROM(Data Flash)...");
+                        tracing::info!("Erasing EEPROM(Data Flash)...");
                         flashing.erase_data()?;
-                        log::info!("EEPROM erased");
+                        tracing::info!("EEPROM erased");
                     }
 
                     let eeprom = std::fs::read(path)?;
-                    log::info!("Read {} bytes from bin file", eeprom.len());
+                    tracing::info!("Read {} bytes from bin file", eeprom.len());
                     if eeprom.len() as u32 != flashing.chip.eeprom_size {
                         anyhow::bail!(
                             "EEPROM size mismatch: expected {}, got {}",
@@ -301,57 +1451,776 @@ fn main() -> Result<()> {
                         );
                     }
 
-                    log::info!("Writing EEPROM(Data Flash)...");
+                    tracing::info!("Writing EEPROM(Data Flash)...");
                     flashing.write_eeprom(&eeprom)?;
-                    log::info!("EEPROM written");
+                    tracing::info!("EEPROM written");
                 }
             }
         }
         Some(Commands::Config { command }) => {
-            let mut flashing = get_flashing(&cli)?;
+            let mut flashing = get_flashing(cli)?;
 
             match command {
-                None | Some(ConfigCommands::Info {}) => {
+                None
+                | Some(ConfigCommands::Info {
+                    format: ConfigFormat::Text,
+                }) => {
                     flashing.dump_config()?;
                 }
+                Some(ConfigCommands::Info { format }) => {
+                    let config = flashing.read_config()?;
+                    let report = ConfigReport {
+                        chip: flashing.chip.display_name().to_string(),
+                        uid: hex::encode(flashing.chip_uid()),
+                        raw: hex::encode(&config.raw),
+                        registers: config
+                            .registers
+                            .into_iter()
+                            .map(|reg| ConfigReportRegister {
+                                name: reg.name,
+                                offset: reg.offset,
+                                value: reg.value,
+                                explanation: reg.explanation,
+                                fields: reg
+                                    .fields
+                                    .into_iter()
+                                    .map(|field| ConfigReportField {
+                                        name: field.name,
+                                        bit_range: [field.bit_range.0, field.bit_range.1],
+                                        value: field.value,
+                                        explanation: field.explanation,
+                                    })
+                                    .collect(),
+                            })
+                            .collect(),
+                        write_protected: config.write_protected,
+                    };
+                    if *format == ConfigFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&report)?);
+                    } else {
+                        print!("{}", toml::to_string(&report)?);
+                    }
+                }
                 Some(ConfigCommands::Reset {}) => {
                     flashing.reset_config()?;
-                    log::info!(
+                    tracing::info!(
                         "Config register restored to default value(non-protected, debug-enabled)"
                     );
                 }
                 Some(ConfigCommands::EnableDebug {}) => {
                     flashing.enable_debug()?;
-                    log::info!("Debug mode enabled");
+                    tracing::info!("Debug mode enabled");
                 }
-                Some(ConfigCommands::Set { value }) => {
-                    // flashing.write_config(value)?;
-                    log::info!("setting cfg value {}", value);
-                    unimplemented!()
+                Some(ConfigCommands::Set { assignments }) => {
+                    let mut config = flashing.read_config()?;
+                    for assignment in assignments {
+                        let (name, value) = assignment.split_once('=').ok_or_else(|| {
+                            anyhow::format_err!("expected NAME=VALUE, got {}", assignment)
+                        })?;
+                        let value = parse_number(value).ok_or_else(|| {
+                            anyhow::format_err!("invalid value of {}: {}", name, value)
+                        })?;
+                        config.set(name, value)?;
+                        tracing::info!("Setting {} to 0x{:X}", name, value);
+                    }
+                    flashing.write_config(&config)?;
+                    tracing::info!("Config registers written");
                 }
                 Some(ConfigCommands::Unprotect {}) => {
                     flashing.unprotect(true)?;
                 }
+                Some(ConfigCommands::ProtectSectors { sectors }) => {
+                    flashing.protect_sectors(sectors.clone(), true)?;
+                }
+                Some(ConfigCommands::UnprotectSectors { sectors }) => {
+                    flashing.protect_sectors(sectors.clone(), false)?;
+                }
+                Some(ConfigCommands::Export { path }) => {
+                    let config = flashing.read_config()?;
+                    let file = ConfigFile {
+                        chip: flashing.chip.name.clone(),
+                        registers: config
+                            .registers
+                            .iter()
+                            .map(|reg| ConfigFileRegister {
+                                name: reg.name.clone(),
+                                value: reg.value,
+                                fields: reg
+                                    .fields
+                                    .iter()
+                                    .map(|field| (field.name.clone(), field.value))
+                                    .collect(),
+                            })
+                            .collect(),
+                    };
+                    std::fs::write(path, serde_json::to_string_pretty(&file)? + "\n")?;
+                    tracing::info!("Config registers saved to {}", path);
+                }
+                Some(ConfigCommands::Import { path }) => {
+                    let file: ConfigFile = serde_json::from_slice(&std::fs::read(path)?)?;
+                    if file.chip != flashing.chip.name {
+                        tracing::warn!(
+                            "Config exported from {}, importing to {}",
+                            file.chip,
+                            flashing.chip.name
+                        );
+                    }
+                    let mut config = flashing.read_config()?;
+                    for reg in &file.registers {
+                        config.set(&reg.name, reg.value)?;
+                    }
+                    flashing.write_config(&config)?;
+                    tracing::info!("Config registers imported from {}", path);
+                }
+                Some(ConfigCommands::Restore { backup }) => {
+                    let path = match backup.as_str() {
+                        "last" => backup::latest(flashing.chip_uid())?,
+                        path => PathBuf::from(path),
+                    };
+                    let backup = backup::load(&path)?;
+                    anyhow::ensure!(
+                        backup.uid == hex::encode(flashing.chip_uid()),
+                        "backup {} is of chip {}, not of the connected one",
+                        path.display(),
+                        backup.uid
+                    );
+                    if backup.chip != flashing.chip.name {
+                        tracing::warn!(
+                            "Backup of {}, restoring to {}",
+                            backup.chip,
+                            flashing.chip.name
+                        );
+                    }
+                    let raw = hex::decode(&backup.raw)
+                        .with_context(|| format!("invalid raw config in {}", path.display()))?;
+                    flashing.write_config_raw(raw)?;
+                    tracing::info!("Config registers restored from {}", path.display());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Code flash images
+type Images = Vec<Segment>;
+
+/// Everything read from the firmware files of a command
+struct FirmwareImages {
+    /// Code flash images, sorted by address
+    code: Images,
+    /// The `.eeprom` ELF section
+    eeprom: Option<Segment>,
+    /// Parts of the images inside the EEPROM window, relative to it
+    data: Images,
+    /// Entry point of the first file recording one
+    entry_point: Option<u32>,
+    /// File SHA-256 if `--sha256` checked it
+    sha256: Option<String>,
+}
+
+/// Read firmware files for the code flash, taking parts inside the EEPROM `window` out by their
+/// absolute addresses, before each file is placed at its address
+fn read_images(
+    paths: &[String],
+    default_address: u32,
+    firmware: &FirmwareArgs,
+    window: Option<&Range<u32>>,
+) -> Result<FirmwareImages> {
+    let opts = firmware.read_options();
+    anyhow::ensure!(
+        firmware.sha256.is_none() || paths.len() == 1,
+        "--sha256 needs a single firmware file"
+    );
+    let mut images = vec![];
+    let mut data = vec![];
+    let mut eeprom = None;
+    let mut entry_point = None;
+    let mut file_sha256 = None;
+    for arg in paths {
+        // NOTE: only treat the suffix as an address if it parses, `@` is valid in file names
+        let (path, address) = match arg.rsplit_once('@') {
+            Some((path, addr)) if parse_address(addr).is_ok() => (path, parse_address(addr)?),
+            _ => (arg.as_str(), default_address),
+        };
+        let file = FirmwareFile::fetch(path)?;
+        file_sha256 = firmware.check_sha256(&file)?;
+        let mut image = wchisp::format::read_firmware(&file.path, &opts)?;
+        if let Some(window) = window {
+            data.extend(image.take_window(window));
+        }
+        entry_point = entry_point.or(image.entry_point);
+        for (offset, data) in image.sections {
+            tracing::info!("Firmware size: {} @ 0x{:08x}", data.len(), address + offset);
+            images.push(Segment::new(address + offset, data));
+        }
+        if let Some(segment) = image.eeprom {
+            anyhow::ensure!(
+                eeprom.is_none(),
+                "more than one firmware file has an {} section",
+                EEPROM_SECTION
+            );
+            eeprom = Some(segment);
+        }
+    }
+    images.sort_by_key(|segment| segment.address);
+
+    for pair in images.windows(2) {
+        if pair[0].end_address() > pair[1].address as u64 {
+            anyhow::bail!(
+                "Firmware images overlap: 0x{:08x}..0x{:08x} and 0x{:08x}",
+                pair[0].address,
+                pair[0].end_address(),
+                pair[1].address
+            );
+        }
+    }
+    Ok(FirmwareImages {
+        code: images,
+        eeprom,
+        data,
+        entry_point,
+        sha256: file_sha256,
+    })
+}
+
+/// A local firmware file, downloaded to a temporary file for `http(s)://` URLs
+struct FirmwareFile {
+    path: PathBuf,
+    /// File name, without the download URL
+    name: String,
+    temporary: bool,
+}
+
+impl FirmwareFile {
+    fn fetch(path: &str) -> Result<Self> {
+        let local = |path: &str| {
+            let path = PathBuf::from(path);
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            FirmwareFile {
+                path,
+                name,
+                temporary: false,
             }
+        };
+        if let Some(path) = path.strip_prefix("file://") {
+            return Ok(local(path));
+        }
+        if !(path.starts_with("https://") || path.starts_with("http://")) {
+            return Ok(local(path));
+        }
+
+        tracing::info!("Downloading {}", path);
+        // NOTE: keep the file name, the firmware format is guessed by extension
+        let name = path
+            .split(['?', '#'])
+            .next()
+            .and_then(|url| url.rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("firmware");
+        let local = std::env::temp_dir().join(format!("wchisp-{}-{}", std::process::id(), name));
+        let resp = ureq::get(path)
+            .call()
+            .with_context(|| format!("failed to download {}", path))?;
+        let mut body = vec![];
+        resp.into_reader().read_to_end(&mut body)?;
+        std::fs::write(&local, &body)?;
+        tracing::info!("Downloaded {} bytes", body.len());
+        Ok(FirmwareFile {
+            path: local,
+            name: name.to_string(),
+            temporary: true,
+        })
+    }
+
+    fn check_sha256(&self, expected: &str) -> Result<()> {
+        let raw = std::fs::read(&self.path)?;
+        let actual = hex::encode(Sha256::digest(&raw));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            anyhow::bail!(wchisp::Error::BadFirmware(format!(
+                "{}: SHA-256 mismatch, expected {}, got {}",
+                self.path.display(),
+                expected,
+                actual
+            )));
+        }
+        tracing::info!("SHA-256 OK: {}", actual);
+        Ok(())
+    }
+}
+
+/// Look up `name` in a `sha256sum` style manifest, `HEX  NAME` or `HEX *NAME` per line.
+/// A manifest with a single bare digest applies to any file.
+fn manifest_sha256(manifest: &str, name: &str) -> Result<String> {
+    let text =
+        std::fs::read_to_string(manifest).with_context(|| format!("reading {}", manifest))?;
+    let entries: Vec<(&str, Option<&str>)> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once(char::is_whitespace) {
+            Some((hex, file)) => (hex, Some(file.trim_start().trim_start_matches('*'))),
+            None => (line, None),
+        })
+        .collect();
+    let digest = match &entries[..] {
+        [(hex, None)] => *hex,
+        _ => entries
+            .iter()
+            .find(|(_, file)| {
+                file.is_some_and(|file| {
+                    std::path::Path::new(file)
+                        .file_name()
+                        .is_some_and(|file| file.to_string_lossy() == name)
+                })
+            })
+            .map(|(hex, _)| *hex)
+            .ok_or_else(|| anyhow::format_err!("{} not listed in {}", name, manifest))?,
+    };
+    Ok(digest.to_ascii_lowercase())
+}
+
+impl Drop for FirmwareFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn parse_address(s: &str) -> Result<u32> {
+    let addr = if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16)?
+    } else {
+        s.parse()?
+    };
+    Ok(addr)
+}
+
+/// Create a flashing session on `device`, locked against other processes, with optional packet
+/// trace and capture
+fn new_flashing<'a>(
+    cli: &Cli,
+    transport: impl Transport + Send + 'a,
+    link: CaptureLink,
+    lock: DeviceLock,
+) -> Result<BoxedFlashing<'a>> {
+    // NOTE: only USB bootloaders of old CH55x chips speak the v1 protocol
+    let transport: Box<dyn Transport + Send + 'a> = if matches!(link, CaptureLink::Usb) {
+        Box::new(LegacyTransport::new(transport))
+    } else {
+        Box::new(transport)
+    };
+    let mut transport: Box<dyn Transport + Send + 'a> =
+        Box::new(LockedTransport::new(transport, lock));
+    if let Some(ms) = cli.timeout {
+        transport = Box::new(TimeoutTransport::new(transport, Duration::from_millis(ms)));
+    }
+    if let Some(path) = &cli.pcap {
+        let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        transport = Box::new(PcapTransport::new(transport, link, writer)?);
+    }
+    if let Some(path) = &cli.trace {
+        let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        transport = Box::new(TraceTransport::new(transport, writer));
+    }
+    match (cli.chip_id, cli.device_type, cli.flash_size) {
+        (Some(chip_id), Some(device_type), Some(flash_size)) => {
+            let chip = ChipDB::load()?.unknown_chip(chip_id, device_type, flash_size);
+            tracing::warn!(
+                "Using {} from the command line, not the chip database",
+                chip
+            );
+            Flashing::new_from_transport_with_chip(transport, chip)
+        }
+        (_, _, Some(flash_size)) if cli.force_generic => {
+            Flashing::new_from_transport_or_generic(transport, flash_size)
         }
+        _ => Flashing::new_from_transport(transport),
+    }
+}
+
+/// A size in bytes, with an optional `K` suffix for KiB
+fn parse_size(s: &str) -> Result<u32> {
+    match s.strip_suffix(['K', 'k']) {
+        Some(kib) => parse_address(kib)?
+            .checked_mul(1024)
+            .ok_or_else(|| anyhow::format_err!("size too large: {}", s)),
+        None => parse_address(s),
+    }
+}
+
+fn parse_bootloader_version(s: &str) -> Result<BootloaderVersion> {
+    s.parse()
+}
+
+/// A 32-byte key in hex, or a file holding it
+fn parse_key(s: &str) -> Result<[u8; 32]> {
+    let text = if std::path::Path::new(s).is_file() {
+        std::fs::read_to_string(s)?
+    } else {
+        s.to_string()
+    };
+    let raw = hex::decode(text.trim())?;
+    <[u8; 32]>::try_from(raw).map_err(|_| anyhow::format_err!("a key is 32 bytes"))
+}
+
+fn parse_key_seed(s: &str) -> Result<[u8; KEY_SEED_LEN]> {
+    let raw = hex::decode(s.trim())?;
+    <[u8; KEY_SEED_LEN]>::try_from(raw)
+        .map_err(|_| anyhow::format_err!("a key seed is {} bytes", KEY_SEED_LEN))
+}
+
+fn read_key(path: &str) -> Result<[u8; 32]> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    parse_key(text.trim()).with_context(|| format!("parsing {}", path))
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    Ok(u8::try_from(parse_address(s)?)?)
+}
+
+/// `START..END`, end exclusive
+fn parse_sector_range(s: &str) -> Result<Range<u32>> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow::format_err!("expected START..END"))?;
+    Ok(parse_address(start)?..parse_address(end)?)
+}
+
+/// `NAME=VALUE`, the value is decimal or `0x` hex
+fn parse_symbol_int(s: &str) -> Result<(String, SymbolValue)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::format_err!("expected NAME=VALUE"))?;
+    let value = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16)?,
+        None => value.parse()?,
+    };
+    Ok((name.to_string(), SymbolValue::Int(value)))
+}
+
+/// `NAME=BYTES`, hex bytes optionally separated by `:`, `-` or spaces
+fn parse_symbol_bytes(s: &str) -> Result<(String, SymbolValue)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::format_err!("expected NAME=BYTES"))?;
+    let digits: String = value
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | ' '))
+        .collect();
+    Ok((name.to_string(), SymbolValue::Bytes(hex::decode(digits)?)))
+}
+
+/// `HUB:PORT`, e.g. `1-1.4:2`
+fn parse_hub_port(s: &str) -> Result<(String, u8)> {
+    let (hub, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::format_err!("expected HUB:PORT, e.g. 1-1.4:2"))?;
+    Ok((hub.to_string(), port.parse()?))
+}
+
+fn parse_parity(s: &str) -> Result<Parity> {
+    match s.to_ascii_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "odd" | "o" => Ok(Parity::Odd),
+        "even" | "e" => Ok(Parity::Even),
+        _ => anyhow::bail!("expected none, odd or even"),
     }
+}
+
+fn parse_stop_bits(s: &str) -> Result<StopBits> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => anyhow::bail!("expected 1 or 2"),
+    }
+}
+
+fn parse_flow_control(s: &str) -> Result<FlowControl> {
+    match s.to_ascii_lowercase().as_str() {
+        "none" => Ok(FlowControl::None),
+        "rts-cts" | "hardware" => Ok(FlowControl::Hardware),
+        "xon-xoff" | "software" => Ok(FlowControl::Software),
+        _ => anyhow::bail!("expected none, rts-cts or xon-xoff"),
+    }
+}
+
+/// Cancelled by Ctrl+C, a second Ctrl+C exits immediately
+fn cancel_token() -> CancelToken {
+    static TOKEN: std::sync::OnceLock<CancelToken> = std::sync::OnceLock::new();
+    TOKEN
+        .get_or_init(|| {
+            let token = CancelToken::new();
+            let handler_token = token.clone();
+            let installed = ctrlc::set_handler(move || {
+                if handler_token.is_cancelled() {
+                    std::process::exit(wchisp::Error::Cancelled.code().into());
+                }
+                eprintln!("Interrupted, finishing the current chunk...");
+                handler_token.cancel();
+            });
+            if let Err(e) = installed {
+                tracing::warn!("Failed to install Ctrl+C handler: {}", e);
+            }
+            token
+        })
+        .clone()
+}
+
+/// Set once the pre-connect hook ran, the post hook only runs for commands that connected
+static HOOKS_ARMED: AtomicBool = AtomicBool::new(false);
 
+/// Run a `--pre-cmd`/`--post-cmd` shell command, failing on non-zero exit
+fn run_hook(kind: &str, cmd: &str, status: &str) -> Result<()> {
+    tracing::info!("Running {} hook: {}", kind, cmd);
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let exit = shell
+        .arg(cmd)
+        .env("WCHISP_HOOK", kind)
+        .env("WCHISP_STATUS", status)
+        .status()
+        .with_context(|| format!("failed to run {} hook", kind))?;
+    if !exit.success() {
+        anyhow::bail!("{} hook `{}` failed: {}", kind, cmd, exit);
+    }
     Ok(())
 }
 
-fn extend_firmware_to_sector_boundary(buf: &mut Vec<u8>) {
-    if buf.len() % 1024 != 0 {
-        let remain = 1024 - (buf.len() % 1024);
-        buf.extend_from_slice(&vec![0; remain]);
+/// Run `open` up to `retries` more times on failure, backing off from 250ms to 2s
+fn with_open_retries<T>(retries: u32, mut open: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match open() {
+            Err(e) if attempt < retries => {
+                let backoff = Duration::from_millis(250 << attempt.min(3));
+                attempt += 1;
+                tracing::warn!(
+                    "Open failed: {:#}, retrying in {:?} ({}/{})",
+                    e,
+                    backoff,
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(backoff);
+            }
+            result => return result,
+        }
     }
 }
 
-fn get_flashing(cli: &Cli) -> Result<Flashing<'_>> {
-    if cli.usb {
-        Flashing::new_from_usb(cli.device)
+impl Cli {
+    /// Fill transport options not given on the command line from `WCHISP_*` environment variables
+    fn apply_env(&mut self, matches: &clap::ArgMatches) -> Result<()> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let explicit = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !explicit("usb") && !explicit("serial") {
+            if let Some(transport) = env("WCHISP_TRANSPORT") {
+                match transport.to_ascii_lowercase().as_str() {
+                    "usb" => (self.usb, self.serial) = (true, false),
+                    "serial" => (self.usb, self.serial) = (false, true),
+                    _ => anyhow::bail!(
+                        "invalid WCHISP_TRANSPORT: {}, expected usb or serial",
+                        transport
+                    ),
+                }
+            }
+        }
+        if self.serial {
+            if self.port.is_none() {
+                self.port = env("WCHISP_PORT");
+            }
+            if self.baudrate.is_none() {
+                if let Some(baudrate) = env("WCHISP_BAUDRATE") {
+                    self.baudrate = Some(Baudrate::from_str(&baudrate, true).map_err(|_| {
+                        anyhow::format_err!("invalid WCHISP_BAUDRATE: {}", baudrate)
+                    })?);
+                }
+            }
+        } else if self.device.is_none() {
+            self.device = env("WCHISP_DEVICE");
+        }
+        Ok(())
+    }
+}
+
+fn get_flashing(cli: &Cli) -> Result<BoxedFlashing<'_>> {
+    if !HOOKS_ARMED.swap(true, Ordering::SeqCst) {
+        if let Some(cmd) = &cli.pre_cmd {
+            run_hook("pre", cmd, "")?;
+        }
+        if let Some((hub, port)) = &cli.power_cycle {
+            UsbTransport::power_cycle_hub_port(hub, *port, POWER_CYCLE_OFF)?;
+            std::thread::sleep(POWER_CYCLE_SETTLE);
+        }
+    }
+    let index = match cli.device.as_deref() {
+        Some(device) => match device.parse::<usize>() {
+            Ok(index) => Some(index),
+            Err(_) => {
+                let settings = Settings::load(cli.config.as_deref())?;
+                let board = settings.board(device)?;
+                if let Some(boot) = &board.boot {
+                    boot.enter_bootloader()?;
+                }
+                let flashing = with_open_retries(cli.retry, || open_board(cli, board))?;
+                tracing::info!("Board {}: {}", device, flashing.chip);
+                return Ok(setup_flashing(cli, flashing));
+            }
+        },
+        None => None,
+    };
+    // NOTE: locks are taken before opening, opening claims the device and serial sends Identify
+    let flashing = if cli.usb {
+        let (lock, transport) = with_open_retries(cli.retry, || {
+            let nth = index.unwrap_or(0);
+            let device = UsbTransport::find_nth(nth)?;
+            let lock = lock_usb(cli, &device)?;
+            tracing::info!("Opening USB device #{}", nth);
+            Ok((lock, UsbTransport::open(&device)?))
+        })?;
+        tracing::info!("USB device: {}", transport.device_info());
+        new_flashing(cli, transport, CaptureLink::Usb, lock)?
     } else if cli.serial {
-        Flashing::new_from_serial(cli.port.as_deref(), cli.baudrate)
+        if let Some(addr) = cli.port.as_deref().and_then(|p| p.strip_prefix("tcp://")) {
+            let lock = DeviceLock::acquire(&format!("tcp-{}", addr), cli.wait_lock)?;
+            let transport = with_open_retries(cli.retry, || TcpTransport::connect(addr))?;
+            new_flashing(cli, transport, CaptureLink::Serial, lock)?
+        } else {
+            let (lock, transport) = with_open_retries(cli.retry, || {
+                let port = match &cli.port {
+                    Some(port) => port.clone(),
+                    None => SerialTransport::default_port()?,
+                };
+                let lock = lock_serial(cli, &port)?;
+                Ok((lock, open_serial(cli, &port)?))
+            })?;
+            new_flashing(cli, transport, CaptureLink::Serial, lock)?
+        }
     } else {
         unreachable!("No transport specified");
+    };
+    Ok(setup_flashing(cli, flashing))
+}
+
+/// Apply the global options to a newly opened flashing session
+fn setup_flashing<'a>(cli: &Cli, mut flashing: BoxedFlashing<'a>) -> BoxedFlashing<'a> {
+    flashing.set_cancel_token(cancel_token());
+    flashing.set_dry_run(cli.dry_run);
+    let mut timing = flashing.chip.timing();
+    timing.erase_settle = cli.erase_settle.unwrap_or(timing.erase_settle);
+    timing.program_settle = cli.program_settle.unwrap_or(timing.program_settle);
+    flashing.chip.set_timing(timing);
+    flashing.set_config_backup(Box::new(|chip, uid, raw| {
+        // NOTE: a missing backup must not stand in the way of e.g. unprotecting a chip
+        match backup::save(chip, uid, raw) {
+            Ok(path) => tracing::info!("Config registers backed up to {}", path.display()),
+            Err(e) => tracing::warn!("Config registers not backed up: {:#}", e),
+        }
+        Ok(())
+    }));
+    if cli.random_key {
+        flashing.use_random_key_seed();
+    }
+    if let Some(seed) = cli.key_seed {
+        flashing.set_key_seed(seed);
+    }
+    if cli.no_progress {
+        flashing.set_progress(Box::new(NoProgress));
+    } else {
+        match cli.progress {
+            Some(ProgressFormat::Bar) => flashing.set_progress(Box::<BarProgress>::default()),
+            Some(ProgressFormat::Line) => flashing.set_progress(Box::<LineProgress>::default()),
+            Some(ProgressFormat::Json) => flashing.set_progress(Box::<JsonProgress>::default()),
+            // NOTE: the library default already picks bar or line by terminal
+            None => {}
+        }
     }
+    flashing
+}
+
+fn open_serial(cli: &Cli, port: &str) -> Result<SerialTransport> {
+    let framing = SerialFraming {
+        parity: cli.parity,
+        stop_bits: cli.stop_bits,
+        flow_control: cli.flow_control,
+    };
+    SerialTransport::open_with_framing(port, cli.baudrate.unwrap_or_default(), framing)
+}
+
+/// Find and open the board named in `wchisp.toml`
+fn open_board<'a>(cli: &'a Cli, board: &Board) -> Result<BoxedFlashing<'a>> {
+    let not_found = || wchisp::Error::NoDevice(format!("board {:?} not found", board));
+    match board.selector()? {
+        Selector::Uid(uid) => {
+            for device in UsbTransport::list_devices()? {
+                let lock = lock_usb(cli, &device)?;
+                let flashing =
+                    new_flashing(cli, UsbTransport::open(&device)?, CaptureLink::Usb, lock)?;
+                if flashing.chip_uid() == uid {
+                    return Ok(flashing);
+                }
+            }
+            Err(not_found().into())
+        }
+        Selector::Usb { bus, address } => {
+            let device = UsbTransport::list_devices()?
+                .into_iter()
+                .find(|device| device.bus_number == bus && device.address == address)
+                .ok_or_else(not_found)?;
+            let lock = lock_usb(cli, &device)?;
+            new_flashing(cli, UsbTransport::open(&device)?, CaptureLink::Usb, lock)
+        }
+        Selector::Port(port) => {
+            let lock = lock_serial(cli, port)?;
+            new_flashing(cli, open_serial(cli, port)?, CaptureLink::Serial, lock)
+        }
+        Selector::SerialNumber(serial) => {
+            let port = SerialTransport::list_ports()?
+                .into_iter()
+                .find(|port| port.serial_number.as_deref() == Some(serial))
+                .ok_or_else(not_found)?;
+            let lock = lock_serial(cli, &port.name)?;
+            new_flashing(
+                cli,
+                open_serial(cli, &port.name)?,
+                CaptureLink::Serial,
+                lock,
+            )
+        }
+    }
+}
+
+/// Lock name of a USB device, by location
+fn usb_device_name(bus: u8, address: u8) -> String {
+    format!("usb-{:03}-{:03}", bus, address)
+}
+
+/// Lock a USB device before opening it
+fn lock_usb(cli: &Cli, device: &UsbDeviceSummary) -> Result<DeviceLock> {
+    DeviceLock::acquire(
+        &usb_device_name(device.bus_number, device.address),
+        cli.wait_lock,
+    )
+}
+
+/// Lock a serial port before opening it
+fn lock_serial(cli: &Cli, port: &str) -> Result<DeviceLock> {
+    DeviceLock::acquire(&format!("serial-{}", port), cli.wait_lock)
 }
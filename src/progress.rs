@@ -0,0 +1,181 @@
+//! Progress reporting of long running operations
+//!
+//! `Flashing` reports to a [`ProgressSink`], so that the CLI can render a
+//! progress bar while IDEs and GUI wrappers receive structured events.
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Receiver of progress events
+pub trait ProgressSink: Send {
+    /// A new phase starts, e.g. `"flash"`, with total bytes
+    fn begin(&mut self, phase: &'static str, total: u64);
+    /// `delta` bytes done
+    fn inc(&mut self, delta: u64);
+    /// Current phase finished
+    fn finish(&mut self);
+}
+
+/// Progress bar with the `cli` feature, silent otherwise.
+///
+/// Falls back to [`LineProgress`] when stderr is not a terminal, where the bar would draw nothing.
+pub(crate) fn default_sink() -> Box<dyn ProgressSink> {
+    #[cfg(feature = "cli")]
+    return if std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        Box::<BarProgress>::default()
+    } else {
+        Box::<LineProgress>::default()
+    };
+    #[cfg(not(feature = "cli"))]
+    return Box::<NoProgress>::default();
+}
+
+/// Interactive terminal progress bar
+#[cfg(feature = "cli")]
+#[derive(Default)]
+pub struct BarProgress {
+    bar: Option<indicatif::ProgressBar>,
+}
+
+#[cfg(feature = "cli")]
+impl ProgressSink for BarProgress {
+    fn begin(&mut self, _phase: &'static str, total: u64) {
+        self.bar = Some(indicatif::ProgressBar::new(total));
+    }
+
+    fn inc(&mut self, delta: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc(delta);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish();
+        }
+    }
+}
+
+/// Periodic JSON lines on stderr, for IDE integrations.
+///
+/// Each line looks like `{"phase":"flash","done":1024,"total":4096,"percent":25.0}`.
+pub struct JsonProgress {
+    phase: &'static str,
+    done: u64,
+    total: u64,
+    last_report: Instant,
+    interval: Duration,
+}
+
+impl Default for JsonProgress {
+    fn default() -> Self {
+        JsonProgress {
+            phase: "",
+            done: 0,
+            total: 0,
+            last_report: Instant::now(),
+            interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl JsonProgress {
+    fn report(&mut self) {
+        let percent = if self.total == 0 {
+            100.0
+        } else {
+            self.done as f64 * 100.0 / self.total as f64
+        };
+        let event = serde_json::json!({
+            "phase": self.phase,
+            "done": self.done,
+            "total": self.total,
+            "percent": (percent * 10.0).round() / 10.0,
+        });
+        let _ = writeln!(std::io::stderr(), "{}", event);
+        self.last_report = Instant::now();
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn begin(&mut self, phase: &'static str, total: u64) {
+        self.phase = phase;
+        self.done = 0;
+        self.total = total;
+        self.report();
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.done += delta;
+        if self.last_report.elapsed() >= self.interval {
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.report();
+    }
+}
+
+/// A log line every 10%, for CI logs and other non-terminal outputs.
+///
+/// Each line looks like `flash: 40% (1638/4096 bytes)`.
+#[derive(Default)]
+pub struct LineProgress {
+    phase: &'static str,
+    done: u64,
+    total: u64,
+    /// Last reported step, in 10%
+    step: u64,
+}
+
+impl LineProgress {
+    fn report(&self) {
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        tracing::info!(
+            "{}: {}% ({}/{} bytes)",
+            self.phase,
+            percent,
+            self.done,
+            self.total
+        );
+    }
+}
+
+impl ProgressSink for LineProgress {
+    fn begin(&mut self, phase: &'static str, total: u64) {
+        self.phase = phase;
+        self.done = 0;
+        self.total = total;
+        self.step = 0;
+        self.report();
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.done += delta;
+        let Some(step) = (self.done * 10).checked_div(self.total) else {
+            return;
+        };
+        // NOTE: 100% is reported by `finish`
+        if step > self.step && step < 10 {
+            self.step = step;
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.done = self.done.max(self.total);
+        self.report();
+    }
+}
+
+/// Discard all progress events
+#[derive(Default)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn begin(&mut self, _phase: &'static str, _total: u64) {}
+    fn inc(&mut self, _delta: u64) {}
+    fn finish(&mut self) {}
+}
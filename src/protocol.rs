@@ -5,7 +5,68 @@ use std::fmt;
 use anyhow::Result;
 use scroll::{Pread, Pwrite};
 
-use crate::constants::commands;
+use crate::{constants::commands, Error};
+
+/// Status byte of a successful command, the first payload byte of responses without reply data
+pub const STATUS_OK: u8 = 0x00;
+
+/// ISP protocol generation of a bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Old CH55x bootloaders, BTVER 1.x, see [`crate::transport::LegacyTransport`]
+    V1,
+    /// All current bootloaders
+    V2,
+}
+
+/// Known failure status bytes, by command, `None` for any command.
+///
+/// Bootloaders report most failures as a bare 0xfe, so the meaning comes from the command.
+const STATUS_TABLE: &[(Option<u8>, u8, &str)] = &[
+    (
+        Some(commands::PROGRAM),
+        0xfe,
+        "address out of code flash range, or not erased",
+    ),
+    (Some(commands::ERASE), 0xfe, "code flash is protected"),
+    (
+        Some(commands::DATA_PROGRAM),
+        0xfe,
+        "address out of EEPROM range",
+    ),
+    (
+        Some(commands::DATA_ERASE),
+        0xfe,
+        "address out of EEPROM range",
+    ),
+    (
+        Some(commands::WRITE_CONFIG),
+        0xfe,
+        "config write refused, the register mask is not supported",
+    ),
+    (Some(commands::SET_BAUD), 0xfe, "baudrate not supported"),
+    (None, 0xfe, "command failed or is not supported"),
+];
+
+/// Meaning of a failure `status` in response to `cmd`
+pub fn status_description(cmd: u8, status: u8) -> &'static str {
+    STATUS_TABLE
+        .iter()
+        .find(|(c, s, _)| c.is_none_or(|c| c == cmd) && *s == status)
+        .map(|(_, _, description)| *description)
+        .unwrap_or("unknown status")
+}
+
+/// How an ISP session ends, the `reason` of [`Command::IspEnd`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum EndReason {
+    /// Finish the session only, the bootloader stays alive for the next invocation
+    Finish = 0,
+    /// Reboot to the application, also applies newly written config registers
+    #[default]
+    Reboot = 1,
+}
 
 /// WCH ISP Command
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -15,7 +76,7 @@ pub enum Command {
     ///
     /// DeviceType = ChipSeries = SerialNumber = McuType + 0x10
     Identify { device_id: u8, device_type: u8 },
-    /// End ISP session, reboot the device.
+    /// End ISP session, see [`EndReason`].
     ///
     /// Connection will lost after response packet
     IspEnd {
@@ -28,7 +89,8 @@ pub enum Command {
     ///
     /// - sum Device UID to a byte, s
     /// - initialize XOR key as [s; 8]
-    /// - select 7 bytes(via some rules) from generated random key
+    /// - select 7 bytes from generated random key, at positions
+    ///   `len/7*4, len/5, len/7, len/7*6, len/7*3, len/5*3, len/7*5`
     /// - `key[0] ~ key[6] ^= corresponding selected byte`
     /// - `key[7] = key[0] + chip_id`
     ///
@@ -59,7 +121,7 @@ pub enum Command {
     /// Write Config Bits. Can be used to unprotect the device.
     WriteConfig { bit_mask: u8, data: Vec<u8> },
     /// Erase the Data Flash, almost the same as `Erase`
-    DataErase { sectors: u32 },
+    DataErase { address: u32, sectors: u32 },
     /// Program the Data Flash, almost the same as `Program`
     DataProgram {
         address: u32,
@@ -134,7 +196,15 @@ impl Command {
     }
 
     pub fn data_erase(sectors: u32) -> Self {
-        Command::DataErase { sectors }
+        Command::DataErase {
+            address: 0,
+            sectors,
+        }
+    }
+
+    /// Erase `sectors` 1KiB sectors of data flash, starting at `address`
+    pub fn data_erase_at(address: u32, sectors: u32) -> Self {
+        Command::DataErase { address, sectors }
     }
 
     pub fn set_baud(baudrate: u32) -> Self {
@@ -244,9 +314,9 @@ impl Command {
             }
             // a9
             // 05 00
-            // 00 00 00 00    ???
+            // 00 00 00 00    start address, always 0 in captures, assumed LE like `Program`
             // 20             sectors of data flash
-            Command::DataErase { sectors } => {
+            Command::DataErase { address, sectors } => {
                 let mut buf = [
                     commands::DATA_ERASE,
                     0x05,
@@ -257,6 +327,7 @@ impl Command {
                     0x00,
                     0x00,
                 ];
+                buf.pwrite_with(address, 3, scroll::LE)?;
                 // FIXME: is this correct?
                 buf[7] = sectors as u8;
                 Ok(buf.to_vec())
@@ -278,6 +349,51 @@ impl Command {
             _ => unimplemented!(),
         }
     }
+
+    /// Decode a raw request, the inverse of [`Command::into_raw`].
+    ///
+    /// ```
+    /// use wchisp::protocol::Command;
+    ///
+    /// let cmd = Command::program(0x0800, 0x5a, vec![0xde, 0xad, 0xbe, 0xef]);
+    /// let raw = cmd.clone().into_raw().unwrap();
+    /// assert_eq!(Command::from_raw(&raw).unwrap(), cmd);
+    /// ```
+    pub fn from_raw(raw: &[u8]) -> Result<Self> {
+        let cmd = *raw
+            .first()
+            .ok_or_else(|| anyhow::format_err!("empty request"))?;
+        let len = raw.pread_with::<u16>(1, scroll::LE)? as usize;
+        let payload = &raw[3..];
+        anyhow::ensure!(
+            payload.len() == len,
+            "request length mismatch: header says {}, got {}",
+            len,
+            payload.len()
+        );
+        let u8_at = |offset: usize| -> Result<u8> { Ok(payload.pread::<u8>(offset)?) };
+        let u32_at = |offset: usize| -> Result<u32> { Ok(payload.pread_with(offset, scroll::LE)?) };
+        let data_from = |offset: usize| payload.get(offset..).unwrap_or_default().to_vec();
+
+        let command = match cmd {
+            commands::IDENTIFY => Command::identify(u8_at(0)?, u8_at(1)?),
+            commands::ISP_END => Command::isp_end(u8_at(0)?),
+            commands::ISP_KEY => Command::isp_key(payload.to_vec()),
+            commands::ERASE => Command::erase(u32_at(0)?),
+            commands::PROGRAM => Command::program(u32_at(0)?, u8_at(4)?, data_from(5)),
+            commands::VERIFY => Command::verify(u32_at(0)?, u8_at(4)?, data_from(5)),
+            commands::READ_CONFIG => Command::read_config(u8_at(0)?),
+            commands::WRITE_CONFIG => Command::write_config(u8_at(0)?, data_from(2)),
+            commands::DATA_ERASE => Command::data_erase_at(u32_at(0)?, u8_at(4)? as u32),
+            commands::DATA_PROGRAM => Command::data_program(u32_at(0)?, u8_at(4)?, data_from(5)),
+            commands::DATA_READ => {
+                Command::data_read(u32_at(0)?, payload.pread_with(4, scroll::LE)?)
+            }
+            commands::SET_BAUD => Command::set_baud(u32_at(0)?),
+            _ => anyhow::bail!("unknown command 0x{:02x}", cmd),
+        };
+        Ok(command)
+    }
 }
 
 /// Response to a Command. The request cmd type is ommitted from the type definition.
@@ -300,31 +416,146 @@ impl fmt::Debug for Response {
 
 impl Response {
     pub fn is_ok(&self) -> bool {
+        matches!(self, Response::Ok(_))
+    }
+
+    pub fn payload(&self) -> &[u8] {
         match self {
-            Response::Ok(_) => true,
-            _ => false,
+            Response::Ok(payload) => payload,
+            Response::Err(_, payload) => payload,
         }
     }
 
-    pub fn payload(&self) -> &[u8] {
+    /// Status byte, meaningful for commands without reply data, e.g. `Program` and `Erase`
+    pub fn status(&self) -> Option<u8> {
+        self.payload().first().copied()
+    }
+
+    /// Fail with [`Error::Status`] unless the status in response to `cmd` is [`STATUS_OK`]
+    pub fn check_status(&self, cmd: u8) -> Result<()> {
+        match self.status() {
+            Some(STATUS_OK) => Ok(()),
+            Some(status) => Err(Error::Status {
+                command: commands::name(cmd),
+                status,
+                description: status_description(cmd, status),
+            }
+            .into()),
+            None => anyhow::bail!("empty response to {}", commands::name(cmd)),
+        }
+    }
+
+    /// The payload, without copying it
+    pub fn into_payload(self) -> Vec<u8> {
         match self {
             Response::Ok(payload) => payload,
             Response::Err(_, payload) => payload,
         }
     }
 
-    pub(crate) fn from_raw(raw: &[u8]) -> Result<Self> {
+    /// Parse a raw response, reusing its buffer for the payload
+    pub(crate) fn from_raw(mut raw: Vec<u8>) -> Result<Self> {
         // FIXME: should raw[1] == 0x00 || raw[1] == 0x82?
         if true {
             let len = raw.pread_with::<u16>(2, scroll::LE)? as usize;
-            let remain = &raw[4..];
-            if remain.len() == len {
-                Ok(Response::Ok(remain.to_vec()))
+            if raw.len() - 4 == len {
+                // NOTE: shifting the few payload bytes in place, no allocation per chunk
+                raw.drain(..4);
+                Ok(Response::Ok(raw))
             } else {
                 Err(anyhow::anyhow!("Invalid response"))
             }
         } else {
-            Ok(Response::Err(raw[1], raw[2..].to_vec()))
+            let code = raw[1];
+            raw.drain(..2);
+            Ok(Response::Err(code, raw))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One command of each encodable variant, with `n` data bytes where there are any
+    fn commands_with_data(n: usize) -> Vec<Command> {
+        let data: Vec<u8> = (0..n).map(|i| (i * 7 + n) as u8).collect();
+        let address = (n as u32) << 10 | 0x38;
+        vec![
+            Command::identify(0x70, 0x17),
+            Command::isp_end(EndReason::Reboot as u8),
+            Command::isp_end(EndReason::Finish as u8),
+            Command::isp_key(data.clone()),
+            Command::erase(n as u32),
+            Command::program(address, n as u8, data.clone()),
+            Command::verify(address, !n as u8, data.clone()),
+            Command::read_config(crate::constants::CFG_MASK_ALL),
+            Command::write_config(crate::constants::CFG_MASK_RDPR_USER_DATA_WPR, data.clone()),
+            Command::data_erase(n as u32 & 0xff),
+            Command::data_erase_at(address, 1),
+            Command::data_program(address, 0x1c, data),
+            Command::data_read(address, n as u16),
+            Command::set_baud(115200 * n as u32),
+        ]
+    }
+
+    #[test]
+    fn round_trip_all_variants() {
+        for n in [0, 1, 7, 8, 30, 56] {
+            for cmd in commands_with_data(n) {
+                let raw = cmd.clone().into_raw().unwrap();
+                let len = raw.pread_with::<u16>(1, scroll::LE).unwrap() as usize;
+                assert_eq!(len, raw.len() - 3, "length header of {:?}", cmd);
+                assert_eq!(Command::from_raw(&raw).unwrap(), cmd);
+            }
+        }
+    }
+
+    #[test]
+    fn golden_frames() {
+        let frames: &[(&str, Command)] = &[
+            // the capture notes of `Command::into_raw`
+            ("a4040008000000", Command::erase(8)),
+            ("a905000000000020", Command::data_erase(0x20)),
+            (
+                "aa3d00380000001c",
+                Command::data_program(0x38, 0x1c, vec![0; 0x38]),
+            ),
+            // NOTE: self-derived from the encoder, no captures of these yet, they only pin its
+            // current output against regressions
+            (
+                "a112007017\
+                 4d4355204953502026205743482e434e",
+                Command::identify(0x70, 0x17),
+            ),
+            ("a2010001", Command::isp_end(1)),
+            ("a702001f00", Command::read_config(0x1f)),
+            ("ab060000010000c800", Command::data_read(0x100, 0xc8)),
+            ("c5040000c20100", Command::set_baud(115200)),
+        ];
+        for (hex_frame, cmd) in frames {
+            let mut expected = hex::decode(hex_frame).unwrap();
+            if let Command::DataProgram { data, .. } = cmd {
+                expected.extend_from_slice(data);
+            }
+            assert_eq!(cmd.clone().into_raw().unwrap(), expected, "{:?}", cmd);
+            assert_eq!(&Command::from_raw(&expected).unwrap(), cmd);
+        }
+    }
+
+    #[test]
+    fn from_raw_rejects_length_mismatch() {
+        let mut raw = Command::program(0, 0, vec![1, 2, 3]).into_raw().unwrap();
+        raw.pop();
+        let err = Command::from_raw(&raw).unwrap_err();
+        assert!(err.to_string().contains("length mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn from_raw_rejects_unknown_command() {
+        let err = Command::from_raw(&[0xb0, 0x00, 0x00]).unwrap_err();
+        assert!(err.to_string().contains("unknown command 0xb0"), "{}", err);
+        assert!(Command::from_raw(&[]).is_err());
+        assert!(Command::from_raw(&[commands::ERASE, 0x04]).is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! Python bindings, enabled by the `python` feature.
+//!
+//! Build a wheel with `maturin build --release`, see `pyproject.toml`.
+//!
+//! Calls talking to the chip for long, like `flash`, release the GIL meanwhile.
+use pyo3::{exceptions::PyException, prelude::*, types::PyBytes};
+
+use crate::{
+    flashing::{BoxedFlashing, EndReason},
+    format::{extend_firmware_to_sector_boundary, read_firmware_segments, ReadOptions, Segment},
+    progress::NoProgress,
+    transport::UsbTransport,
+    Baudrate,
+};
+
+pyo3::create_exception!(wchisp, WchispError, PyException, "Any wchisp failure");
+
+fn py_err(err: anyhow::Error) -> PyErr {
+    WchispError::new_err(format!("{:#}", err))
+}
+
+/// Chip definition
+#[pyclass(name = "Chip", frozen)]
+#[derive(Clone)]
+struct PyChip {
+    chip: crate::Chip,
+}
+
+#[pymethods]
+impl PyChip {
+    #[getter]
+    fn name(&self) -> &str {
+        &self.chip.name
+    }
+
+    /// Full part number, e.g. `"CH32V103R8T6"`, `None` if the chip ID doesn't tell it
+    #[getter]
+    fn part_number(&self) -> Option<&str> {
+        self.chip.part_number()
+    }
+
+    #[getter]
+    fn chip_id(&self) -> u8 {
+        self.chip.chip_id
+    }
+
+    #[getter]
+    fn device_type(&self) -> u8 {
+        self.chip.device_type()
+    }
+
+    #[getter]
+    fn flash_size(&self) -> u32 {
+        self.chip.flash_size
+    }
+
+    #[getter]
+    fn eeprom_size(&self) -> u32 {
+        self.chip.eeprom_size
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Chip {}>", self.chip)
+    }
+}
+
+/// A connected chip in ISP mode
+#[pyclass(name = "Flashing", unsendable)]
+struct PyFlashing {
+    flashing: BoxedFlashing<'static>,
+}
+
+#[pymethods]
+impl PyFlashing {
+    /// Open the USB device at `index`, or the first one
+    #[staticmethod]
+    #[pyo3(signature = (index = None))]
+    fn usb(index: Option<usize>) -> PyResult<Self> {
+        let flashing = crate::Flashing::new_from_usb(index).map_err(py_err)?;
+        Ok(Self::new(flashing.boxed()))
+    }
+
+    /// Open the serial port `port`, or the first one
+    #[staticmethod]
+    #[pyo3(signature = (port = None, baudrate = 115200))]
+    fn serial(port: Option<&str>, baudrate: u32) -> PyResult<Self> {
+        let baudrate = Baudrate::try_from(baudrate).map_err(py_err)?;
+        let flashing = crate::Flashing::new_from_serial(port, Some(baudrate)).map_err(py_err)?;
+        Ok(Self::new(flashing.boxed()))
+    }
+
+    #[getter]
+    fn chip(&self) -> PyChip {
+        PyChip {
+            chip: self.flashing.chip.clone(),
+        }
+    }
+
+    /// Chip unique identifier
+    #[getter]
+    fn uid<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.flashing.chip_uid())
+    }
+
+    /// Bootloader version, e.g. `"02.90"`
+    #[getter]
+    fn bootloader_version(&self) -> String {
+        self.flashing.info().bootloader_version.to_string()
+    }
+
+    /// `None` if the chip doesn't support code flash protection
+    #[getter]
+    fn code_flash_protected(&self) -> Option<bool> {
+        self.flashing.info().code_flash_protected
+    }
+
+    /// Erase, flash, verify and reset, returns the image SHA-256 as hex
+    #[pyo3(signature = (data, address = 0, erase = true, verify = true, reset = true))]
+    fn flash(
+        &mut self,
+        py: Python<'_>,
+        data: &[u8],
+        address: u32,
+        erase: bool,
+        verify: bool,
+        reset: bool,
+    ) -> PyResult<String> {
+        let binary = data.to_vec();
+        let flashing = &mut self.flashing;
+        let digest = py
+            .detach(|| {
+                flashing
+                    .session()
+                    .erase(erase)
+                    .verify(verify)
+                    .reset(reset)
+                    .run(&[Segment::new(address, binary)])
+            })
+            .map_err(py_err)?;
+        Ok(hex::encode(digest.sha256))
+    }
+
+    /// Verify the code flash content at `address`
+    #[pyo3(signature = (data, address = 0))]
+    fn verify(&mut self, py: Python<'_>, data: &[u8], address: u32) -> PyResult<()> {
+        let mut binary = data.to_vec();
+        let sector_size = self.flashing.chip.sector_size() as usize;
+        extend_firmware_to_sector_boundary(&mut binary, sector_size, 0x00);
+        let flashing = &mut self.flashing;
+        py.detach(|| flashing.verify(address, &binary))
+            .map_err(py_err)
+    }
+
+    /// Erase `sectors` 1KiB sectors of code flash, defaults to the whole code flash
+    #[pyo3(signature = (sectors = None))]
+    fn erase(&mut self, py: Python<'_>, sectors: Option<u32>) -> PyResult<()> {
+        let max_sectors = self.flashing.chip.flash_size / 1024;
+        let sectors = sectors.unwrap_or(max_sectors);
+        if sectors > max_sectors {
+            return Err(WchispError::new_err(format!(
+                "sectors must be at most {} for {}",
+                max_sectors, self.flashing.chip.name
+            )));
+        }
+        let flashing = &mut self.flashing;
+        py.detach(|| flashing.erase_code(sectors)).map_err(py_err)
+    }
+
+    /// End the ISP session, rebooting to the application unless `stay`
+    #[pyo3(signature = (stay = false))]
+    fn reset(&mut self, stay: bool) -> PyResult<()> {
+        let reason = if stay {
+            EndReason::Finish
+        } else {
+            EndReason::Reboot
+        };
+        self.flashing.end_session(reason).map_err(py_err)
+    }
+
+    /// Read the whole EEPROM(data flash)
+    fn read_eeprom<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let flashing = &mut self.flashing;
+        let eeprom = py
+            .detach(|| {
+                flashing.reidenfity()?;
+                flashing.dump_eeprom()
+            })
+            .map_err(py_err)?;
+        Ok(PyBytes::new(py, &eeprom))
+    }
+
+    /// Read a config register or field by name, e.g. `"RDPR_USER.IWDG_SW"`
+    fn config_get(&mut self, name: &str) -> PyResult<u32> {
+        let config = self.flashing.read_config().map_err(py_err)?;
+        config.get(name).map_err(py_err)
+    }
+
+    fn unprotect(&mut self) -> PyResult<()> {
+        self.flashing.unprotect(true).map_err(py_err)
+    }
+}
+
+impl PyFlashing {
+    fn new(mut flashing: BoxedFlashing<'static>) -> Self {
+        flashing.set_progress(Box::new(NoProgress));
+        PyFlashing { flashing }
+    }
+}
+
+/// Number of connected WCH ISP USB devices
+#[pyfunction]
+fn probe_usb() -> PyResult<usize> {
+    UsbTransport::scan_devices().map_err(py_err)
+}
+
+/// Read all segments of a firmware file, as a list of `(address, bytes)`
+#[pyfunction]
+fn load_firmware<'py>(py: Python<'py>, path: &str) -> PyResult<Vec<(u32, Bound<'py, PyBytes>)>> {
+    let segments = read_firmware_segments(path, &ReadOptions::default()).map_err(py_err)?;
+    Ok(segments
+        .into_iter()
+        .map(|segment| (segment.address, PyBytes::new(py, &segment.data)))
+        .collect())
+}
+
+#[pymodule]
+fn wchisp(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("WchispError", m.py().get_type::<WchispError>())?;
+    m.add_class::<PyChip>()?;
+    m.add_class::<PyFlashing>()?;
+    m.add_function(wrap_pyfunction!(probe_usb, m)?)?;
+    m.add_function(wrap_pyfunction!(load_firmware, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,225 @@
+//! Remote flashing server, `wchisp serve`
+//!
+//! Line based JSON over TCP, one request per line, e.g.
+//! `{"method":"flash","device":0,"address":0,"data":"<hex>"}`.
+//! Every request gets one response line, `{"ok":true,"result":...}` or
+//! `{"ok":false,"error":"...","code":N}`, where `code` is the same as the process exit code.
+//!
+//! There is no authentication, only listen on trusted networks.
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use wchisp::{
+    format::{extend_firmware_to_sector_boundary, Segment},
+    progress::NoProgress,
+    transport::UsbTransport,
+    Flashing,
+};
+
+use crate::{
+    lock::{DeviceLock, LockedTransport},
+    usb_device_name,
+};
+
+#[derive(Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    /// List connected USB devices, devices in use by another process have an `error` instead of a chip
+    List,
+    /// Get chip info
+    Info { device: usize },
+    /// Erase, flash, verify and reset
+    Flash {
+        device: usize,
+        #[serde(default)]
+        address: u32,
+        /// Hex encoded binary
+        data: String,
+        #[serde(default = "yes")]
+        erase: bool,
+        #[serde(default = "yes")]
+        verify: bool,
+        #[serde(default = "yes")]
+        reset: bool,
+    },
+    /// Verify code flash content
+    Verify {
+        device: usize,
+        #[serde(default)]
+        address: u32,
+        /// Hex encoded binary
+        data: String,
+    },
+    /// Read decoded config registers
+    Config { device: usize },
+    /// Reset the chip
+    Reset { device: usize },
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Serve requests on `listen` until interrupted, one connection at a time.
+pub fn run(listen: &str) -> Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    tracing::info!("Listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        tracing::info!("Connection from {}", peer);
+        if let Err(e) = serve_connection(stream) {
+            tracing::warn!("Connection from {} closed: {}", peer, e);
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match handle(&line) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(err) => {
+                tracing::error!("Request failed: {:#}", err);
+                json!({
+                    "ok": false,
+                    "error": format!("{:#}", err),
+                    "code": wchisp::Error::exit_code(&err),
+                })
+            }
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+fn handle(line: &str) -> Result<Value> {
+    let request: Request = serde_json::from_str(line)?;
+    match request {
+        Request::List => {
+            let mut devices = vec![];
+            for device in UsbTransport::list_devices()? {
+                let name = usb_device_name(device.bus_number, device.address);
+                let _lock = match DeviceLock::acquire(&name, false) {
+                    Ok(lock) => lock,
+                    Err(e) => {
+                        devices.push(json!({
+                            "index": device.index,
+                            "bus_number": device.bus_number,
+                            "address": device.address,
+                            "error": format!("{:#}", e),
+                        }));
+                        continue;
+                    }
+                };
+                let mut transport = UsbTransport::open(&device)?;
+                let chip = Flashing::get_chip(&mut transport)?;
+                let usb = transport.device_info();
+                devices.push(json!({
+                    "index": device.index,
+                    "chip": chip.to_string(),
+                    "vendor_id": usb.vendor_id,
+                    "product_id": usb.product_id,
+                    "bus_number": usb.bus_number,
+                    "address": usb.address,
+                    "product": usb.product,
+                }));
+            }
+            Ok(Value::Array(devices))
+        }
+        Request::Info { device } => {
+            let info = open(device)?.info();
+            Ok(json!({
+                "name": info.name,
+                "part_number": info.part_number,
+                "chip_id": info.chip_id,
+                "device_type": info.device_type,
+                "flash_size": info.flash_size,
+                "eeprom_size": info.eeprom_size,
+                "uid": hex::encode(&info.uid),
+                "bootloader_version": info.bootloader_version.to_string(),
+                "code_flash_protected": info.code_flash_protected,
+            }))
+        }
+        Request::Flash {
+            device,
+            address,
+            data,
+            erase,
+            verify,
+            reset,
+        } => {
+            let binary = hex::decode(data)?;
+            let mut flashing = open(device)?;
+            let digest = flashing
+                .session()
+                .erase(erase)
+                .verify(verify)
+                .reset(reset)
+                .run(&[Segment::new(address, binary)])?;
+            Ok(json!({
+                "crc32": digest.crc32,
+                "sha256": hex::encode(digest.sha256),
+            }))
+        }
+        Request::Verify {
+            device,
+            address,
+            data,
+        } => {
+            let mut binary = hex::decode(data)?;
+            let mut flashing = open(device)?;
+            let sector_size = flashing.chip.sector_size() as usize;
+            extend_firmware_to_sector_boundary(&mut binary, sector_size, 0x00);
+            flashing.verify(address, &binary)?;
+            Ok(Value::Null)
+        }
+        Request::Config { device } => {
+            let config = open(device)?.read_config()?;
+            let registers: Vec<Value> = config
+                .registers
+                .iter()
+                .map(|reg| {
+                    json!({
+                        "name": reg.name,
+                        "value": reg.value,
+                        "explanation": reg.explanation,
+                        "fields": reg.fields.iter().map(|field| json!({
+                            "name": field.name,
+                            "bit_range": [field.bit_range.0, field.bit_range.1],
+                            "value": field.value,
+                            "explanation": field.explanation,
+                        })).collect::<Vec<_>>(),
+                    })
+                })
+                .collect();
+            Ok(json!({ "raw": hex::encode(&config.raw), "registers": registers }))
+        }
+        Request::Reset { device } => {
+            open(device)?.reset()?;
+            Ok(Value::Null)
+        }
+    }
+}
+
+/// Open the `device`th USB device, failing right away if another process is using it
+fn open(device: usize) -> Result<Flashing<LockedTransport<UsbTransport>>> {
+    let summary = UsbTransport::find_nth(device)?;
+    let lock = DeviceLock::acquire(&usb_device_name(summary.bus_number, summary.address), false)?;
+    let transport = UsbTransport::open(&summary)?;
+    let mut flashing = Flashing::new_from_transport(LockedTransport::new(transport, lock))?;
+    flashing.set_progress(Box::new(NoProgress));
+    Ok(flashing)
+}
@@ -0,0 +1,103 @@
+//! Per-project settings, `wchisp.toml`
+//!
+//! ```toml
+//! [boards.sensor-v2]
+//! uid = "cd-ab-12-34-56-78-9a-bc"
+//!
+//! [boards.gateway]
+//! port = "/dev/ttyUSB0"
+//! boot = { gpiochip = "/dev/gpiochip0", boot0 = 17, reset = 27 }
+//! ```
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::boot::BootConfig;
+
+/// Looked up in the current directory when `--config` is not given
+pub const DEFAULT_PATH: &str = "wchisp.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Friendly names for `--device`
+    #[serde(default)]
+    pub boards: BTreeMap<String, Board>,
+}
+
+/// How to find a board, exactly one selector is set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Board {
+    /// Chip UID, as printed by `wchisp info`
+    pub uid: Option<String>,
+    /// USB location of the ISP device, `BUS:ADDRESS`
+    pub usb: Option<String>,
+    /// Serial port name
+    pub port: Option<String>,
+    /// USB serial number of the USB-UART bridge
+    pub serial: Option<String>,
+    /// BOOT0 and reset lines of the fixture, driven to enter the bootloader before connecting
+    pub boot: Option<BootConfig>,
+}
+
+/// A resolved [`Board`] selector
+pub enum Selector<'a> {
+    Uid(Vec<u8>),
+    Usb { bus: u8, address: u8 },
+    Port(&'a str),
+    SerialNumber(&'a str),
+}
+
+impl Settings {
+    /// Load `path`, or `wchisp.toml` if it exists
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_PATH).exists() => DEFAULT_PATH,
+            None => return Ok(Settings::default()),
+        };
+        let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        toml::from_str(&text).with_context(|| format!("parsing {}", path))
+    }
+
+    pub fn board(&self, name: &str) -> Result<&Board> {
+        self.boards.get(name).ok_or_else(|| {
+            anyhow::format_err!(
+                "unknown device: {}, expected an index or a board name in {}",
+                name,
+                DEFAULT_PATH
+            )
+        })
+    }
+}
+
+impl Board {
+    pub fn selector(&self) -> Result<Selector<'_>> {
+        let selectors = [&self.uid, &self.usb, &self.port, &self.serial];
+        anyhow::ensure!(
+            selectors.iter().filter(|s| s.is_some()).count() == 1,
+            "a board needs exactly one of uid, usb, port or serial"
+        );
+        if let Some(uid) = &self.uid {
+            let hex: String = uid.chars().filter(|c| c.is_ascii_hexdigit()).collect();
+            return Ok(Selector::Uid(
+                hex::decode(hex).context("invalid board uid")?,
+            ));
+        }
+        if let Some(usb) = &self.usb {
+            let (bus, address) = usb
+                .split_once(':')
+                .ok_or_else(|| anyhow::format_err!("board usb must be BUS:ADDRESS, got {}", usb))?;
+            return Ok(Selector::Usb {
+                bus: bus.parse()?,
+                address: address.parse()?,
+            });
+        }
+        if let Some(port) = &self.port {
+            return Ok(Selector::Port(port));
+        }
+        Ok(Selector::SerialNumber(self.serial.as_deref().unwrap()))
+    }
+}
@@ -0,0 +1,125 @@
+//! The serial framing, shared by stream transports like serial ports and TCP, and the emulator.
+//!
+//! A request frame is `57 ab`, the raw command, then a byte sum of the raw command. A response
+//! frame is `55 aa`, the raw response, then a byte sum of the raw response.
+//!
+//! ```
+//! # fn main() -> anyhow::Result<()> {
+//! use wchisp::transport::framed;
+//!
+//! let mut wire = vec![];
+//! framed::write_request(&mut wire, &[0xa1, 0x01, 0x00, 0x30])?;
+//! assert_eq!(wire, [0x57, 0xab, 0xa1, 0x01, 0x00, 0x30, 0xd2]);
+//! let request = framed::read_request(&mut &wire[..])?;
+//! assert_eq!(request.as_deref(), Some(&[0xa1, 0x01, 0x00, 0x30][..]));
+//!
+//! let mut wire = vec![];
+//! framed::write_response(&mut wire, &[0xa1, 0x00, 0x02, 0x00, 0x70, 0x17])?;
+//! assert_eq!(framed::read_response(&mut &wire[..])?, [0xa1, 0x00, 0x02, 0x00, 0x70, 0x17]);
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fmt,
+    io::{ErrorKind, Read, Write},
+};
+
+use anyhow::Result;
+use scroll::{Pread, LE};
+
+pub const REQUEST_PREFIX: [u8; 2] = [0x57, 0xab];
+pub const RESPONSE_PREFIX: [u8; 2] = [0x55, 0xaa];
+
+/// A malformed frame. The stream stays usable, the next frame can be read right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    BadPrefix([u8; 2]),
+    BadChecksum { expected: u8, found: u8 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadPrefix(prefix) => write!(f, "invalid frame prefix {:02x?}", prefix),
+            FrameError::BadChecksum { expected, found } => write!(
+                f,
+                "incorrect frame checksum ({:02x} != {:02x})",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Byte sum of a raw request or response
+pub fn checksum(raw: &[u8]) -> u8 {
+    raw.iter().fold(0u8, |acc, &val| acc.wrapping_add(val))
+}
+
+fn write_frame(w: &mut impl Write, prefix: [u8; 2], raw: &[u8]) -> Result<()> {
+    let mut v = Vec::with_capacity(raw.len() + 3);
+    v.extend_from_slice(&prefix);
+    v.extend_from_slice(raw);
+    v.push(checksum(raw));
+
+    w.write_all(&v)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Read the rest of a frame after the prefix, `len_at` is the offset of the u16 payload length
+fn read_frame_body(r: &mut impl Read, head_len: usize, len_at: usize) -> Result<Vec<u8>> {
+    let mut raw = vec![0u8; head_len];
+    r.read_exact(&mut raw)?;
+    let len = raw.pread_with::<u16>(len_at, LE)? as usize;
+    raw.resize(head_len + len, 0);
+    r.read_exact(&mut raw[head_len..])?;
+
+    let mut found = [0u8; 1];
+    r.read_exact(&mut found)?;
+    let expected = checksum(&raw);
+    if found[0] != expected {
+        return Err(FrameError::BadChecksum {
+            expected,
+            found: found[0],
+        }
+        .into());
+    }
+    Ok(raw)
+}
+
+/// Write a raw request, `CMD LEN_LO LEN_HI PAYLOAD...`, as a request frame
+pub fn write_request(w: &mut impl Write, raw: &[u8]) -> Result<()> {
+    write_frame(w, REQUEST_PREFIX, raw)
+}
+
+/// Read a request frame, `None` at the end of stream
+pub fn read_request(r: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut prefix = [0u8; 2];
+    match r.read_exact(&mut prefix) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    if prefix != REQUEST_PREFIX {
+        return Err(FrameError::BadPrefix(prefix).into());
+    }
+    read_frame_body(r, 3, 1).map(Some)
+}
+
+/// Write a raw response, `CMD 00 LEN_LO LEN_HI PAYLOAD...`, as a response frame
+pub fn write_response(w: &mut impl Write, raw: &[u8]) -> Result<()> {
+    write_frame(w, RESPONSE_PREFIX, raw)
+}
+
+/// Read a response frame, returning the raw response
+pub fn read_response(r: &mut impl Read) -> Result<Vec<u8>> {
+    let mut prefix = [0u8; 2];
+    r.read_exact(&mut prefix)?;
+    if prefix != RESPONSE_PREFIX {
+        return Err(FrameError::BadPrefix(prefix).into());
+    }
+    let raw = read_frame_body(r, 4, 2)?;
+    anyhow::ensure!(raw.len() > 4, "Response data length is zero");
+    Ok(raw)
+}
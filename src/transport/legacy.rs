@@ -0,0 +1,222 @@
+//! The v1 ISP protocol of old CH55x bootloaders(BTVER 1.x), as a transport wrapper.
+//!
+//! v1 frames are `[cmd, len, payload...]` with a 1-byte length, 16-bit addresses and no XOR key,
+//! the same as implemented by ch552tool. [`LegacyTransport`] answers v2 requests of
+//! [`crate::Flashing`] by running the matching v1 commands, so sessions work unchanged.
+use std::{collections::VecDeque, time::Duration};
+
+use anyhow::Result;
+
+use super::{Link, Transport};
+use crate::{
+    constants::{commands, commands_v1, CFG_MASK_BTVER, CFG_MASK_RDPR_USER_DATA_WPR, CFG_MASK_UID},
+    flashing::xor_key,
+    protocol::{Command, ProtocolVersion, STATUS_OK},
+};
+
+/// Wait for each v1 response, erase and program are per page or chunk
+const V1_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Identify string of the v1 detect command
+const V1_DETECT: &[u8] = b"USB DBG CH559 & ISP\0";
+/// Device type of all v1 chips, the CH55x family
+const V1_DEVICE_TYPE: u8 = 0x11;
+/// Largest data chunk of a v1 program or verify frame
+const V1_CHUNK: usize = 0x3c;
+/// Status of commands v1 bootloaders do not have
+const STATUS_UNSUPPORTED: u8 = 0xfe;
+
+/// Transport wrapper speaking the v1 protocol when the chip does not answer the v2 `Identify`.
+///
+/// The protocol version is selected at the first `Identify`. Traces of outer wrappers show the
+/// v2 requests, not the v1 frames on the wire.
+pub struct LegacyTransport<T> {
+    inner: T,
+    version: Option<ProtocolVersion>,
+    chip_id: u8,
+    /// XOR key of the v2 requests, removed before sending v1 frames
+    key: [u8; 8],
+    /// v2 responses of requests run as v1 commands
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl<T: Transport> LegacyTransport<T> {
+    pub fn new(inner: T) -> Self {
+        LegacyTransport {
+            inner,
+            version: None,
+            chip_id: 0,
+            key: [0; 8],
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Protocol version of the chip, `None` before the first `Identify`
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.version
+    }
+
+    /// Send a v1 frame, returns the response
+    fn transfer_v1(&mut self, cmd: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut frame = vec![cmd, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        tracing::debug!("=> v1 {}", hex::encode(&frame));
+        self.inner.send_raw(&frame)?;
+        let resp = self.inner.recv_raw(V1_TIMEOUT)?;
+        tracing::debug!("<= v1 {}", hex::encode(&resp));
+        Ok(resp)
+    }
+
+    /// Send a v1 frame answered by a status byte
+    fn transfer_v1_status(&mut self, cmd: u8, payload: &[u8]) -> Result<u8> {
+        let resp = self.transfer_v1(cmd, payload)?;
+        resp.first()
+            .copied()
+            .ok_or_else(|| anyhow::format_err!("empty v1 response"))
+    }
+
+    /// Identify the chip with v2, falling back to v1. Returns the v2 response.
+    fn identify(&mut self, raw: &[u8]) -> Result<Vec<u8>> {
+        self.inner.send_raw(raw)?;
+        match self.inner.recv_raw(V1_TIMEOUT) {
+            Ok(resp) if resp.first() == Some(&commands::IDENTIFY) => {
+                self.version = Some(ProtocolVersion::V2);
+                return Ok(resp);
+            }
+            Ok(resp) => tracing::debug!("v2 Identify answered by {}", hex::encode(resp)),
+            Err(e) => tracing::debug!("v2 Identify failed: {:#}", e),
+        }
+
+        let resp = self
+            .transfer_v1(commands_v1::DETECT, V1_DETECT)
+            .map_err(|e| e.context("no response to the v1 or v2 Identify"))?;
+        let chip_id = *resp
+            .first()
+            .ok_or_else(|| anyhow::format_err!("empty v1 detect response"))?;
+        tracing::info!("Chip speaks the v1 ISP protocol");
+        self.version = Some(ProtocolVersion::V1);
+        self.chip_id = chip_id;
+        Ok(vec![chip_id, V1_DEVICE_TYPE])
+    }
+
+    /// Run a v2 request as v1 commands, returns the v2 response payload
+    fn execute_v1(&mut self, command: Command) -> Result<Vec<u8>> {
+        let status = |status: u8| Ok(vec![status, 0x00]);
+        match command {
+            Command::Identify { .. } => Ok(vec![self.chip_id, V1_DEVICE_TYPE]),
+            Command::IspKey { key } => {
+                // NOTE: v1 has no key, keep it to decode the v2 data, the UID reads as all-zero
+                self.key = xor_key(&[0; 8], &key, self.chip_id);
+                let checksum = self.key.iter().fold(0_u8, |acc, &x| acc.wrapping_add(x));
+                Ok(vec![checksum, 0x00])
+            }
+            Command::ReadConfig { bit_mask } => {
+                let resp = self.transfer_v1(commands_v1::READ_CONFIG, &[])?;
+                let version = *resp
+                    .get(3)
+                    .ok_or_else(|| anyhow::format_err!("short v1 config response"))?;
+                let mut payload = vec![bit_mask, 0x00];
+                if bit_mask & CFG_MASK_RDPR_USER_DATA_WPR != 0 {
+                    payload.extend_from_slice(&[0xff; 12]);
+                }
+                if bit_mask & CFG_MASK_BTVER != 0 {
+                    payload.extend_from_slice(&[0x00, version >> 4, version & 0x0f, 0x00]);
+                }
+                if bit_mask & CFG_MASK_UID != 0 {
+                    payload.extend_from_slice(&[0; 8]);
+                }
+                Ok(payload)
+            }
+            Command::Erase { sectors } => {
+                // NOTE: 1KiB sectors of four 256-byte pages
+                for sector in 0..sectors.min(0x40) {
+                    let result =
+                        self.transfer_v1_status(commands_v1::ERASE, &[0x00, (sector * 4) as u8])?;
+                    if result != STATUS_OK {
+                        return status(result);
+                    }
+                }
+                status(STATUS_OK)
+            }
+            Command::Program { address, data, .. } => {
+                self.write_v1(commands_v1::PROGRAM, address, &data)
+            }
+            Command::Verify { address, data, .. } => {
+                self.write_v1(commands_v1::VERIFY, address, &data)
+            }
+            Command::IspEnd { reason } => {
+                let result = self.transfer_v1_status(commands_v1::END, &[reason, 0x00]);
+                // NOTE: the chip may reboot before answering
+                status(result.unwrap_or(STATUS_OK))
+            }
+            _ => status(STATUS_UNSUPPORTED),
+        }
+    }
+
+    /// Program or verify XORed v2 `data` in v1 frames of plain data
+    fn write_v1(&mut self, cmd: u8, address: u32, data: &[u8]) -> Result<Vec<u8>> {
+        anyhow::ensure!(
+            address as usize + data.len() <= 0x10000,
+            "v1 addresses are 16-bit, 0x{:08x} is out of range",
+            address
+        );
+        let plain: Vec<u8> = data
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x ^ self.key[i % 8])
+            .collect();
+        let mut address = address as u16;
+        for chunk in plain.chunks(V1_CHUNK) {
+            let mut payload = address.to_le_bytes().to_vec();
+            payload.extend_from_slice(chunk);
+            let result = self.transfer_v1_status(cmd, &payload)?;
+            if result != STATUS_OK {
+                return Ok(vec![result, 0x00]);
+            }
+            address = address.wrapping_add(chunk.len() as u16);
+        }
+        Ok(vec![STATUS_OK, 0x00])
+    }
+}
+
+impl<T: Transport> Transport for LegacyTransport<T> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        let cmd = raw.first().copied().unwrap_or_default();
+        let payload = match self.version {
+            Some(ProtocolVersion::V2) => return self.inner.send_raw(raw),
+            None if cmd == commands::IDENTIFY => {
+                let resp = self.identify(raw)?;
+                if self.version == Some(ProtocolVersion::V2) {
+                    self.responses.push_back(resp);
+                    return Ok(());
+                }
+                resp
+            }
+            None => return self.inner.send_raw(raw),
+            Some(ProtocolVersion::V1) => self.execute_v1(Command::from_raw(raw)?)?,
+        };
+        let mut resp = vec![cmd, 0x00];
+        resp.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        resp.extend(payload);
+        self.responses.push_back(resp);
+        Ok(())
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        match self.responses.pop_front() {
+            Some(resp) => Ok(resp),
+            None => self.inner.recv_raw(timeout),
+        }
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        self.inner.link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.inner.negotiate_baudrate(max_baudrate)
+    }
+}
@@ -1,37 +1,162 @@
 //! Abstract Device transport interface.
-use std::{thread::sleep, time::Duration};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use crate::protocol::{Command, Response};
+use crate::{
+    protocol::{Command, Response},
+    Error,
+};
 
-pub use self::serial::{Baudrate, SerialTransport};
-pub use self::usb::UsbTransport;
+pub use self::legacy::LegacyTransport;
+pub use self::pcap::{CaptureLink, PcapTransport};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::serial::{
+    Baudrate, FlowControl, Parity, SerialFraming, SerialPortSummary, SerialTransport, StopBits,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::tcp::TcpTransport;
+pub use self::timeout::TimeoutTransport;
+pub use self::trace::TraceTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::usb::{UsbDeviceInfo, UsbDeviceSummary, UsbTransport};
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use self::webusb::UsbTransport;
 
+pub mod framed;
+mod legacy;
+mod pcap;
+// NOTE: wasm32 has no native USB or serial access, the `wasm` feature adds a WebUSB
+// `UsbTransport`, or use `Flashing::new_from_transport` with another transport of the host
+#[cfg(not(target_arch = "wasm32"))]
 mod serial;
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp;
+mod timeout;
+mod trace;
+#[cfg(not(target_arch = "wasm32"))]
 mod usb;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub(crate) mod webusb;
 
 const DEFAULT_TRANSPORT_TIMEOUT_MS: u64 = 1000;
 
+/// ISP interface of the chip a transport talks to, see `support_usb` etc. of the chip database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Usb,
+    Serial,
+}
+
+impl std::fmt::Display for Link {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Link::Usb => write!(f, "USB"),
+            Link::Serial => write!(f, "serial"),
+        }
+    }
+}
+
 /// Abstraction of the transport layer.
 /// Might be a USB, a serial port, or Network.
+///
+/// `Send` is not required, so that host environment transports like WebUSB can be used, but the
+/// native ones are `Send`, and so is [`crate::Flashing`] over them.
 pub trait Transport {
     fn send_raw(&mut self, raw: &[u8]) -> Result<()>;
     fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>>;
 
+    /// Max packet size of the link, e.g. of the USB bulk endpoint, `None` for byte streams
+    fn max_packet_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// The chip side of the link, checked against the chip database, `None` if not known
+    fn link(&self) -> Option<Link> {
+        None
+    }
+
+    /// Switch to the baudrate asked for when opening, within `max_baudrate` of the identified
+    /// chip. Returns the baudrate in use, `None` for links without one
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        let _ = max_baudrate;
+        Ok(None)
+    }
+
     fn transfer(&mut self, cmd: Command) -> Result<Response> {
         self.transfer_with_wait(cmd, Duration::from_millis(DEFAULT_TRANSPORT_TIMEOUT_MS))
     }
 
     fn transfer_with_wait(&mut self, cmd: Command, wait: Duration) -> Result<Response> {
         let req = &cmd.into_raw()?;
-        log::debug!("=> {}   {}", hex::encode(&req[..3]), hex::encode(&req[3..]));
-        self.send_raw(&req)?;
-        sleep(Duration::from_micros(1)); // required for some Linux platform
-
-        let resp = self.recv_raw(wait)?;
+        tracing::debug!("=> {}   {}", hex::encode(&req[..3]), hex::encode(&req[3..]));
+        self.send_raw(req).context(Error::Transport)?;
+        let resp = self.recv_raw(wait).context(Error::Transport)?;
         anyhow::ensure!(req[0] == resp[0], "response command type mismatch");
-        log::debug!("<= {} {}", hex::encode(&resp[..4]), hex::encode(&resp[4..]));
-        Response::from_raw(&resp)
+        tracing::debug!("<= {} {}", hex::encode(&resp[..4]), hex::encode(&resp[4..]));
+        Response::from_raw(resp)
+    }
+}
+
+// NOTE: GUI frontends and parallel flashing move sessions to worker threads, keep these `Send`
+#[cfg(not(target_arch = "wasm32"))]
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    const fn assert_sync<T: Sync>() {}
+
+    assert_send::<UsbTransport>();
+    assert_sync::<UsbTransport>();
+    // `serialport` handles are `Send` only, all transport calls take `&mut self` anyway
+    assert_send::<SerialTransport>();
+    assert_send::<TcpTransport>();
+    assert_send::<TraceTransport<UsbTransport, std::fs::File>>();
+    assert_send::<PcapTransport<SerialTransport, std::fs::File>>();
+    assert_send::<crate::Flashing<UsbTransport>>();
+    assert_send::<crate::Flashing<SerialTransport>>();
+    assert_send::<crate::BoxedFlashing<'static>>();
+};
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        (**self).send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        (**self).recv_raw(timeout)
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        (**self).max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        (**self).link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        (**self).negotiate_baudrate(max_baudrate)
+    }
+}
+
+// NOTE: keeps the transport, e.g. an `Emulator`, inspectable after the session
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        (**self).send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        (**self).recv_raw(timeout)
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        (**self).max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        (**self).link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        (**self).negotiate_baudrate(max_baudrate)
     }
 }
@@ -0,0 +1,152 @@
+//! Packet capture in pcapng format, as a transport wrapper.
+//!
+//! USB traffic is written with the USBPcap link type, so captures can be
+//! compared with the ones of WCHISPTool taken by USBPcap on Windows.
+//! Serial traffic is written as raw command frames with the USER0 link type.
+use std::{
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use scroll::{Pwrite, LE};
+
+use super::{Link, Transport};
+
+const LINKTYPE_USER0: u16 = 147;
+const LINKTYPE_USBPCAP: u16 = 249;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+
+const USBPCAP_HEADER_LEN: usize = 27;
+const URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER: u16 = 0x0009;
+const USBPCAP_TRANSFER_BULK: u8 = 3;
+
+/// Link layer of the captured packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureLink {
+    /// USB bulk transfers on EP 0x02 and 0x82
+    Usb,
+    /// Serial pseudo-link, command frames without the serial prefix and checksum
+    Serial,
+}
+
+/// Transport wrapper that captures every raw packet to a pcapng file.
+pub struct PcapTransport<T, W> {
+    inner: T,
+    writer: W,
+    link: CaptureLink,
+    irp_id: u64,
+}
+
+impl<T: Transport, W: Write> PcapTransport<T, W> {
+    pub fn new(inner: T, link: CaptureLink, mut writer: W) -> Result<Self> {
+        // Section Header Block, section length is unspecified
+        let mut shb = [0u8; 28];
+        shb.pwrite_with(BLOCK_SHB, 0, LE)?;
+        shb.pwrite_with(28_u32, 4, LE)?;
+        shb.pwrite_with(0x1a2b_3c4d_u32, 8, LE)?;
+        shb.pwrite_with(1_u16, 12, LE)?;
+        shb.pwrite_with(0_u16, 14, LE)?;
+        shb.pwrite_with(-1_i64, 16, LE)?;
+        shb.pwrite_with(28_u32, 24, LE)?;
+        writer.write_all(&shb)?;
+
+        // Interface Description Block, default microsecond timestamps
+        let link_type = match link {
+            CaptureLink::Usb => LINKTYPE_USBPCAP,
+            CaptureLink::Serial => LINKTYPE_USER0,
+        };
+        let mut idb = [0u8; 20];
+        idb.pwrite_with(BLOCK_IDB, 0, LE)?;
+        idb.pwrite_with(20_u32, 4, LE)?;
+        idb.pwrite_with(link_type, 8, LE)?;
+        idb.pwrite_with(0_u32, 12, LE)?; // no snaplen limit
+        idb.pwrite_with(20_u32, 16, LE)?;
+        writer.write_all(&idb)?;
+        writer.flush()?;
+
+        Ok(PcapTransport {
+            inner,
+            writer,
+            link,
+            irp_id: 0,
+        })
+    }
+
+    fn capture(&mut self, outbound: bool, raw: &[u8]) -> Result<()> {
+        let mut packet = vec![];
+        if self.link == CaptureLink::Usb {
+            self.irp_id += 1;
+            let mut header = [0u8; USBPCAP_HEADER_LEN];
+            header.pwrite_with(USBPCAP_HEADER_LEN as u16, 0, LE)?;
+            header.pwrite_with(self.irp_id, 2, LE)?;
+            header.pwrite_with(0_u32, 10, LE)?; // USBD_STATUS_SUCCESS
+            header.pwrite_with(URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER, 14, LE)?;
+            header[16] = if outbound { 0 } else { 1 }; // PDO -> FDO for responses
+            header.pwrite_with(1_u16, 17, LE)?; // bus
+            header.pwrite_with(1_u16, 19, LE)?; // device
+            header[21] = if outbound { 0x02 } else { 0x82 };
+            header[22] = USBPCAP_TRANSFER_BULK;
+            header.pwrite_with(raw.len() as u32, 23, LE)?;
+            packet.extend_from_slice(&header);
+        }
+        packet.extend_from_slice(raw);
+
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let padded_len = packet.len().div_ceil(4) * 4;
+        // header(28) + data + epb_flags option(8) + opt_endofopt(4) + trailing length(4)
+        let block_len = 28 + padded_len + 8 + 4 + 4;
+
+        let mut block = vec![0u8; block_len];
+        block.pwrite_with(BLOCK_EPB, 0, LE)?;
+        block.pwrite_with(block_len as u32, 4, LE)?;
+        block.pwrite_with(0_u32, 8, LE)?; // interface id
+        block.pwrite_with((ts >> 32) as u32, 12, LE)?;
+        block.pwrite_with(ts as u32, 16, LE)?;
+        block.pwrite_with(packet.len() as u32, 20, LE)?;
+        block.pwrite_with(packet.len() as u32, 24, LE)?;
+        block[28..28 + packet.len()].copy_from_slice(&packet);
+        let mut offset = 28 + padded_len;
+        // epb_flags, direction in bits 0-1: 0b01 inbound, 0b10 outbound
+        block.pwrite_with(2_u16, offset, LE)?;
+        block.pwrite_with(4_u16, offset + 2, LE)?;
+        block.pwrite_with(if outbound { 0b10_u32 } else { 0b01_u32 }, offset + 4, LE)?;
+        offset += 8 + 4; // opt_endofopt is all zero
+        block.pwrite_with(block_len as u32, offset, LE)?;
+
+        self.writer.write_all(&block)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: Transport, W: Write> Transport for PcapTransport<T, W> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        self.capture(true, raw)?;
+        self.inner.send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let resp = self.inner.recv_raw(timeout)?;
+        self.capture(false, &resp)?;
+        Ok(resp)
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        self.inner.link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.inner.negotiate_baudrate(max_baudrate)
+    }
+}
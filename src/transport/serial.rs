@@ -1,12 +1,15 @@
 //! Serial Transportation.
-use std::{fmt::Display, io::Read, time::Duration};
+use std::{
+    fmt::Display,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Error, Ok, Result};
-use clap::{builder::PossibleValue, ValueEnum};
-use scroll::Pread;
 use serialport::SerialPort;
+pub use serialport::{FlowControl, Parity, StopBits};
 
-use super::{Command, Transport};
+use super::{framed, Command, Link, Transport};
 
 const SERIAL_TIMEOUT_MS: u64 = 1000;
 
@@ -14,6 +17,9 @@ const SERIAL_TIMEOUT_MS: u64 = 1000;
 pub enum Baudrate {
     #[default]
     Baud115200,
+    Baud230400,
+    Baud460800,
+    Baud921600,
     Baud1m,
     Baud2m,
 }
@@ -22,26 +28,58 @@ impl From<Baudrate> for u32 {
     fn from(value: Baudrate) -> Self {
         match value {
             Baudrate::Baud115200 => 115200,
+            Baudrate::Baud230400 => 230400,
+            Baudrate::Baud460800 => 460800,
+            Baudrate::Baud921600 => 921600,
             Baudrate::Baud1m => 1000000,
             Baudrate::Baud2m => 2000000,
         }
     }
 }
 
+impl TryFrom<u32> for Baudrate {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Baudrate::ALL
+            .into_iter()
+            .find(|&baudrate| u32::from(baudrate) == value)
+            .ok_or_else(|| anyhow::anyhow!("unsupported baudrate: {}", value))
+    }
+}
+
+impl Baudrate {
+    /// All supported baudrates, slowest first
+    pub const ALL: [Baudrate; 6] = [
+        Baudrate::Baud115200,
+        Baudrate::Baud230400,
+        Baudrate::Baud460800,
+        Baudrate::Baud921600,
+        Baudrate::Baud1m,
+        Baudrate::Baud2m,
+    ];
+}
+
 impl Display for Baudrate {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", u32::from(*self))
     }
 }
 
-impl ValueEnum for Baudrate {
+#[cfg(feature = "cli")]
+impl clap::ValueEnum for Baudrate {
     fn value_variants<'a>() -> &'a [Self] {
-        &[Baudrate::Baud115200, Baudrate::Baud1m, Baudrate::Baud2m]
+        &Baudrate::ALL
     }
 
-    fn to_possible_value(&self) -> Option<PossibleValue> {
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        use clap::builder::PossibleValue;
+
         match self {
             Baudrate::Baud115200 => Some(PossibleValue::new("Baud115200").aliases(["115200"])),
+            Baudrate::Baud230400 => Some(PossibleValue::new("Baud230400").aliases(["230400"])),
+            Baudrate::Baud460800 => Some(PossibleValue::new("Baud460800").aliases(["460800"])),
+            Baudrate::Baud921600 => Some(PossibleValue::new("Baud921600").aliases(["921600"])),
             Baudrate::Baud1m => {
                 Some(PossibleValue::new("Baud1m").aliases(["1000000", "1_000_000", "1m"]))
             }
@@ -54,24 +92,126 @@ impl ValueEnum for Baudrate {
 
 pub struct SerialTransport {
     serial_port: Box<dyn SerialPort>,
+    /// Baudrate asked for when opening, switched to by [`Transport::negotiate_baudrate`]
+    baudrate: Baudrate,
+    /// `max_baudrate` of the chip, known once negotiated
+    max_baudrate: Option<u32>,
+}
+
+/// Serial line framing and flow control, 8N1 without flow control by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialFraming {
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialFraming {
+    fn default() -> Self {
+        SerialFraming {
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// A serial port found by [`SerialTransport::list_ports`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialPortSummary {
+    pub name: String,
+    /// USB VID/PID, `None` for non-USB ports like on-board UARTs
+    pub usb_id: Option<(u16, u16)>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl SerialPortSummary {
+    pub fn is_usb(&self) -> bool {
+        self.usb_id.is_some()
+    }
+}
+
+impl Display for SerialPortSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some((vid, pid)) = self.usb_id {
+            write!(f, " [{:04x}:{:04x}", vid, pid)?;
+            for s in [&self.manufacturer, &self.product].into_iter().flatten() {
+                write!(f, " {}", s)?;
+            }
+            if let Some(serial_number) = &self.serial_number {
+                write!(f, " SN {}", serial_number)?;
+            }
+            write!(f, "]")?;
+        }
+        std::fmt::Result::Ok(())
+    }
 }
 
 impl SerialTransport {
-    pub fn scan_ports() -> Result<Vec<String>> {
+    /// All serial ports, with USB details if available
+    pub fn list_ports() -> Result<Vec<SerialPortSummary>> {
         let ports = serialport::available_ports()?;
-        Ok(ports.into_iter().map(|p| p.port_name).collect())
+        Ok(ports
+            .into_iter()
+            .map(|p| match p.port_type {
+                serialport::SerialPortType::UsbPort(usb) => SerialPortSummary {
+                    name: p.port_name,
+                    usb_id: Some((usb.vid, usb.pid)),
+                    serial_number: usb.serial_number,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                },
+                _ => SerialPortSummary {
+                    name: p.port_name,
+                    usb_id: None,
+                    serial_number: None,
+                    manufacturer: None,
+                    product: None,
+                },
+            })
+            .collect())
     }
 
-    pub fn open(port: &str, baudrate: Baudrate) -> Result<Self> {
-        log::info!("Opening serial port: \"{}\" @ 115200 baud", port);
-        let port = serialport::new(port, Baudrate::default().into())
-            .timeout(Duration::from_millis(SERIAL_TIMEOUT_MS))
-            .open()?;
+    /// Names of all serial ports, see [`SerialTransport::list_ports`]
+    pub fn scan_ports() -> Result<Vec<String>> {
+        Ok(Self::list_ports()?.into_iter().map(|p| p.name).collect())
+    }
 
-        let mut transport = SerialTransport { serial_port: port };
-        transport.set_baudrate(baudrate)?;
+    pub fn open(port: &str, baudrate: Baudrate) -> Result<Self> {
+        Self::open_with_framing(port, baudrate, SerialFraming::default())
+    }
 
-        Ok(transport)
+    /// Open `port` with non-default framing, for bridges or isolated links needing e.g. even parity
+    pub fn open_with_framing(
+        port: &str,
+        baudrate: Baudrate,
+        framing: SerialFraming,
+    ) -> Result<Self> {
+        tracing::info!("Opening serial port: \"{}\" @ 115200 baud", port);
+        if framing != SerialFraming::default() {
+            tracing::info!(
+                "Serial framing: parity {}, stop bits {}, flow control {}",
+                framing.parity,
+                framing.stop_bits,
+                framing.flow_control
+            );
+        }
+        let builder = serialport::new(port, Baudrate::default().into())
+            .timeout(Duration::from_millis(SERIAL_TIMEOUT_MS))
+            .parity(framing.parity)
+            .stop_bits(framing.stop_bits)
+            .flow_control(framing.flow_control);
+        let port = open_waiting_for_grabbers(port, builder)?;
+
+        // NOTE: the chip is not known yet, the switch waits for `Transport::negotiate_baudrate`
+        Ok(SerialTransport {
+            serial_port: port,
+            baudrate,
+            max_baudrate: None,
+        })
     }
 
     pub fn open_nth(nth: usize, baudrate: Baudrate) -> Result<Self> {
@@ -79,26 +219,104 @@ impl SerialTransport {
 
         match ports.get(nth) {
             Some(port) => Self::open(&port.port_name, baudrate),
-            None => Err(Error::msg("No serial ports found!")),
+            None => Err(Error::new(crate::Error::NoDevice(
+                "No serial ports found!".to_string(),
+            ))),
         }
     }
 
+    /// Open the first USB serial port, or the first port if there is no USB one
     pub fn open_any(baudrate: Baudrate) -> Result<Self> {
-        Self::open_nth(0, baudrate)
+        Self::open(&Self::default_port()?, baudrate)
+    }
+
+    /// Name of the port [`SerialTransport::open_any`] opens
+    pub fn default_port() -> Result<String> {
+        let mut ports = Self::list_ports()?;
+        let usb = ports.iter().position(|p| p.is_usb()).unwrap_or(0);
+        match (usb < ports.len()).then(|| ports.swap_remove(usb)) {
+            Some(port) => Ok(port.name),
+            None => Err(Error::new(crate::Error::NoDevice(
+                "No serial ports found!".to_string(),
+            ))),
+        }
     }
 
+    /// Switch to `baudrate`, stepping down 2M -> 1M -> 921600 -> 460800 -> 230400 -> 115200 if
+    /// the switch or the re-sync at the new baudrate fails, skipping rates above `max_baudrate`.
+    /// Returns the baudrate in use.
+    fn step_down(&mut self, baudrate: Baudrate, max_baudrate: Option<u32>) -> Result<u32> {
+        let mut good = self.serial_port.baud_rate()?;
+        if let Some(max) = max_baudrate.filter(|max| u32::from(baudrate) > *max) {
+            tracing::warn!(
+                "Baudrate {} exceeds the chip's maximum of {}, using a lower one",
+                u32::from(baudrate),
+                max
+            );
+        }
+        for rate in Baudrate::ALL
+            .into_iter()
+            .rev()
+            .filter(|rate| *rate <= baudrate)
+        {
+            let rate = u32::from(rate);
+            if max_baudrate.is_some_and(|max| rate > max) {
+                continue;
+            }
+            if rate == good {
+                break;
+            }
+            if let Err(e) = self.switch_baudrate(rate).and_then(|_| self.sync()) {
+                tracing::warn!("Baudrate {} failed: {}, falling back", rate, e);
+            } else {
+                return Ok(self.serial_port.baud_rate()?);
+            }
+            // NOTE: the chip may or may not have switched, resume at whichever baudrate it answers
+            if self.sync().is_err() {
+                self.serial_port.set_baud_rate(good)?;
+                self.sync()
+                    .map_err(|e| e.context("lost sync with the chip while falling back"))?;
+            }
+            good = self.serial_port.baud_rate()?;
+        }
+        Ok(good)
+    }
+
+    /// Check the chip answers at the current baudrate
+    fn sync(&mut self) -> Result<()> {
+        let _ = self.serial_port.clear(serialport::ClearBuffer::Input);
+        let resp = self.transfer(Command::identify(0, 0))?;
+        anyhow::ensure!(resp.is_ok(), "identify failed");
+        Ok(())
+    }
+
+    /// Switch to `baudrate` via `SetBaud`.
+    ///
+    /// Fails above the `max_baudrate` of the chip once negotiated, see
+    /// [`Transport::negotiate_baudrate`], since the chip would accept the switch and then lose sync.
     pub fn set_baudrate(&mut self, baudrate: impl Into<u32>) -> Result<()> {
         let baudrate: u32 = baudrate.into();
+        if let Some(max) = self.max_baudrate.filter(|max| baudrate > *max) {
+            anyhow::bail!(
+                "baudrate {} exceeds the chip's maximum of {}, see `max_baudrate` in the chip database",
+                baudrate,
+                max
+            );
+        }
+        self.switch_baudrate(baudrate)
+    }
 
+    /// Switch to `baudrate` via `SetBaud`, without checking the chip's maximum
+    fn switch_baudrate(&mut self, baudrate: u32) -> Result<()> {
         if baudrate != self.serial_port.baud_rate()? {
             let resp: crate::Response = self.transfer(Command::set_baud(baudrate))?;
             anyhow::ensure!(resp.is_ok(), "set baudrate failed");
 
             if let Some(0xfe) = resp.payload().first() {
-                log::info!("Custom baudrate not supported by the current chip. Using 115200");
+                tracing::info!("Custom baudrate not supported by the current chip. Using 115200");
             } else {
-                log::info!("Switching baudrate to: {baudrate} baud");
-                self.serial_port.set_baud_rate(baudrate.into())?;
+                tracing::info!("Switching baudrate to: {baudrate} baud");
+                self.serial_port.set_baud_rate(baudrate)?;
             }
         }
 
@@ -106,68 +324,117 @@ impl SerialTransport {
     }
 }
 
-impl Transport for SerialTransport {
-    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
-        let mut v = Vec::new();
-
-        v.extend_from_slice(&[0x57, 0xab]); // Append request prefix
-        v.extend_from_slice(raw);
-        v.extend_from_slice(&[raw.iter().fold(0u8, |acc, &val| acc.wrapping_add(val))]); // Append the CRC
-
-        self.serial_port.write_all(&v)?;
-        self.serial_port.flush()?;
-        Ok(())
+/// How long to wait for ModemManager, brltty and alike to release a newly attached port
+const PORT_GRAB_WAIT: Duration = Duration::from_secs(15);
+
+/// Open the port, waiting on Linux while it's busy, held by another process or not yet
+/// created, as right after plug-in
+fn open_waiting_for_grabbers(
+    port: &str,
+    builder: serialport::SerialPortBuilder,
+) -> Result<Box<dyn SerialPort>> {
+    let start = Instant::now();
+    let mut warned = false;
+    loop {
+        let e = match builder.clone().open() {
+            Err(e) => e,
+            result => return Ok(result?),
+        };
+        let holders = port_holders(port);
+        if !is_port_grabbed(&e, &holders) || start.elapsed() >= PORT_GRAB_WAIT {
+            if cfg!(target_os = "linux")
+                && e.kind() == serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied)
+            {
+                tracing::warn!("hint: add yourself to the `dialout` group, or stop ModemManager via `sudo systemctl stop ModemManager`");
+            }
+            return Err(e.into());
+        }
+        if !warned {
+            if holders.is_empty() {
+                tracing::warn!("Serial port {} not available yet: {}, waiting...", port, e);
+            } else {
+                tracing::warn!(
+                    "Serial port {} is held by {}, waiting for release...",
+                    port,
+                    holders.join(", ")
+                );
+            }
+            warned = true;
+        }
+        sleep(Duration::from_millis(500));
     }
+}
 
-    fn recv_raw(&mut self, _timeout: Duration) -> Result<Vec<u8>> {
-        // Ignore the custom timeout
-        // self.serial_port.set_timeout(timeout)?;
-
-        // Read the serial header and validate.
-        let mut head_buf = [0u8; 2];
-        self.serial_port.read_exact(&mut head_buf)?;
-        anyhow::ensure!(
-            head_buf == [0x55, 0xaa],
-            "Response has invalid serial header {head_buf:02x?}",
-        );
-
-        // Read the payload header and extract given length value.
-        let mut payload_head_buf = [0u8; 4];
-        self.serial_port.read_exact(&mut payload_head_buf)?;
-        let payload_data_len = payload_head_buf.pread_with::<u16>(2, scroll::LE)? as usize;
-        anyhow::ensure!(payload_data_len > 0, "Response data length is zero");
-
-        // Read the amount of payload data given in the header.
-        let mut payload_data_buf = vec![0u8; payload_data_len];
-        self.serial_port.read_exact(&mut payload_data_buf)?;
-
-        // Read the checksum and verify against actual sum calculated from
-        // entire payload (header + data).
-        let mut cksum_buf = [0u8; 1];
-        self.serial_port.read_exact(&mut cksum_buf)?;
+/// Whether opening failed only for a while, Linux only: the port is not created yet, busy, or
+/// denied while another process holds it. A plain permission error fails right away.
+fn is_port_grabbed(e: &serialport::Error, holders: &[String]) -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    match e.kind() {
+        serialport::ErrorKind::NoDevice => true,
+        serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => !holders.is_empty(),
+        // NOTE: EBUSY has no dedicated kind
+        _ => e.description.contains("busy"),
+    }
+}
 
-        // Stuff the payload header and data into response to be returned.
-        let resp_vec: Vec<u8> = payload_head_buf
-            .into_iter()
-            .chain(payload_data_buf.into_iter())
+/// Names of the processes having `port` open, Linux only
+fn port_holders(port: &str) -> Vec<String> {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(port) = std::fs::canonicalize(port).ok() else {
+            return vec![];
+        };
+        let Some(procs) = std::fs::read_dir("/proc").ok() else {
+            return vec![];
+        };
+        let mut holders: Vec<String> = procs
+            .flatten()
+            .filter(|proc| {
+                std::fs::read_dir(proc.path().join("fd"))
+                    .map(|fds| {
+                        fds.flatten()
+                            .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|p| p == port))
+                    })
+                    .unwrap_or(false)
+            })
+            .filter_map(|proc| std::fs::read_to_string(proc.path().join("comm")).ok())
+            .map(|comm| comm.trim().to_string())
             .collect();
+        holders.sort();
+        holders.dedup();
+        holders
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = port;
+        vec![]
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        framed::write_request(&mut self.serial_port, raw)
+    }
 
-        // Read the checksum and verify against actual sum calculated from
-        // entire payload (header + data).
-        let checksum = resp_vec.iter().fold(0u8, |acc, &val| acc.wrapping_add(val));
-        anyhow::ensure!(
-            checksum == cksum_buf[0],
-            "Response has incorrect checksum ({:02x} != {:02x})",
-            cksum_buf[0],
-            checksum
-        );
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        // Honor long operation timeouts(erase), but never wait less than the port default
+        self.serial_port
+            .set_timeout(timeout.max(Duration::from_millis(SERIAL_TIMEOUT_MS)))?;
+        framed::read_response(&mut self.serial_port)
+    }
 
-        Ok(resp_vec)
+    fn link(&self) -> Option<Link> {
+        Some(Link::Serial)
     }
-}
 
-impl Drop for SerialTransport {
-    fn drop(&mut self) {
-        let _ = self.set_baudrate(Baudrate::Baud115200);
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.max_baudrate = max_baudrate;
+        let current = self.serial_port.baud_rate()?;
+        if u32::from(self.baudrate) <= current {
+            return Ok(Some(current));
+        }
+        self.step_down(self.baudrate, max_baudrate).map(Some)
     }
 }
@@ -0,0 +1,39 @@
+//! Serial framing over TCP, e.g. to a `wchisp emulate` bootloader or a serial-to-network bridge
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use super::{framed, Transport};
+
+const TCP_TIMEOUT_MS: u64 = 1000;
+
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    /// Connect to `addr`, `HOST:PORT`
+    pub fn connect(addr: impl ToSocketAddrs + std::fmt::Display) -> Result<Self> {
+        tracing::info!("Connecting to {}", addr);
+        let stream = TcpStream::connect(&addr)
+            .with_context(|| format!("connecting to {}", addr))
+            .context(crate::Error::Transport)?;
+        stream.set_nodelay(true).context(crate::Error::Transport)?;
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        framed::write_request(&mut self.stream, raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(TCP_TIMEOUT_MS))))?;
+        framed::read_response(&mut self.stream)
+    }
+}
@@ -0,0 +1,43 @@
+//! Longer response timeouts, as a transport wrapper.
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::{Link, Transport};
+
+/// Transport wrapper that waits at least `timeout` for every response, e.g. for slow USB hubs
+/// or long serial cables.
+///
+/// Longer per-operation waits, like code flash erase, are kept.
+pub struct TimeoutTransport<T> {
+    inner: T,
+    timeout: Duration,
+}
+
+impl<T: Transport> TimeoutTransport<T> {
+    pub fn new(inner: T, timeout: Duration) -> Self {
+        TimeoutTransport { inner, timeout }
+    }
+}
+
+impl<T: Transport> Transport for TimeoutTransport<T> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        self.inner.send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        self.inner.recv_raw(timeout.max(self.timeout))
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        self.inner.link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.inner.negotiate_baudrate(max_baudrate)
+    }
+}
@@ -0,0 +1,71 @@
+//! Protocol tracing, as a transport wrapper.
+use std::{
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use super::{Link, Transport};
+use crate::{constants::commands, protocol::Command};
+
+/// Transport wrapper that writes every raw packet as a JSON line.
+///
+/// Each line has the UNIX timestamp, direction(`tx` or `rx`), command name and raw hex.
+/// Requests also carry the decoded [`Command`], when it can be decoded.
+pub struct TraceTransport<T, W> {
+    inner: T,
+    writer: W,
+}
+
+impl<T: Transport, W: Write> TraceTransport<T, W> {
+    pub fn new(inner: T, writer: W) -> Self {
+        TraceTransport { inner, writer }
+    }
+
+    fn trace(&mut self, dir: &str, raw: &[u8]) -> Result<()> {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut record = serde_json::json!({
+            "ts": ts,
+            "dir": dir,
+            "cmd": raw.first().map(|&c| commands::name(c)).unwrap_or("UNKNOWN"),
+            "raw": hex::encode(raw),
+        });
+        if dir == "tx" {
+            if let Ok(command) = Command::from_raw(raw) {
+                record["decoded"] = format!("{:?}", command).into();
+            }
+        }
+        writeln!(self.writer, "{}", record)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: Transport, W: Write> Transport for TraceTransport<T, W> {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        self.trace("tx", raw)?;
+        self.inner.send_raw(raw)
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let resp = self.inner.recv_raw(timeout)?;
+        self.trace("rx", &resp)?;
+        Ok(resp)
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        self.inner.max_packet_size()
+    }
+
+    fn link(&self) -> Option<Link> {
+        self.inner.link()
+    }
+
+    fn negotiate_baudrate(&mut self, max_baudrate: Option<u32>) -> Result<Option<u32>> {
+        self.inner.negotiate_baudrate(max_baudrate)
+    }
+}
@@ -1,97 +1,152 @@
 //! USB Transportation.
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 use anyhow::Result;
-use rusb::{Context, DeviceHandle, UsbContext};
+use rusb::{Context, DeviceHandle, Direction, Recipient, RequestType, UsbContext};
 
-use super::Transport;
+use super::{Link, Transport};
 
 const ENDPOINT_OUT: u8 = 0x02;
 const ENDPOINT_IN: u8 = 0x82;
 
 const USB_TIMEOUT_MS: u64 = 5000;
 
+const USB_CLASS_HUB: u8 = 0x09;
+/// Port feature selector of `SetPortFeature`/`ClearPortFeature`
+const USB_PORT_FEAT_POWER: u16 = 8;
+
 pub struct UsbTransport {
     device_handle: DeviceHandle<rusb::Context>,
+    info: UsbDeviceInfo,
+    /// wMaxPacketSize of the bulk OUT endpoint
+    max_packet_size: usize,
+}
+
+/// Which USB device a [`UsbTransport`] talks to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    /// Product string descriptor, if readable
+    pub product: Option<String>,
+    /// USB backend library in use
+    pub backend: &'static str,
+}
+
+impl fmt::Display for UsbDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x} bus {} address {}",
+            self.vendor_id, self.product_id, self.bus_number, self.address
+        )?;
+        if let Some(product) = &self.product {
+            write!(f, " \"{}\"", product)?;
+        }
+        write!(f, " via {}", self.backend)
+    }
+}
+
+/// A WCH ISP USB device found by [`UsbTransport::list_devices`], not opened yet
+#[derive(Debug, Clone)]
+pub struct UsbDeviceSummary {
+    /// Index to use with [`UsbTransport::open_nth`]
+    pub index: usize,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    device: rusb::Device<Context>,
 }
 
 impl UsbTransport {
-    pub fn scan_devices() -> Result<usize> {
+    /// All connected WCH ISP USB devices(4348:55e0 or 1a86:55e0), in one enumeration pass
+    pub fn list_devices() -> Result<Vec<UsbDeviceSummary>> {
         let context = Context::new()?;
 
-        let n = context
+        let devices = context
             .devices()?
             .iter()
-            .filter(|device| {
-                device
-                    .device_descriptor()
-                    .map(|desc| {
-                        (desc.vendor_id() == 0x4348 || desc.vendor_id() == 0x1a86)
-                            && desc.product_id() == 0x55e0
-                    })
-                    .unwrap_or(false)
+            .filter_map(|device| {
+                let desc = device.device_descriptor().ok()?;
+                ((desc.vendor_id() == 0x4348 || desc.vendor_id() == 0x1a86)
+                    && desc.product_id() == 0x55e0)
+                    .then_some((desc, device))
             })
             .enumerate()
-            .map(|(i, device)| {
-                log::debug!("Found WCH ISP USB device #{}: [{:?}]", i, device);
+            .map(|(index, (desc, device))| {
+                tracing::debug!("Found WCH ISP USB device #{}: [{:?}]", index, device);
+                UsbDeviceSummary {
+                    index,
+                    vendor_id: desc.vendor_id(),
+                    product_id: desc.product_id(),
+                    bus_number: device.bus_number(),
+                    address: device.address(),
+                    device,
+                }
             })
-            .count();
-        Ok(n)
+            .collect();
+        Ok(devices)
     }
 
-    pub fn open_nth(nth: usize) -> Result<UsbTransport> {
-        log::info!("Opening USB device #{}", nth);
-
-        let context = Context::new()?;
+    /// Number of connected WCH ISP USB devices, see [`UsbTransport::list_devices`]
+    pub fn scan_devices() -> Result<usize> {
+        Ok(Self::list_devices()?.len())
+    }
 
-        let device = context
-            .devices()?
-            .iter()
-            .filter(|device| {
-                device
-                    .device_descriptor()
-                    .map(|desc| {
-                        (desc.vendor_id() == 0x4348 || desc.vendor_id() == 0x1a86)
-                            && desc.product_id() == 0x55e0
-                    })
-                    .unwrap_or(false)
-            })
+    /// The `nth` connected WCH ISP USB device, not opened yet
+    pub fn find_nth(nth: usize) -> Result<UsbDeviceSummary> {
+        let summary = Self::list_devices()?
+            .into_iter()
             .nth(nth)
-            .ok_or(anyhow::format_err!(
+            .ok_or(crate::Error::NoDevice(format!(
                 "No WCH ISP USB device found(4348:55e0 or 1a86:55e0 device not found at index #{})",
                 nth
-            ))?;
-        log::debug!("Found USB Device {:?}", device);
+            )))?;
+        Ok(summary)
+    }
+
+    pub fn open_nth(nth: usize) -> Result<UsbTransport> {
+        tracing::info!("Opening USB device #{}", nth);
+        Self::open(&Self::find_nth(nth)?)
+    }
+
+    /// Open a device from [`UsbTransport::list_devices`], without enumerating again
+    pub fn open(summary: &UsbDeviceSummary) -> Result<UsbTransport> {
+        let device = &summary.device;
+        tracing::debug!("Found USB Device {:?}", device);
 
         let device_handle = match device.open() {
             Ok(handle) => handle,
             #[cfg(target_os = "windows")]
             Err(rusb::Error::NotSupported) => {
-                log::error!("Failed to open USB device: {:?}", device);
-                log::warn!("It's likely no WinUSB/LibUSB drivers installed. Please install it from Zadig. See also: https://zadig.akeo.ie");
+                tracing::error!("Failed to open USB device: {:?}", device);
+                tracing::warn!("It's likely no WinUSB/LibUSB drivers installed. Please install it from Zadig. See also: https://zadig.akeo.ie");
                 anyhow::bail!("Failed to open USB device on Windows");
             }
             #[cfg(target_os = "linux")]
             Err(rusb::Error::Access) => {
-                log::error!("Failed to open USB device: {:?}", device);
-                log::warn!("It's likely the udev rules is not installed properly. Please refer to README.md for more details.");
+                tracing::error!("Failed to open USB device: {:?}", device);
+                tracing::warn!("It's likely the udev rules is not installed properly. Please refer to README.md for more details.");
                 anyhow::bail!("Failed to open USB device on Linux due to no enough permission");
             }
             Err(e) => {
-                log::error!("Failed to open USB device: {}", e);
+                tracing::error!("Failed to open USB device: {}", e);
                 anyhow::bail!("Failed to open USB device");
             }
         };
 
         let config = device.config_descriptor(0)?;
 
-        let mut endpoint_out_found = false;
+        let mut endpoint_out_found = None;
         let mut endpoint_in_found = false;
         if let Some(intf) = config.interfaces().next() {
             if let Some(desc) = intf.descriptors().next() {
                 for endpoint in desc.endpoint_descriptors() {
                     if endpoint.address() == ENDPOINT_OUT {
-                        endpoint_out_found = true;
+                        endpoint_out_found = Some(endpoint.max_packet_size() as usize);
                     }
                     if endpoint.address() == ENDPOINT_IN {
                         endpoint_in_found = true;
@@ -100,22 +155,135 @@ impl UsbTransport {
             }
         }
 
-        if !(endpoint_out_found && endpoint_in_found) {
+        let Some(max_packet_size) = endpoint_out_found.filter(|_| endpoint_in_found) else {
             anyhow::bail!("USB Endpoints not found");
-        }
+        };
+        tracing::debug!("Bulk endpoint max packet size: {}", max_packet_size);
 
         device_handle.set_active_configuration(1)?;
         let _config = device.active_config_descriptor()?;
-        let _descriptor = device.device_descriptor()?;
+        let descriptor = device.device_descriptor()?;
 
         device_handle.claim_interface(0)?;
 
-        Ok(UsbTransport { device_handle })
+        let info = UsbDeviceInfo {
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            bus_number: device.bus_number(),
+            address: device.address(),
+            product: device_handle
+                .read_product_string_ascii(&descriptor)
+                .ok()
+                .filter(|s| !s.is_empty()),
+            backend: "libusb",
+        };
+        tracing::debug!("USB device: {}", info);
+
+        Ok(UsbTransport {
+            device_handle,
+            info,
+            max_packet_size,
+        })
+    }
+
+    /// VID/PID, bus location and product string of the opened device
+    pub fn device_info(&self) -> &UsbDeviceInfo {
+        &self.info
     }
 
     pub fn open_any() -> Result<UsbTransport> {
         Self::open_nth(0)
     }
+
+    /// Switch `port` of a USB hub off for `off_time` and on again, like `uhubctl -a cycle`, so
+    /// that a board powered by the port restarts, e.g. into its bootloader.
+    ///
+    /// `hub` is the hub location as listed by `uhubctl`, `BUS-PORT[.PORT...]`, or `BUS` for a
+    /// root hub. Only hubs with per-port or ganged power switching are supported.
+    pub fn power_cycle_hub_port(hub: &str, port: u8, off_time: Duration) -> Result<()> {
+        let (bus, ports) = match hub.split_once('-') {
+            Some((bus, ports)) => (
+                bus,
+                ports.split('.').map(str::parse).collect::<Result<_, _>>(),
+            ),
+            None => (hub, Ok(vec![])),
+        };
+        let (bus, ports): (u8, Vec<u8>) = match (bus.parse(), ports) {
+            (Ok(bus), Ok(ports)) => (bus, ports),
+            _ => anyhow::bail!("invalid hub location: {}, expected BUS-PORT[.PORT...]", hub),
+        };
+
+        let context = Context::new()?;
+        let device = context
+            .devices()?
+            .iter()
+            .find(|device| {
+                device.bus_number() == bus
+                    && device.port_numbers().is_ok_and(|p| p == ports)
+                    && device
+                        .device_descriptor()
+                        .is_ok_and(|desc| desc.class_code() == USB_CLASS_HUB)
+            })
+            .ok_or_else(|| {
+                crate::Error::NoDevice(format!("No USB hub found at location {}", hub))
+            })?;
+        let handle = device.open()?;
+
+        // NOTE: USB 3 hubs have a SuperSpeed hub descriptor, USB 2 hubs the classic one
+        let is_usb3 = device.device_descriptor()?.usb_version().major() >= 3;
+        let descriptor_type: u16 = if is_usb3 { 0x2a } else { 0x29 };
+        let mut desc = [0u8; 16];
+        let n = handle.read_control(
+            rusb::request_type(Direction::In, RequestType::Class, Recipient::Device),
+            rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+            descriptor_type << 8,
+            0,
+            &mut desc,
+            Duration::from_millis(USB_TIMEOUT_MS),
+        )?;
+        anyhow::ensure!(n >= 5, "hub {} returned a short hub descriptor", hub);
+        let num_ports = desc[2];
+        let power_switching = u16::from_le_bytes([desc[3], desc[4]]) & 0x03;
+        anyhow::ensure!(
+            (1..=num_ports).contains(&port),
+            "hub {} has ports 1 to {}, not {}",
+            hub,
+            num_ports,
+            port
+        );
+        match power_switching {
+            0b00 => tracing::warn!("Hub {} switches power of all ports together", hub),
+            0b01 => {}
+            _ => anyhow::bail!("hub {} does not support port power switching", hub),
+        }
+
+        let port_power = |on: bool| {
+            let request = if on {
+                rusb::constants::LIBUSB_REQUEST_SET_FEATURE
+            } else {
+                rusb::constants::LIBUSB_REQUEST_CLEAR_FEATURE
+            };
+            handle.write_control(
+                rusb::request_type(Direction::Out, RequestType::Class, Recipient::Other),
+                request,
+                USB_PORT_FEAT_POWER,
+                port as u16,
+                &[],
+                Duration::from_millis(USB_TIMEOUT_MS),
+            )
+        };
+        tracing::info!(
+            "Powering off port {} of hub {} for {:?}",
+            port,
+            hub,
+            off_time
+        );
+        port_power(false)?;
+        std::thread::sleep(off_time);
+        port_power(true)?;
+        tracing::info!("Powered on port {} of hub {}", port, hub);
+        Ok(())
+    }
 }
 
 impl Drop for UsbTransport {
@@ -140,4 +308,12 @@ impl Transport for UsbTransport {
             .read_bulk(ENDPOINT_IN, &mut buf, timeout)?;
         Ok(buf[..nread].to_vec())
     }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        Some(self.max_packet_size)
+    }
+
+    fn link(&self) -> Option<Link> {
+        Some(Link::Usb)
+    }
 }
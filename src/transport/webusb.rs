@@ -0,0 +1,130 @@
+//! WebUSB transport of the `wasm` feature.
+//!
+//! WebUSB is promise-based and only the page event loop can drive it, while [`Transport`] is
+//! blocking. So [`UsbTransport`] runs in a Web Worker, hands each transfer to the page through
+//! a `SharedArrayBuffer` and blocks on `Atomics.wait` until `js/webusb-bridge.js` has run it on
+//! the device.
+//!
+//! Layout of the shared buffer, 32-bit words then data:
+//!
+//! | Offset | Content                                          |
+//! |--------|--------------------------------------------------|
+//! | 0      | state: idle, request, done or error              |
+//! | 4      | operation: bulk OUT or bulk IN                   |
+//! | 8      | data length, of the request or of the response   |
+//! | 12     | timeout of a bulk IN, in ms                      |
+//! | 16..   | data, or the UTF-8 error message                 |
+use std::time::Duration;
+
+use anyhow::Result;
+use js_sys::{Atomics, Int32Array, SharedArrayBuffer, Uint8Array};
+
+use super::{Link, Transport};
+use crate::constants::MAX_PACKET_SIZE;
+
+const STATE: u32 = 0;
+const OP: u32 = 1;
+const LEN: u32 = 2;
+const TIMEOUT: u32 = 3;
+const HEADER_SIZE: u32 = 16;
+
+const STATE_IDLE: i32 = 0;
+const STATE_REQUEST: i32 = 1;
+const STATE_DONE: i32 = 2;
+const STATE_ERROR: i32 = 3;
+
+const OP_OUT: i32 = 1;
+const OP_IN: i32 = 2;
+
+/// Extra wait for the page on top of the transfer timeout
+const BRIDGE_SLACK: Duration = Duration::from_millis(1000);
+
+/// A WCH ISP USB device opened by the page, see the [module docs](self)
+pub struct UsbTransport {
+    control: Int32Array,
+    data: Uint8Array,
+}
+
+impl UsbTransport {
+    /// Talk to the device served by `serve(device, buffer)` of `js/webusb-bridge.js`.
+    ///
+    /// Must be called in a Web Worker, the page thread is not allowed to block.
+    pub fn new(buffer: &SharedArrayBuffer) -> Result<Self> {
+        anyhow::ensure!(
+            buffer.byte_length() >= HEADER_SIZE + MAX_PACKET_SIZE as u32,
+            "shared buffer of {} bytes is too small, at least {} needed",
+            buffer.byte_length(),
+            HEADER_SIZE + MAX_PACKET_SIZE as u32
+        );
+        Ok(UsbTransport {
+            control: Int32Array::new_with_byte_offset_and_length(buffer, 0, HEADER_SIZE / 4),
+            data: Uint8Array::new_with_byte_offset(buffer, HEADER_SIZE),
+        })
+    }
+
+    /// Run a bulk transfer on the page, returns the data length of the response
+    fn call(&mut self, op: i32, len: usize, timeout: Duration) -> Result<usize> {
+        let store = |index: u32, value: i32| {
+            Atomics::store(&self.control, index, value)
+                .map_err(|e| anyhow::format_err!("Atomics.store failed: {:?}", e))
+        };
+        store(OP, op)?;
+        store(LEN, len as i32)?;
+        store(TIMEOUT, timeout.as_millis() as i32)?;
+        store(STATE, STATE_REQUEST)?;
+        let _ = Atomics::notify(&self.control, STATE);
+
+        let wait = (timeout + BRIDGE_SLACK).as_secs_f64() * 1000.0;
+        let result = Atomics::wait_with_timeout(&self.control, STATE, STATE_REQUEST, wait)
+            .map_err(|e| {
+                anyhow::format_err!("Atomics.wait failed, not in a Web Worker? {:?}", e)
+            })?;
+        let state = Atomics::load(&self.control, STATE).unwrap_or(STATE_REQUEST);
+        anyhow::ensure!(
+            state != STATE_REQUEST,
+            "no answer from the WebUSB bridge ({})",
+            String::from(result)
+        );
+        let len = Atomics::load(&self.control, LEN).unwrap_or(0).max(0) as usize;
+        let len = len.min(self.data.length() as usize);
+        store(STATE, STATE_IDLE)?;
+        if state == STATE_ERROR {
+            let message = self.data.subarray(0, len as u32).to_vec();
+            anyhow::bail!("WebUSB: {}", String::from_utf8_lossy(&message));
+        }
+        debug_assert_eq!(state, STATE_DONE);
+        Ok(len)
+    }
+}
+
+impl Transport for UsbTransport {
+    fn send_raw(&mut self, raw: &[u8]) -> Result<()> {
+        anyhow::ensure!(
+            raw.len() <= self.data.length() as usize,
+            "request of {} bytes does not fit the shared buffer",
+            raw.len()
+        );
+        self.data.subarray(0, raw.len() as u32).copy_from(raw);
+        self.call(OP_OUT, raw.len(), Duration::ZERO)?;
+        Ok(())
+    }
+
+    fn recv_raw(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let len = self.call(OP_IN, MAX_PACKET_SIZE, timeout)?;
+        Ok(self.data.subarray(0, len as u32).to_vec())
+    }
+
+    fn max_packet_size(&self) -> Option<usize> {
+        Some(MAX_PACKET_SIZE)
+    }
+
+    fn link(&self) -> Option<Link> {
+        Some(Link::Usb)
+    }
+}
+
+/// `std::thread::sleep` panics on wasm32, block the worker on an atomic instead
+pub(crate) fn sleep(duration: Duration) {
+    let cell = Int32Array::new(&SharedArrayBuffer::new(4));
+    let _ = Atomics::wait_with_timeout(&cell, 0, 0, duration.as_secs_f64() * 1000.0);
+}
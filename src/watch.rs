@@ -0,0 +1,95 @@
+//! Device attach/detach events, `wchisp probe --watch`
+use std::{collections::BTreeMap, thread::sleep, time::Duration};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use wchisp::{
+    flashing::CancelToken,
+    transport::{SerialTransport, UsbTransport},
+};
+
+/// Enumeration interval, short enough to catch boards bouncing in and out of the bootloader
+const POLL_INTERVAL_MS: u64 = 250;
+
+/// Print a line for every device that appears or disappears, until cancelled.
+///
+/// With `json`, every event is a JSON line on stdout, e.g.
+/// `{"event":"attach","transport":"usb","bus":1,"address":5,"vendor_id":17224,"product_id":21984}`.
+pub fn run(usb: bool, serial: bool, json: bool, cancel: CancelToken) -> Result<()> {
+    tracing::info!("Watching for devices, press Ctrl+C to stop");
+
+    // key -> (label, JSON event payload)
+    let mut known: BTreeMap<String, (String, Value)> = BTreeMap::new();
+    while !cancel.is_cancelled() {
+        let mut current = BTreeMap::new();
+        if usb {
+            for device in UsbTransport::list_devices()? {
+                let label = format!(
+                    "USB bus {} address {} [{:04x}:{:04x}]",
+                    device.bus_number, device.address, device.vendor_id, device.product_id
+                );
+                current.insert(
+                    format!("usb:{}:{}", device.bus_number, device.address),
+                    (
+                        label,
+                        json!({
+                            "transport": "usb",
+                            "bus": device.bus_number,
+                            "address": device.address,
+                            "vendor_id": device.vendor_id,
+                            "product_id": device.product_id,
+                        }),
+                    ),
+                );
+            }
+        }
+        if serial {
+            for port in SerialTransport::list_ports()? {
+                let label = format!("Serial port {}", port);
+                let usb_id = port
+                    .usb_id
+                    .map(|(vid, pid)| format!("{:04x}:{:04x}", vid, pid));
+                current.insert(
+                    format!("serial:{}", port.name),
+                    (
+                        label,
+                        json!({
+                            "transport": "serial",
+                            "port": port.name,
+                            "usb_id": usb_id,
+                            "product": port.product,
+                        }),
+                    ),
+                );
+            }
+        }
+
+        for (key, (label, device)) in &current {
+            if !known.contains_key(key) {
+                report("attach", label, device, json);
+            }
+        }
+        for (key, (label, device)) in &known {
+            if !current.contains_key(key) {
+                report("detach", label, device, json);
+            }
+        }
+        known = current;
+
+        sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+    Ok(())
+}
+
+fn report(event: &str, label: &str, device: &Value, json: bool) {
+    if json {
+        let mut line = device.clone();
+        line["event"] = json!(event);
+        println!("{}", line);
+    } else if event == "attach" {
+        tracing::info!("+ {}", label);
+    } else {
+        tracing::info!("- {}", label);
+    }
+}
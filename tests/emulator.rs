@@ -0,0 +1,100 @@
+//! `FlashSession` driven through the in-process `Emulator`.
+use wchisp::{device::ChipDB, emulator::Emulator, format::Segment, Flashing};
+
+fn emulator(name: &str) -> Emulator {
+    let chip = ChipDB::load().unwrap().find_chip_by_name(name).unwrap();
+    Emulator::new(chip)
+}
+
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+        .collect()
+}
+
+#[test]
+fn multi_image() {
+    let mut emulator = emulator("CH582");
+    let boot = pattern(4096, 1);
+    let app = pattern(5000, 2);
+    {
+        let mut flashing = Flashing::new_from_transport(&mut emulator).unwrap();
+        flashing
+            .session()
+            .reset(false)
+            .run(&[
+                Segment::new(0, boot.clone()),
+                Segment::new(0x2000, app.clone()),
+            ])
+            .unwrap();
+    }
+    assert_eq!(&emulator.flash[..0x1000], &boot[..]);
+    assert_eq!(&emulator.flash[0x2000..0x2000 + app.len()], &app[..]);
+}
+
+#[test]
+fn offset_needs_erase_all() {
+    let mut emulator = emulator("CH582");
+    {
+        let mut flashing = Flashing::new_from_transport(&mut emulator).unwrap();
+        let err = flashing
+            .session()
+            .reset(false)
+            .offset(0x1000)
+            .run(&[Segment::new(0, pattern(256, 3))])
+            .unwrap_err();
+        assert!(err.to_string().contains("--erase-all"), "{err}");
+    }
+    assert!(emulator.flash.iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn eeprom_window_split() {
+    let mut emulator = emulator("CH582");
+    let window = emulator.chip().eeprom_window().unwrap();
+    let data = pattern(64, 4);
+    {
+        let mut flashing = Flashing::new_from_transport(&mut emulator).unwrap();
+        flashing
+            .session()
+            .reset(false)
+            .erase_all(true)
+            .pad(None)
+            .run(&[Segment::new(window.start - 32, data.clone())])
+            .unwrap();
+    }
+    let code_end = window.start as usize;
+    assert_eq!(&emulator.flash[code_end - 32..code_end], &data[..32]);
+    assert_eq!(&emulator.eeprom[..32], &data[32..]);
+}
+
+#[test]
+fn dry_run_leaves_flash_untouched() {
+    let mut emulator = emulator("CH582");
+    {
+        let mut flashing = Flashing::new_from_transport(&mut emulator).unwrap();
+        flashing.set_dry_run(true);
+        flashing
+            .session()
+            .reset(false)
+            .run(&[Segment::new(0, pattern(4096, 5))])
+            .unwrap();
+    }
+    assert!(emulator.flash.iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn pipelined() {
+    let mut emulator = emulator("CH32V307");
+    let image = pattern(20000, 6);
+    {
+        let mut flashing = Flashing::new_from_transport(&mut emulator).unwrap();
+        flashing.set_pipelined(true);
+        flashing
+            .session()
+            .reset(false)
+            .run(&[Segment::new(0, image.clone())])
+            .unwrap();
+    }
+    assert_eq!(&emulator.flash[..image.len()], &image[..]);
+}